@@ -0,0 +1,309 @@
+/*
+The manifest of a multi-part AOF, in the format of Redis 7: the files of
+`appenddirname` that make up the dataset, one per line, as
+
+    file appendonly.aof.1.base.rdb seq 1 type b
+    file appendonly.aof.1.incr.aof seq 1 type i
+
+The dataset is the base file (a snapshot, in RDB or AOF format) followed by
+the incremental files in order. History files (type h) are leftovers of a
+rewrite, waiting to be deleted.
+*/
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AofFileType {
+    Base,
+    Incr,
+    History,
+}
+
+impl AofFileType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AofFileType::Base => "b",
+            AofFileType::Incr => "i",
+            AofFileType::History => "h",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "b" => Some(AofFileType::Base),
+            "i" => Some(AofFileType::Incr),
+            "h" => Some(AofFileType::History),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AofInfo {
+    pub name: String,
+    pub seq: u64,
+    pub file_type: AofFileType,
+}
+
+/*
+- cur_base_seq / cur_incr_seq: the highest sequence numbers handed out, so
+  new files never reuse a name even after their predecessors are deleted.
+*/
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AofManifest {
+    pub base: Option<AofInfo>,
+    pub incrs: Vec<AofInfo>,
+    pub history: Vec<AofInfo>,
+    pub cur_base_seq: u64,
+    pub cur_incr_seq: u64,
+}
+
+pub fn manifest_name(prefix: &str) -> String {
+    format!("{}.manifest", prefix)
+}
+
+pub fn base_name(prefix: &str, seq: u64, rdb_preamble: bool) -> String {
+    let ext: &str = if rdb_preamble { "rdb" } else { "aof" };
+    format!("{}.{}.base.{}", prefix, seq, ext)
+}
+
+pub fn incr_name(prefix: &str, seq: u64) -> String {
+    format!("{}.{}.incr.aof", prefix, seq)
+}
+
+impl AofManifest {
+    /*
+    Parses a manifest. Blank lines and `#` comments are ignored; unknown keys
+    on a line are skipped so newer manifests still load.
+    */
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut manifest = AofManifest::default();
+        for (lineno, line) in text.lines().enumerate() {
+            let line: &str = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let bad_line = || format!("Invalid AOF manifest line {}: {}", lineno + 1, line);
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let pairs = fields.chunks_exact(2);
+            if !pairs.remainder().is_empty() {
+                return Err(bad_line());
+            }
+            let mut name: Option<String> = None;
+            let mut seq: Option<u64> = None;
+            let mut file_type: Option<AofFileType> = None;
+            for pair in pairs {
+                match pair[0] {
+                    "file" => name = Some(pair[1].to_string()),
+                    "seq" => seq = Some(pair[1].parse().map_err(|_| bad_line())?),
+                    "type" => file_type = Some(AofFileType::parse(pair[1]).ok_or_else(bad_line)?),
+                    _ => {}
+                }
+            }
+            let info: AofInfo = match (name, seq, file_type) {
+                (Some(name), Some(seq), Some(file_type)) => AofInfo {
+                    name,
+                    seq,
+                    file_type,
+                },
+                _ => return Err(bad_line()),
+            };
+            match info.file_type {
+                AofFileType::Base => {
+                    if manifest.base.is_some() {
+                        return Err(format!(
+                            "Found duplicate base file in AOF manifest: {}",
+                            line
+                        ));
+                    }
+                    manifest.cur_base_seq = info.seq;
+                    manifest.base = Some(info);
+                }
+                AofFileType::Incr => {
+                    if info.seq <= manifest.cur_incr_seq {
+                        return Err(format!(
+                            "Found a non-monotonic sequence number in AOF manifest: {}",
+                            line
+                        ));
+                    }
+                    manifest.cur_incr_seq = info.seq;
+                    manifest.incrs.push(info);
+                }
+                AofFileType::History => manifest.history.push(info),
+            }
+        }
+        if manifest.base.is_none() && manifest.incrs.is_empty() {
+            return Err("Found an empty AOF manifest".to_string());
+        }
+        Ok(manifest)
+    }
+
+    /*
+    Serializes the manifest: base first, then history, then the incremental
+    files in order.
+    */
+    pub fn to_text(&self) -> String {
+        self.base
+            .iter()
+            .chain(self.history.iter())
+            .chain(self.incrs.iter())
+            .map(|info| {
+                format!(
+                    "file {} seq {} type {}\n",
+                    info.name,
+                    info.seq,
+                    info.file_type.as_str()
+                )
+            })
+            .collect()
+    }
+
+    /*
+    Every file the dataset is loaded from, in load order.
+    */
+    pub fn data_files(&self) -> impl Iterator<Item = &AofInfo> {
+        self.base.iter().chain(self.incrs.iter())
+    }
+
+    /*
+    Appends a new incremental file, which all writes go to from now on.
+    */
+    pub fn add_incr(&mut self, prefix: &str) -> AofInfo {
+        self.cur_incr_seq += 1;
+        let info = AofInfo {
+            name: incr_name(prefix, self.cur_incr_seq),
+            seq: self.cur_incr_seq,
+            file_type: AofFileType::Incr,
+        };
+        self.incrs.push(info.clone());
+        info
+    }
+
+    /*
+    Installs a new base after a rewrite. The old base and the incremental
+    files before `first_kept_incr` are covered by it, so they become history.
+    */
+    pub fn set_base(&mut self, info: AofInfo, first_kept_incr: Option<u64>) {
+        if let Some(mut old) = self.base.replace(info) {
+            old.file_type = AofFileType::History;
+            self.history.push(old);
+        }
+        self.cur_base_seq = self.base.as_ref().map_or(0, |b| b.seq);
+        let (kept, covered): (Vec<AofInfo>, Vec<AofInfo>) = std::mem::take(&mut self.incrs)
+            .into_iter()
+            .partition(|incr| first_kept_incr.is_some_and(|seq| incr.seq >= seq));
+        self.incrs = kept;
+        self.history.extend(covered.into_iter().map(|mut incr| {
+            incr.file_type = AofFileType::History;
+            incr
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = "file appendonly.aof.2.base.rdb seq 2 type b\n\
+                            file appendonly.aof.1.base.rdb seq 1 type h\n\
+                            file appendonly.aof.3.incr.aof seq 3 type i\n\
+                            file appendonly.aof.4.incr.aof seq 4 type i\n";
+
+    fn info(name: &str, seq: u64, file_type: AofFileType) -> AofInfo {
+        AofInfo {
+            name: name.to_string(),
+            seq,
+            file_type,
+        }
+    }
+
+    #[test]
+    fn a_manifest_survives_a_round_trip() {
+        let manifest: AofManifest = AofManifest::parse(MANIFEST).unwrap();
+        assert_eq!(
+            manifest.base,
+            Some(info("appendonly.aof.2.base.rdb", 2, AofFileType::Base))
+        );
+        assert_eq!(
+            manifest.history,
+            vec![info("appendonly.aof.1.base.rdb", 1, AofFileType::History)]
+        );
+        assert_eq!(manifest.incrs.len(), 2);
+        assert_eq!(manifest.cur_base_seq, 2);
+        assert_eq!(manifest.cur_incr_seq, 4);
+        assert_eq!(manifest.to_text(), MANIFEST);
+    }
+
+    #[test]
+    fn comments_blank_lines_and_unknown_keys_are_skipped() {
+        let text: &str = "# written by a newer version\n\
+                          \n\
+                          file appendonly.aof.1.base.rdb seq 1 type b size 10\n\
+                          file appendonly.aof.1.incr.aof seq 1 type i\n";
+        let manifest: AofManifest = AofManifest::parse(text).unwrap();
+        assert_eq!(
+            manifest.to_text(),
+            "file appendonly.aof.1.base.rdb seq 1 type b\n\
+             file appendonly.aof.1.incr.aof seq 1 type i\n"
+        );
+    }
+
+    #[test]
+    fn malformed_manifests_are_refused() {
+        for text in [
+            "",
+            "# nothing but a comment\n",
+            "file appendonly.aof.1.incr.aof seq 1\n",
+            "file appendonly.aof.1.incr.aof seq 1 type\n",
+            "file appendonly.aof.1.incr.aof seq one type i\n",
+            "file appendonly.aof.1.incr.aof seq 1 type x\n",
+            "file a seq 1 type b\nfile b seq 2 type b\n",
+            "file a seq 2 type i\nfile b seq 2 type i\n",
+        ] {
+            assert!(AofManifest::parse(text).is_err(), "{:?} was accepted", text);
+        }
+    }
+
+    #[test]
+    fn new_incremental_files_never_reuse_a_sequence_number() {
+        let mut manifest: AofManifest = AofManifest::parse(MANIFEST).unwrap();
+        manifest.set_base(
+            info("appendonly.aof.3.base.rdb", 3, AofFileType::Base),
+            None,
+        );
+        assert!(manifest.incrs.is_empty());
+        let incr: AofInfo = manifest.add_incr("appendonly.aof");
+        assert_eq!(
+            incr,
+            info("appendonly.aof.5.incr.aof", 5, AofFileType::Incr)
+        );
+    }
+
+    #[test]
+    fn a_new_base_turns_what_it_covers_into_history() {
+        let mut manifest: AofManifest = AofManifest::parse(MANIFEST).unwrap();
+        manifest.add_incr("appendonly.aof");
+        manifest.set_base(
+            info("appendonly.aof.3.base.rdb", 3, AofFileType::Base),
+            Some(5),
+        );
+
+        assert_eq!(manifest.cur_base_seq, 3);
+        assert_eq!(
+            manifest
+                .data_files()
+                .map(|f| f.name.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["appendonly.aof.3.base.rdb", "appendonly.aof.5.incr.aof"]
+        );
+        assert_eq!(
+            manifest.history,
+            vec![
+                info("appendonly.aof.1.base.rdb", 1, AofFileType::History),
+                info("appendonly.aof.2.base.rdb", 2, AofFileType::History),
+                info("appendonly.aof.3.incr.aof", 3, AofFileType::History),
+                info("appendonly.aof.4.incr.aof", 4, AofFileType::History),
+            ]
+        );
+        let reparsed: AofManifest = AofManifest::parse(&manifest.to_text()).unwrap();
+        assert_eq!(reparsed, manifest);
+    }
+}
@@ -0,0 +1,405 @@
+use crate::{
+    aof_manifest::AofManifest,
+    parser::{parse_resp_prefix, RespType},
+    rdb::{parse_rdb_prefix, Rdb, RDB_MAGIC},
+};
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/*
+Offline verification of persistence files, in the spirit of redis-check-rdb
+and redis-check-aof, for use after disk incidents:
+
+    redis-starter-rust check-rdb <file.rdb>
+    redis-starter-rust check-aof [--fix] <file.aof | file.manifest | appendonlydir>
+
+Files are read with the same readers the server loads them with. The tools
+print what they found and the offset of the first byte that doesn't parse,
+and exit with 0 only if everything is valid. With --fix, a damaged AOF is
+truncated at its last valid command (for a multi-part AOF, only the last
+incremental file can be fixed: losing the end of an earlier one would lose
+writes in the middle of the history). A command cut short at the end of the
+file is dropped right away; corruption further up would also drop the valid
+commands after it, so the tool asks for confirmation first.
+*/
+
+pub fn check_rdb_main(args: &[String]) -> i32 {
+    let path: &String = match args {
+        [path] => path,
+        _ => {
+            eprintln!("Usage: redis-starter-rust check-rdb <rdb-file-name>");
+            return 1;
+        }
+    };
+    let bytes: Vec<u8> = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("Cannot open {}: {}", path, e);
+            return 1;
+        }
+    };
+    println!("[offset 0] Checking RDB file {}", path);
+    if check_rdb(&bytes) {
+        println!("\\o/ RDB looks OK! \\o/");
+        0
+    } else {
+        1
+    }
+}
+
+pub fn check_aof_main(args: &[String]) -> i32 {
+    let (fix, path): (bool, &String) = match args {
+        [flag, path] if flag == "--fix" => (true, path),
+        [path] => (false, path),
+        _ => {
+            eprintln!(
+                "Usage: redis-starter-rust check-aof [--fix] <file.aof | file.manifest | dir>"
+            );
+            return 1;
+        }
+    };
+    let path: &Path = Path::new(path);
+    let manifest_path: Option<PathBuf> = if path.is_dir() {
+        match find_manifest(path) {
+            Some(manifest) => Some(manifest),
+            None => {
+                eprintln!("No AOF manifest found in {}", path.display());
+                return 1;
+            }
+        }
+    } else if path.extension().is_some_and(|ext| ext == "manifest") {
+        Some(path.to_path_buf())
+    } else {
+        None
+    };
+
+    let ok: bool = match manifest_path {
+        Some(manifest_path) => check_multi_part_aof(&manifest_path, fix),
+        None => check_aof_file(path, fix),
+    };
+    if ok {
+        0
+    } else {
+        1
+    }
+}
+
+fn find_manifest(dir: &Path) -> Option<PathBuf> {
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "manifest"))
+}
+
+/*
+Parses a whole RDB file and reports its contents. Anything after the
+checksum is an error, as no writer puts data there.
+*/
+fn check_rdb(bytes: &[u8]) -> bool {
+    let size: usize = match check_rdb_prefix(bytes) {
+        Some(size) => size,
+        None => return false,
+    };
+    if size < bytes.len() {
+        println!("--- RDB ERROR DETECTED ---");
+        println!(
+            "[offset {}] {} bytes of trailing data after the checksum",
+            size,
+            bytes.len() - size
+        );
+        println!("[additional info] Total file size: {} bytes", bytes.len());
+        return false;
+    }
+    true
+}
+
+/*
+Parses the RDB snapshot at the start of `bytes` and reports its contents: aux
+fields, and the number of keys per database and per type. Returns the size
+of the snapshot, or None if it doesn't parse.
+*/
+fn check_rdb_prefix(bytes: &[u8]) -> Option<usize> {
+    let (rdb, size): (Rdb, usize) = match parse_rdb_prefix(bytes) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            println!("--- RDB ERROR DETECTED ---");
+            println!("[offset {}] {}", e.offset, e.message);
+            println!("[additional info] Total file size: {} bytes", bytes.len());
+            return None;
+        }
+    };
+    println!("[offset 9] RDB version {}", rdb.version);
+    for (key, value) in &rdb.aux {
+        println!("[info] AUX FIELD {} = '{}'", key, value);
+    }
+
+    // (keys, keys with an expire) per database
+    let mut dbs: BTreeMap<usize, (usize, usize)> = BTreeMap::new();
+    let mut types: BTreeMap<&str, usize> = BTreeMap::new();
+    for entry in &rdb.entries {
+        let counts = dbs.entry(entry.db).or_default();
+        counts.0 += 1;
+        counts.1 += entry.expire_ms.is_some() as usize;
+        // every value we can read is a string for now
+        *types.entry("string").or_default() += 1;
+    }
+    for (db, (keys, expires)) in &dbs {
+        println!(
+            "[info] db {}: {} keys, {} with an expire",
+            db, keys, expires
+        );
+    }
+    for (value_type, keys) in &types {
+        println!("[info] {}: {} keys", value_type, keys);
+    }
+    println!("[info] {} keys read", rdb.entries.len());
+
+    let checksum: &[u8] = &bytes[size.saturating_sub(8)..size];
+    if rdb.version < 5 || checksum.iter().all(|b| *b == 0) {
+        println!("[info] RDB file has no checksum");
+    } else {
+        println!("[info] Checksum OK");
+    }
+    Some(size)
+}
+
+/*
+Checks every file listed by a multi-part AOF manifest, in load order.
+*/
+fn check_multi_part_aof(manifest_path: &Path, fix: bool) -> bool {
+    println!("Start checking Multi Part AOF");
+    let text: String = match fs::read_to_string(manifest_path) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("Cannot open {}: {}", manifest_path.display(), e);
+            return false;
+        }
+    };
+    if !text.is_empty() && !text.ends_with('\n') {
+        println!(
+            "The AOF manifest {} ends with a partial line",
+            manifest_path.display()
+        );
+        return false;
+    }
+    let manifest: AofManifest = match AofManifest::parse(&text) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            println!("Invalid AOF manifest {}: {}", manifest_path.display(), e);
+            return false;
+        }
+    };
+    let dir: &Path = manifest_path.parent().unwrap_or(Path::new("."));
+    let files: Vec<PathBuf> = manifest
+        .data_files()
+        .map(|info| dir.join(&info.name))
+        .collect();
+    for (idx, file) in files.iter().enumerate() {
+        let last: bool = idx + 1 == files.len();
+        if !check_aof_file(file, fix && last) {
+            if fix && !last {
+                println!("Only the last AOF file can be fixed");
+            }
+            return false;
+        }
+    }
+    println!("All AOF files and manifest are valid");
+    true
+}
+
+/*
+Checks a single AOF file: a sequence of RESP commands, which are counted by
+name, possibly after an RDB preamble (a base file, or a single-file AOF from
+an older version).
+*/
+fn check_aof_file(path: &Path, fix: bool) -> bool {
+    let bytes: Vec<u8> = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("Cannot open {}: {}", path.display(), e);
+            return false;
+        }
+    };
+    let mut pos: usize = 0;
+    if bytes.starts_with(RDB_MAGIC) {
+        println!(
+            "The AOF {} has an RDB preamble, checking it",
+            path.display()
+        );
+        pos = match check_rdb_prefix(&bytes) {
+            Some(size) => size,
+            None => return false,
+        };
+        if pos < bytes.len() {
+            println!(
+                "[offset {}] Checking the commands after the RDB preamble",
+                pos
+            );
+        }
+    }
+
+    let mut commands: BTreeMap<String, usize> = BTreeMap::new();
+    let mut error: Option<&str> = None;
+    // only a command cut short at the end of the file is fixed without asking
+    let mut truncated_tail: bool = false;
+    while pos < bytes.len() {
+        match parse_resp_prefix(&bytes[pos..]) {
+            Ok(Some((RespType::Array(args), size))) if !args.is_empty() => {
+                let name: String = match &args[0] {
+                    RespType::BulkString(name) => name.to_lowercase(),
+                    _ => {
+                        error = Some("command name is not a bulk string");
+                        break;
+                    }
+                };
+                *commands.entry(name).or_default() += 1;
+                pos += size;
+            }
+            Ok(Some(_)) | Err(_) => {
+                error = Some("bad file format");
+                break;
+            }
+            Ok(None) => {
+                error = Some("truncated command at the end of the file");
+                truncated_tail = true;
+                break;
+            }
+        }
+    }
+
+    for (name, count) in &commands {
+        println!("[info] {}: {} commands", name, count);
+    }
+    let ok_up_to_line: usize = bytes[..pos].iter().filter(|b| **b == b'\n').count() + 1;
+    println!(
+        "AOF analyzed: filename={}, size={}, ok_up_to={}, ok_up_to_line={}, diff={}",
+        path.display(),
+        bytes.len(),
+        pos,
+        ok_up_to_line,
+        bytes.len() - pos
+    );
+    let error: &str = match error {
+        Some(error) => error,
+        None => {
+            println!("AOF {} is valid", path.display());
+            return true;
+        }
+    };
+    println!("[offset {}] {}", pos, error);
+    if !fix {
+        println!(
+            "AOF {} is not valid. Use the --fix option to try fixing it.",
+            path.display()
+        );
+        return false;
+    }
+    if !truncated_tail {
+        println!(
+            "This will shrink the AOF {} from {} bytes, with {} bytes, to {} bytes",
+            path.display(),
+            bytes.len(),
+            bytes.len() - pos,
+            pos
+        );
+        if !confirm("Continue? [y/N]: ") {
+            println!("Aborting...");
+            return false;
+        }
+    }
+    match OpenOptions::new()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_len(pos as u64))
+    {
+        Ok(()) => {
+            println!("Successfully truncated AOF {}", path.display());
+            true
+        }
+        Err(e) => {
+            println!("Failed to truncate AOF {}: {}", path.display(), e);
+            false
+        }
+    }
+}
+
+/*
+Asks the operator a yes/no question on stdin. Anything but "y" is a no,
+including a closed stdin.
+*/
+fn confirm(question: &str) -> bool {
+    print!("{}", question);
+    let _ = io::stdout().flush();
+    let mut answer: String = String::new();
+    match io::stdin().read_line(&mut answer) {
+        Ok(_) => answer.trim().eq_ignore_ascii_case("y"),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{rdb::RdbWriter, test_dir::TempDir};
+
+    fn snapshot() -> Vec<u8> {
+        let mut writer = RdbWriter::new();
+        writer.select_db(0, 1, 0);
+        writer.string_entry("a", "1", None);
+        writer.finish()
+    }
+
+    fn command(args: &[&str]) -> Vec<u8> {
+        let args: Vec<RespType> = args
+            .iter()
+            .map(|arg| RespType::BulkString(arg.to_string()))
+            .collect();
+        RespType::Array(args).to_resp_bytes()
+    }
+
+    #[test]
+    fn bytes_after_the_rdb_checksum_are_an_error() {
+        let mut bytes: Vec<u8> = snapshot();
+        assert!(check_rdb(&bytes));
+        bytes.extend_from_slice(b"junk");
+        assert!(!check_rdb(&bytes));
+    }
+
+    #[test]
+    fn the_commands_after_an_rdb_preamble_are_checked() {
+        let dir = TempDir::new();
+        let path: PathBuf = dir.path().join("appendonly.aof");
+        let mut bytes: Vec<u8> = snapshot();
+        bytes.extend(command(&["SET", "b", "2"]));
+        fs::write(&path, &bytes).unwrap();
+        assert!(check_aof_file(&path, false));
+
+        let mut corrupt: Vec<u8> = bytes.clone();
+        corrupt.extend_from_slice(b"#garbage\r\n");
+        corrupt.extend(command(&["SET", "c", "3"]));
+        fs::write(&path, &corrupt).unwrap();
+        assert!(!check_aof_file(&path, false));
+        assert_eq!(fs::read(&path).unwrap(), corrupt);
+    }
+
+    #[test]
+    fn fix_truncates_a_command_cut_short_after_an_rdb_preamble() {
+        let dir = TempDir::new();
+        let path: PathBuf = dir.path().join("appendonly.aof");
+        let mut bytes: Vec<u8> = snapshot();
+        bytes.extend(command(&["SET", "b", "2"]));
+        let complete: usize = bytes.len();
+        bytes.extend(&command(&["SET", "c", "3"])[..10]);
+        fs::write(&path, &bytes).unwrap();
+
+        assert!(!check_aof_file(&path, false));
+        assert!(check_aof_file(&path, true));
+        assert_eq!(fs::read(&path).unwrap(), &bytes[..complete]);
+        assert!(check_aof_file(&path, false));
+    }
+}
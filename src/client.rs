@@ -0,0 +1,86 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/*
+How PSYNC answered: FULLRESYNC, to be followed by an RDB snapshot, or
+CONTINUE, to be followed by the part of the replication backlog from the
+given offset on. A diskless FULLRESYNC is only answered once its sync
+starts.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resync {
+    Full,
+    Diskless,
+    Partial(u64),
+}
+
+/*
+What a blocked WAIT (or WAITAOF, with `aof`) waits for: `numreplicas`
+replicas, and `numlocal` local fsyncs, that reached `offset`. None as the
+deadline blocks forever.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WaitRequest {
+    pub aof: bool,
+    pub numlocal: i64,
+    pub numreplicas: i64,
+    pub offset: u64,
+    pub deadline: Option<Instant>,
+}
+
+/*
+State that belongs to one connection rather than to the whole server. The
+connection thread owns it and hands it to `ServerState::execute` with every
+command.
+- id: unique for the lifetime of the process.
+- db: the database it has SELECTed.
+- resync: set by PSYNC when the connection must be turned into a replica
+  once the reply is out (see `Resync`).
+- woff: the replication offset right after its last write, which WAIT waits
+  for replicas to reach.
+- skip_reply: the command gets no reply, like REPLCONF ACK.
+- repl_listening_port / repl_capa: what a replica announced with REPLCONF
+  during its handshake.
+- wait: set by WAIT and WAITAOF when the connection has to block until
+  enough acknowledgements arrive.
+- master: the link a replica applies its master's write stream from, which
+  is not subject to read-only mode.
+*/
+#[derive(Debug, Clone)]
+pub struct Client {
+    pub id: u64,
+    pub db: usize,
+    pub resync: Option<Resync>,
+    pub woff: u64,
+    pub skip_reply: bool,
+    pub wait: Option<WaitRequest>,
+    pub repl_listening_port: u16,
+    pub repl_capa: Vec<String>,
+    pub master: bool,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Client {
+    pub fn new() -> Self {
+        Client {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            db: 0,
+            resync: None,
+            woff: 0,
+            skip_reply: false,
+            wait: None,
+            repl_listening_port: 0,
+            repl_capa: Vec::new(),
+            master: false,
+        }
+    }
+}
@@ -0,0 +1,257 @@
+use crate::server::ServerAddr;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_PORT: u16 = 6379;
+pub const DEFAULT_DATABASES: usize = 16;
+pub const DEFAULT_DBFILENAME: &str = "dump.rdb";
+pub const DEFAULT_APPENDFILENAME: &str = "appendonly.aof";
+pub const DEFAULT_APPENDDIRNAME: &str = "appendonlydir";
+pub const DEFAULT_REPL_BACKLOG_SIZE: usize = 1024 * 1024;
+pub const DEFAULT_REPL_DISKLESS_SYNC_DELAY: u64 = 5;
+
+// save after 3600s if at least 1 key changed, after 300s if 100 did, ...
+pub const DEFAULT_SAVE_POINTS: &[(u64, u64)] = &[(3600, 1), (300, 100), (60, 10000)];
+
+/*
+When the append only file is fsynced: after every write, once per second
+from the cron, or never (left to the operating system).
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AppendFsync {
+    Always,
+    Everysec,
+    No,
+}
+
+impl AppendFsync {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "always" => Ok(AppendFsync::Always),
+            "everysec" => Ok(AppendFsync::Everysec),
+            "no" => Ok(AppendFsync::No),
+            _ => Err(format!("Invalid appendfsync: {}", value)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AppendFsync::Always => "always",
+            AppendFsync::Everysec => "everysec",
+            AppendFsync::No => "no",
+        }
+    }
+}
+
+/*
+How a replica loads the RDB of a full sync: after saving it as its own RDB
+file, or straight from the socket into a new keyspace that replaces the old
+one at once (always, or only when there is no data to lose).
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReplDisklessLoad {
+    Disabled,
+    OnEmptyDb,
+    Swapdb,
+}
+
+impl ReplDisklessLoad {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "disabled" => Ok(ReplDisklessLoad::Disabled),
+            "on-empty-db" => Ok(ReplDisklessLoad::OnEmptyDb),
+            "swapdb" => Ok(ReplDisklessLoad::Swapdb),
+            _ => Err(format!("Invalid repl-diskless-load: {}", value)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReplDisklessLoad::Disabled => "disabled",
+            ReplDisklessLoad::OnEmptyDb => "on-empty-db",
+            ReplDisklessLoad::Swapdb => "swapdb",
+        }
+    }
+}
+
+/*
+Startup configuration, filled in from the command line flags.
+*/
+#[derive(Clone)]
+pub struct Config {
+    pub port: u16,
+    pub replica_of: Option<ServerAddr>,
+    pub databases: usize,
+    pub dir: String,
+    pub dbfilename: String,
+    pub save: Vec<(u64, u64)>,
+    pub appendonly: bool,
+    pub appendfilename: String,
+    pub appenddirname: String,
+    pub appendfsync: AppendFsync,
+    pub aof_load_truncated: bool,
+    pub aof_use_rdb_preamble: bool,
+    pub repl_backlog_size: usize,
+    pub replica_read_only: bool,
+    pub replica_serve_stale_data: bool,
+    pub repl_diskless_sync: bool,
+    pub repl_diskless_sync_delay: u64,
+    pub repl_diskless_load: ReplDisklessLoad,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            port: DEFAULT_PORT,
+            replica_of: None,
+            databases: DEFAULT_DATABASES,
+            dir: std::env::current_dir()
+                .map(|d| d.display().to_string())
+                .unwrap_or_else(|_| ".".to_string()),
+            dbfilename: DEFAULT_DBFILENAME.to_string(),
+            save: DEFAULT_SAVE_POINTS.to_vec(),
+            appendonly: false,
+            appendfilename: DEFAULT_APPENDFILENAME.to_string(),
+            appenddirname: DEFAULT_APPENDDIRNAME.to_string(),
+            appendfsync: AppendFsync::Everysec,
+            aof_load_truncated: true,
+            aof_use_rdb_preamble: true,
+            repl_backlog_size: DEFAULT_REPL_BACKLOG_SIZE,
+            replica_read_only: true,
+            replica_serve_stale_data: true,
+            // a full sync sends a length-prefixed RDB right away unless asked
+            repl_diskless_sync: false,
+            repl_diskless_sync_delay: DEFAULT_REPL_DISKLESS_SYNC_DELAY,
+            repl_diskless_load: ReplDisklessLoad::Disabled,
+        }
+    }
+}
+
+impl Config {
+    /*
+    Full path of the RDB snapshot file.
+    */
+    pub fn rdb_path(&self) -> PathBuf {
+        Path::new(&self.dir).join(&self.dbfilename)
+    }
+
+    /*
+    Directory of the multi-part append only file.
+    */
+    pub fn aof_dir(&self) -> PathBuf {
+        Path::new(&self.dir).join(&self.appenddirname)
+    }
+
+    /*
+    Full path of a single-file append only file, as written by older
+    versions.
+    */
+    pub fn aof_path(&self) -> PathBuf {
+        Path::new(&self.dir).join(&self.appendfilename)
+    }
+
+    /*
+    Parses a yes/no flag value.
+    */
+    pub fn parse_yes_no(value: &str) -> Result<bool, String> {
+        match value.to_lowercase().as_str() {
+            "yes" => Ok(true),
+            "no" => Ok(false),
+            _ => Err(format!("Expected yes or no, got: {}", value)),
+        }
+    }
+
+    /*
+    Parses a size in bytes, optionally with a unit as in redis.conf: 1k is
+    1000 bytes and 1kb is 1024, likewise for m/mb and g/gb.
+    */
+    pub fn parse_memory(value: &str) -> Result<usize, String> {
+        let value: String = value.to_lowercase();
+        let digits: usize = value
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(value.len());
+        let unit: usize = match &value[digits..] {
+            "" | "b" => 1,
+            "k" => 1000,
+            "kb" => 1024,
+            "m" => 1000 * 1000,
+            "mb" => 1024 * 1024,
+            "g" => 1000 * 1000 * 1000,
+            "gb" => 1024 * 1024 * 1024,
+            _ => return Err(format!("Invalid memory size: {}", value)),
+        };
+        value[..digits]
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| n.checked_mul(unit))
+            .ok_or_else(|| format!("Invalid memory size: {}", value))
+    }
+
+    /*
+    Parses save points in the `save` directive format: "<seconds> <changes>"
+    pairs separated by spaces. An empty string disables snapshotting.
+    */
+    pub fn parse_save_points(spec: &str) -> Result<Vec<(u64, u64)>, String> {
+        let numbers: Vec<u64> = spec
+            .split_whitespace()
+            .map(|n| n.parse::<u64>())
+            .collect::<Result<Vec<u64>, _>>()
+            .map_err(|_| format!("Invalid save parameters: {}", spec))?;
+        let pairs = numbers.chunks_exact(2);
+        if !pairs.remainder().is_empty() {
+            return Err(format!("Invalid save parameters: {}", spec));
+        }
+        Ok(pairs.map(|p| (p[0], p[1])).collect())
+    }
+
+    /*
+    Parameters exposed through CONFIG GET, as (name, value) pairs.
+    */
+    pub fn params(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("port", self.port.to_string()),
+            ("databases", self.databases.to_string()),
+            ("dir", self.dir.clone()),
+            ("dbfilename", self.dbfilename.clone()),
+            (
+                "save",
+                self.save
+                    .iter()
+                    .map(|(seconds, changes)| format!("{} {}", seconds, changes))
+                    .collect::<Vec<String>>()
+                    .join(" "),
+            ),
+            ("appendonly", yes_no(self.appendonly)),
+            ("appendfilename", self.appendfilename.clone()),
+            ("appenddirname", self.appenddirname.clone()),
+            ("appendfsync", self.appendfsync.as_str().to_string()),
+            ("aof-load-truncated", yes_no(self.aof_load_truncated)),
+            ("aof-use-rdb-preamble", yes_no(self.aof_use_rdb_preamble)),
+            ("repl-backlog-size", self.repl_backlog_size.to_string()),
+            ("replica-read-only", yes_no(self.replica_read_only)),
+            (
+                "replica-serve-stale-data",
+                yes_no(self.replica_serve_stale_data),
+            ),
+            ("repl-diskless-sync", yes_no(self.repl_diskless_sync)),
+            (
+                "repl-diskless-sync-delay",
+                self.repl_diskless_sync_delay.to_string(),
+            ),
+            (
+                "repl-diskless-load",
+                self.repl_diskless_load.as_str().to_string(),
+            ),
+            (
+                "replicaof",
+                match &self.replica_of {
+                    Some(addr) => format!("{} {}", addr._ip, addr._port),
+                    None => String::new(),
+                },
+            ),
+        ]
+    }
+}
+
+fn yes_no(value: bool) -> String {
+    if value { "yes" } else { "no" }.to_string()
+}
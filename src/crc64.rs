@@ -0,0 +1,40 @@
+/*
+CRC-64/Jones, the checksum Redis appends to RDB files and DUMP payloads.
+Reflected input and output, polynomial 0xad93d23594c935a9, no final xor;
+crc64(0, b"123456789") == 0xe9c6d914c4b8d9ca.
+*/
+
+// 0xad93d23594c935a9 with its bits reversed, for the reflected algorithm
+const POLY_REFLECTED: u64 = 0x95ac_9329_ac4b_c9b5;
+
+const TABLE: [u64; 256] = build_table();
+
+const fn build_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY_REFLECTED
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/*
+Continues the checksum `crc` over `data`; start with 0.
+*/
+pub fn crc64(mut crc: u64, data: &[u8]) -> u64 {
+    for byte in data {
+        crc = TABLE[((crc ^ *byte as u64) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
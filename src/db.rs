@@ -0,0 +1,64 @@
+use crate::dict::Dict;
+
+/*
+One logical database, as addressed by SELECT: the key-value pairs plus the
+expire times (Unix milliseconds) of the keys that have one.
+*/
+#[derive(Clone, Default)]
+pub struct Database {
+    pub dict: Dict<String>,
+    pub expires: Dict<u64>,
+
+    // where the active expire cycle left off in `expires`
+    pub expires_cursor: u64,
+}
+
+impl Database {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.dict.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dict.is_empty()
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        self.expires.remove(key);
+        self.dict.remove(key)
+    }
+
+    /*
+    Walks `expires` from `expires_cursor` until at least `count` keys have been
+    sampled (or a full pass completes) and deletes the ones whose expire time
+    is before `now`. Returns how many keys were sampled and the ones that
+    expired.
+    */
+    pub fn expire_sample(&mut self, now: u64, count: usize) -> (usize, Vec<String>) {
+        let mut sampled: usize = 0;
+        let mut expired_keys: Vec<String> = Vec::new();
+
+        // sample whole buckets, but don't walk an almost empty table forever
+        let mut max_buckets: usize = count * 20;
+        loop {
+            self.expires_cursor = self.expires.scan(self.expires_cursor, |key, when| {
+                sampled += 1;
+                if now > *when {
+                    expired_keys.push(key.clone());
+                }
+            });
+            max_buckets -= 1;
+            if self.expires_cursor == 0 || max_buckets == 0 || sampled >= count {
+                break;
+            }
+        }
+
+        for key in &expired_keys {
+            self.remove(key);
+        }
+        (sampled, expired_keys)
+    }
+}
@@ -0,0 +1,247 @@
+use std::{collections::hash_map::RandomState, hash::BuildHasher};
+
+/*
+A chained hash table keyed by String, modeled after Redis' dict.c.
+
+We keep our own table (instead of std's HashMap) because cursor-based iteration
+(SCAN, and later HSCAN/SSCAN/ZSCAN) needs to know which bucket a key lives in.
+The table size is always a power of two, so a key's bucket is `hash & mask`,
+and `scan` walks the buckets with a reverse-binary cursor: it increments the
+*high* bits of the cursor first. Because of that, a table that doubles or
+halves between two calls still maps every not-yet-visited bucket to a cursor
+greater than the current one, so every key that is present for the whole
+iteration is returned at least once (some may be returned more than once after
+a shrink).
+*/
+const INITIAL_SIZE: usize = 4;
+
+// shrink once fewer than 1/MIN_FILL of the buckets are in use
+const MIN_FILL: usize = 8;
+
+#[derive(Clone)]
+pub struct Dict<V> {
+    table: Vec<Vec<(String, V)>>,
+    len: usize,
+    hasher: RandomState,
+}
+
+impl<V> Default for Dict<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V> Dict<V> {
+    pub fn new() -> Self {
+        Dict {
+            table: Self::empty_table(INITIAL_SIZE),
+            len: 0,
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn empty_table(size: usize) -> Vec<Vec<(String, V)>> {
+        (0..size).map(|_| Vec::new()).collect()
+    }
+
+    fn mask(&self) -> u64 {
+        (self.table.len() - 1) as u64
+    }
+
+    fn bucket_of(&self, key: &str) -> usize {
+        (self.hasher.hash_one(key) & self.mask()) as usize
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: &str) -> Option<&V> {
+        self.table[self.bucket_of(key)]
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v)
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut V> {
+        let idx = self.bucket_of(key);
+        self.table[idx]
+            .iter_mut()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /*
+    Inserts or overwrites `key`, returning the previous value if there was one.
+    */
+    pub fn insert(&mut self, key: String, value: V) -> Option<V> {
+        if let Some(slot) = self.get_mut(&key) {
+            return Some(std::mem::replace(slot, value));
+        }
+        if self.len >= self.table.len() {
+            self.resize((self.len + 1).next_power_of_two());
+        }
+        let idx = self.bucket_of(&key);
+        self.table[idx].push((key, value));
+        self.len += 1;
+        None
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<V> {
+        let idx = self.bucket_of(key);
+        let pos = self.table[idx].iter().position(|(k, _)| k == key)?;
+        let (_, value) = self.table[idx].swap_remove(pos);
+        self.len -= 1;
+        if self.table.len() > INITIAL_SIZE && self.len * MIN_FILL < self.table.len() {
+            self.resize(self.len.next_power_of_two().max(INITIAL_SIZE));
+        }
+        Some(value)
+    }
+
+    pub fn clear(&mut self) {
+        self.table = Self::empty_table(INITIAL_SIZE);
+        self.len = 0;
+    }
+
+    /*
+    Rehashes every entry into a table of `size` buckets. The whole table is
+    behind the server lock, so unlike Redis we rehash in one go instead of
+    incrementally.
+    */
+    fn resize(&mut self, size: usize) {
+        let old = std::mem::replace(&mut self.table, Self::empty_table(size));
+        for (key, value) in old.into_iter().flatten() {
+            let idx = self.bucket_of(&key);
+            self.table[idx].push((key, value));
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &V)> {
+        self.table.iter().flatten().map(|(k, v)| (k, v))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.iter().map(|(k, _)| k)
+    }
+
+    /*
+    Visits every entry of the bucket addressed by `cursor` and returns the
+    cursor to continue from. A returned cursor of 0 means the iteration is
+    complete.
+    */
+    pub fn scan<F>(&self, cursor: u64, mut visit: F) -> u64
+    where
+        F: FnMut(&String, &V),
+    {
+        if self.is_empty() {
+            return 0;
+        }
+        let mask = self.mask();
+        for (key, value) in &self.table[(cursor & mask) as usize] {
+            visit(key, value);
+        }
+
+        // set the unmasked bits so incrementing the reversed cursor carries
+        // straight into the masked bits, then increment the reversed cursor
+        let mut v = cursor | !mask;
+        v = v.reverse_bits();
+        v = v.wrapping_add(1);
+        v.reverse_bits()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn dict_with(keys: impl Iterator<Item = String>) -> Dict<()> {
+        let mut dict: Dict<()> = Dict::new();
+        for key in keys {
+            dict.insert(key, ());
+        }
+        dict
+    }
+
+    /*
+    Scans `steps` buckets from `cursor`, adding the keys seen to `seen`.
+    Returns the cursor to continue from.
+    */
+    fn scan_steps(
+        dict: &Dict<()>,
+        mut cursor: u64,
+        steps: usize,
+        seen: &mut HashSet<String>,
+    ) -> u64 {
+        for _ in 0..steps {
+            cursor = dict.scan(cursor, |key, _| {
+                seen.insert(key.clone());
+            });
+            if cursor == 0 {
+                break;
+            }
+        }
+        cursor
+    }
+
+    fn scan_to_end(dict: &Dict<()>, mut cursor: u64, seen: &mut HashSet<String>) {
+        while cursor != 0 {
+            cursor = scan_steps(dict, cursor, 1, seen);
+        }
+    }
+
+    #[test]
+    fn a_full_scan_returns_every_key() {
+        let dict: Dict<()> = dict_with((0..1000).map(|i| format!("key:{}", i)));
+        let mut seen: HashSet<String> = HashSet::new();
+        let cursor: u64 = scan_steps(&dict, 0, 1, &mut seen);
+        scan_to_end(&dict, cursor, &mut seen);
+        assert_eq!(seen.len(), 1000);
+        assert_eq!(Dict::<()>::new().scan(0, |_, _| panic!()), 0);
+    }
+
+    #[test]
+    fn scan_returns_every_key_across_a_growth() {
+        let kept: Vec<String> = (0..100).map(|i| format!("kept:{}", i)).collect();
+        let mut dict: Dict<()> = dict_with(kept.iter().cloned());
+        let mut seen: HashSet<String> = HashSet::new();
+        let cursor: u64 = scan_steps(&dict, 0, 20, &mut seen);
+        assert_ne!(cursor, 0);
+
+        let buckets: usize = dict.table.len();
+        for i in 0..5000 {
+            dict.insert(format!("new:{}", i), ());
+        }
+        assert!(dict.table.len() > buckets);
+        scan_to_end(&dict, cursor, &mut seen);
+        assert!(kept.iter().all(|key| seen.contains(key)));
+    }
+
+    #[test]
+    fn scan_returns_every_key_across_a_shrink() {
+        let kept: Vec<String> = (0..50).map(|i| format!("kept:{}", i)).collect();
+        let mut dict: Dict<()> = dict_with(kept.iter().cloned());
+        for i in 0..5000 {
+            dict.insert(format!("gone:{}", i), ());
+        }
+        let mut seen: HashSet<String> = HashSet::new();
+        let cursor: u64 = scan_steps(&dict, 0, 1000, &mut seen);
+        assert_ne!(cursor, 0);
+
+        let buckets: usize = dict.table.len();
+        for i in 0..5000 {
+            dict.remove(&format!("gone:{}", i));
+        }
+        assert!(dict.table.len() < buckets);
+        scan_to_end(&dict, cursor, &mut seen);
+        assert!(kept.iter().all(|key| seen.contains(key)));
+    }
+}
@@ -0,0 +1,216 @@
+/*
+Redis-compatible glob-style pattern matching, as used by KEYS, SCAN MATCH and
+friends. Supports:
+- `*` matches any sequence of bytes (including none)
+- `?` matches exactly one byte
+- `[abc]`, `[a-z]` and `[^x]` match (or exclude) a set of bytes
+- `\x` matches `x` literally
+Matching is done on raw bytes, just like Redis does.
+*/
+
+// deep nesting of `*` could otherwise exhaust the stack on hostile patterns
+const MAX_NESTING: usize = 1000;
+
+pub fn glob_match(pattern: &str, string: &str) -> bool {
+    glob_match_bytes(pattern.as_bytes(), string.as_bytes(), false)
+}
+
+pub fn glob_match_bytes(pattern: &[u8], string: &[u8], nocase: bool) -> bool {
+    let mut skip_longer_matches = false;
+    match_impl(pattern, string, nocase, &mut skip_longer_matches, 0)
+}
+
+fn eq_byte(a: u8, b: u8, nocase: bool) -> bool {
+    if nocase {
+        a.eq_ignore_ascii_case(&b)
+    } else {
+        a == b
+    }
+}
+
+/*
+Direct port of `stringmatchlen_impl` from Redis' util.c. `skip_longer_matches`
+short-circuits the `*` backtracking once a suffix is known to never match, which
+keeps patterns such as `a*a*a*a*b` from going exponential.
+*/
+fn match_impl(
+    pattern: &[u8],
+    string: &[u8],
+    nocase: bool,
+    skip_longer_matches: &mut bool,
+    nesting: usize,
+) -> bool {
+    if nesting > MAX_NESTING {
+        return false;
+    }
+
+    let mut p: usize = 0;
+    let mut s: usize = 0;
+
+    while p < pattern.len() && s < string.len() {
+        match pattern[p] {
+            b'*' => {
+                while p + 1 < pattern.len() && pattern[p + 1] == b'*' {
+                    p += 1;
+                }
+                if p + 1 == pattern.len() {
+                    return true;
+                }
+                while s < string.len() {
+                    if match_impl(
+                        &pattern[p + 1..],
+                        &string[s..],
+                        nocase,
+                        skip_longer_matches,
+                        nesting + 1,
+                    ) {
+                        return true;
+                    }
+                    if *skip_longer_matches {
+                        return false;
+                    }
+                    s += 1;
+                }
+                *skip_longer_matches = true;
+                return false;
+            }
+            b'?' => {
+                s += 1;
+            }
+            b'[' => {
+                p += 1;
+                let not = p < pattern.len() && pattern[p] == b'^';
+                if not {
+                    p += 1;
+                }
+                let mut matched = false;
+                loop {
+                    if p >= pattern.len() {
+                        // unterminated class: treat the end of the pattern as `]`
+                        p -= 1;
+                        break;
+                    } else if pattern[p] == b'\\' && pattern.len() - p >= 2 {
+                        p += 1;
+                        if pattern[p] == string[s] {
+                            matched = true;
+                        }
+                    } else if pattern[p] == b']' {
+                        break;
+                    } else if pattern.len() - p >= 3 && pattern[p + 1] == b'-' {
+                        let mut start = pattern[p];
+                        let mut end = pattern[p + 2];
+                        let mut c = string[s];
+                        if start > end {
+                            std::mem::swap(&mut start, &mut end);
+                        }
+                        if nocase {
+                            start = start.to_ascii_lowercase();
+                            end = end.to_ascii_lowercase();
+                            c = c.to_ascii_lowercase();
+                        }
+                        p += 2;
+                        if c >= start && c <= end {
+                            matched = true;
+                        }
+                    } else if eq_byte(pattern[p], string[s], nocase) {
+                        matched = true;
+                    }
+                    p += 1;
+                }
+                if not {
+                    matched = !matched;
+                }
+                if !matched {
+                    return false;
+                }
+                s += 1;
+            }
+            b'\\' if pattern.len() - p >= 2 => {
+                p += 1;
+                if !eq_byte(pattern[p], string[s], nocase) {
+                    return false;
+                }
+                s += 1;
+            }
+            c => {
+                if !eq_byte(c, string[s], nocase) {
+                    return false;
+                }
+                s += 1;
+            }
+        }
+        p += 1;
+        if s == string.len() {
+            while p < pattern.len() && pattern[p] == b'*' {
+                p += 1;
+            }
+            break;
+        }
+    }
+
+    p == pattern.len() && s == string.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        // as in Redis, nothing is matched against an empty string
+        assert!(!glob_match("*", ""));
+        assert!(glob_match("h*o", "hello"));
+        assert!(glob_match("h*o", "ho"));
+        assert!(!glob_match("h*o", "hola"));
+        assert!(glob_match("h?llo", "hello"));
+        assert!(!glob_match("h?llo", "hllo"));
+        assert!(glob_match("**a", "a"));
+        assert!(glob_match("a*", "a"));
+        assert!(!glob_match("", "a"));
+    }
+
+    #[test]
+    fn escapes_match_literally() {
+        assert!(glob_match("a\\*", "a*"));
+        assert!(!glob_match("a\\*", "ab"));
+        assert!(glob_match("\\?", "?"));
+        assert!(!glob_match("\\?", "x"));
+        assert!(glob_match("[\\]]", "]"));
+        assert!(glob_match("[\\-]", "-"));
+        // a trailing backslash stands for itself
+        assert!(glob_match("a\\", "a\\"));
+    }
+
+    #[test]
+    fn character_classes() {
+        assert!(glob_match("[abc]", "b"));
+        assert!(!glob_match("[abc]", "d"));
+        assert!(glob_match("[a-z]1", "q1"));
+        assert!(!glob_match("[a-z]", "Q"));
+        assert!(glob_match("[^a-z]", "1"));
+        assert!(!glob_match("[^a-z]", "m"));
+        assert!(glob_match("[^abc]x", "dx"));
+        // a reversed range is the same range
+        assert!(glob_match("[z-a]", "k"));
+        // an unterminated class ends with the pattern
+        assert!(glob_match("[abc", "a"));
+        assert!(!glob_match("[abc", "d"));
+    }
+
+    #[test]
+    fn nocase_folds_ascii_letters() {
+        assert!(glob_match_bytes(b"H*O", b"hello", true));
+        assert!(!glob_match_bytes(b"H*O", b"hello", false));
+        assert!(glob_match_bytes(b"[A-C]x", b"bX", true));
+        assert!(glob_match_bytes(b"[^A-C]", b"d", true));
+        assert!(!glob_match_bytes(b"[^A-C]", b"b", true));
+        assert!(glob_match_bytes(b"\\H", b"h", true));
+    }
+
+    #[test]
+    fn many_stars_do_not_backtrack_exponentially() {
+        let string: String = "a".repeat(64);
+        assert!(!glob_match("a*a*a*a*a*a*a*a*a*a*a*a*b", &string));
+        assert!(glob_match("a*a*a*a*a*a*a*a*a*a*a*a*a", &string));
+    }
+}
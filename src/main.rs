@@ -1,7 +1,21 @@
 #![allow(unused_imports)]
+pub mod aof_manifest;
+pub mod check;
+pub mod client;
+pub mod clock;
+pub mod config;
+pub mod crc64;
+pub mod db;
+pub mod dict;
+pub mod glob;
+pub mod master_link;
 pub mod parser;
+pub mod rdb;
+pub mod rdb_json;
 pub mod server;
 pub mod role;
+#[cfg(test)]
+pub mod test_dir;
 
 use std::{
     env,
@@ -9,182 +23,187 @@ use std::{
     net::{TcpListener, TcpStream},
     sync::{Arc, Mutex},
     thread,
+    time::{Duration, Instant},
 };
 
-use parser::{parse_resp, parse_retain_cmd, RespType};
-use server::{ServerAddr, ServerState};
+use client::{Client, Resync, WaitRequest};
+use config::{AppendFsync, Config, ReplDisklessLoad};
+use parser::{parse_resp, parse_resp_prefix, RespType};
+use server::{DisklessSync, ServerAddr, ServerState, SyncSnapshot};
 use role::Role;
 
-const DEFAULT_PORT: u16 = 6379;
+// how often a blocked WAIT checks for acknowledgements, in milliseconds
+const WAIT_POLL_MS: u64 = 10;
 
 fn handle_client(mut stream: TcpStream, srv: &Arc<Mutex<ServerState>>, role: Role) {
-    loop {
+    let mut client = Client::new();
+    // bytes read but not parsed yet: a command may span several reads, and a
+    // read may carry several commands
+    let mut pending: Vec<u8> = Vec::new();
+    'conn: loop {
         let mut buf: [u8; 1024] = [0; 1024];
-        let read_res: Result<usize, Error> = stream.read(&mut buf);
-        let msg: parser::RespType;
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(size) => pending.extend_from_slice(&buf[..size]),
+        }
 
-        match read_res {
-            Ok(0) => return,
-            Err(_) => return,
-            Ok(size) => {
-                let command = String::from_utf8_lossy(&buf[..size]);
-                msg = parser::parse_resp(&command).unwrap();
-                println!("{} received command: {:?}", role, msg);
-            }
+        loop {
+            let msg: RespType = match parse_resp_prefix(&pending) {
+                Ok(Some((msg, size))) => {
+                    pending.drain(..size);
+                    msg
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    let reply = RespType::Error(format!("ERR Protocol error: {}", e));
+                    let _ = stream.write_all(reply.to_resp_string().as_bytes());
+                    break 'conn;
+                }
+            };
+            println!("{} received command: {:?}", role, msg);
+            process_command(&mut stream, srv, &mut client, msg);
         }
+    }
+    // if this connection was a replica, stop feeding it
+    srv.lock().unwrap().client_closed(client.id);
+}
 
-        // before it parses the response and and changes the state of the server
-        // it needs to lock the server state, so that no other thread can access it
-        // this scope is NECESSARY to ENSURE the lock is released.
-        {
-            srv.lock().unwrap().update_replication_offset(msg.clone());
-            let parsed_response: RespType = srv.lock().unwrap().execute_resp(msg.clone());
-            let serialized_response: String = parsed_response.to_resp_string();
-            let _ = stream.write(serialized_response.as_bytes());
-            println!("-Sent response: {:?}", serialized_response);
+fn process_command(
+    stream: &mut TcpStream,
+    srv: &Arc<Mutex<ServerState>>,
+    client: &mut Client,
+    msg: RespType,
+) {
+    // before it parses the response and and changes the state of the server
+    // it needs to lock the server state, so that no other thread can access it
+    // this scope is NECESSARY to ENSURE the lock is released.
+    let mut snapshot: Option<SyncSnapshot> = None;
+    let mut attached: bool = false;
+    let mut diskless: bool = false;
+    let serialized_response: Vec<u8> = {
+        let mut guard = srv.lock().unwrap();
+        let parsed_response: RespType = guard.execute(client, msg);
 
-            // check if resp has a slave of command; if it does, extract it
-            // this is a bad way to do it.... idk how else to do it
-            if parse_retain_cmd(&msg.clone()) {
-                match stream.try_clone() {
-                    Ok(cloned_stream) => {
-                        srv.lock().unwrap().retain_slave(cloned_stream);
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to clone stream: {}", e);
-                    }
+        // PSYNC answered: the replica must be attached while we still hold
+        // the lock, together with taking the snapshot for a FULLRESYNC, so
+        // that no write is missed
+        if let Some(resync) = client.resync.take() {
+            match (stream.try_clone(), resync) {
+                (Ok(cloned_stream), Resync::Full) => {
+                    snapshot = Some(guard.start_full_resync(client, cloned_stream));
+                    attached = true;
+                }
+                (Ok(cloned_stream), Resync::Diskless) => {
+                    guard.replica_wait_bgsave_start(client, cloned_stream);
+                    diskless = true;
+                }
+                (Ok(cloned_stream), Resync::Partial(offset)) => {
+                    guard.start_partial_resync(client, cloned_stream, offset);
+                    attached = true;
+                }
+                (Err(e), _) => {
+                    eprintln!("Failed to clone stream: {}", e);
                 }
             }
-
-            // check if resp needs to do a full resync (check for full resync command)
-            // if it does, then send it after the serialized response
-            // this is a bad way to do it.... idk how else to do it
-            if serialized_response.contains("FULLRESYNC") {
-                let full_resync: (String, Vec<u8>) = srv.lock().unwrap().full_resync();
-                let _ = stream.write(full_resync.0.as_bytes());
-                let _ = stream.write(full_resync.1.as_slice());
+        }
+        if std::mem::take(&mut client.skip_reply) {
+            return;
+        }
+        parsed_response.to_resp_bytes()
+    };
+    // a diskless FULLRESYNC is answered once its sync starts, and the write
+    // stream follows the replica's first REPLCONF ACK
+    if diskless {
+        if let Some(sync) = wait_for_diskless_sync(srv, client.id) {
+            let _ = stream.write_all(&sync.reply());
+            srv.lock().unwrap().replica_send_bulk(client.id);
+            if let Err(e) = sync.write_payload(stream) {
+                eprintln!("Failed to send the RDB to the replica: {}", e);
             }
         }
+        return;
+    }
+    // WAIT and WAITAOF block until the replicas acknowledged enough
+    let serialized_response: Vec<u8> = match client.wait.take() {
+        Some(request) => wait_for_acks(srv, &request).to_resp_bytes(),
+        None => serialized_response,
+    };
+    let _ = stream.write_all(&serialized_response);
+    println!(
+        "-Sent response: {:?}",
+        String::from_utf8_lossy(&serialized_response)
+    );
+
+    // serialize and send the snapshot after the +FULLRESYNC reply, without
+    // holding the lock, then let the replica catch up with the writes that
+    // happened in the meantime (and with the backlog after +CONTINUE)
+    if let Some(snapshot) = snapshot {
+        let payload: Vec<u8> = snapshot.to_payload();
+        srv.lock().unwrap().replica_send_bulk(client.id);
+        let _ = stream.write_all(&payload);
+    }
+    if attached {
+        srv.lock().unwrap().replica_online(client.id);
     }
 }
 
-fn request_replication(
-    server_state: Arc<Mutex<ServerState>>,
-    self_port: u16,
-    master_ip: String,
-    master_port: u16,
-) {
-    // send ping
-    let mut stream = TcpStream::connect(format!("{}:{}", master_ip, master_port)).unwrap();
-    let serial_ping: String =
-        RespType::Array(vec![RespType::BulkString("PING".to_string())]).to_resp_string();
-    let _ = stream.write(serial_ping.as_bytes());
-
-    // read pong
-    let mut buf: [u8; 1024] = [0; 1024];
-    let _ = stream.read(&mut buf);
-    let pong = String::from_utf8_lossy(&buf);
-    print!(" Received pong: {}", pong);
-
-    // send replication request
-    let serial_listening_port: String = RespType::Array(vec![
-        RespType::BulkString("REPLCONF".to_string()),
-        RespType::BulkString("listening-port".to_string()),
-        RespType::BulkString(format!("{}", self_port)),
-    ])
-    .to_resp_string();
-    let _ = stream.write(serial_listening_port.as_bytes());
-
-    // read replication response
-    let mut buf: [u8; 1024] = [0; 1024];
-    let _ = stream.read(&mut buf);
-    let replconf = String::from_utf8_lossy(&buf);
-    print!(" Received replconf: {}", replconf);
-
-    // send capabilitiy sync
-    let serial_capa_sync: String = RespType::Array(vec![
-        RespType::BulkString("REPLCONF".to_string()),
-        RespType::BulkString("capa".to_string()),
-        RespType::BulkString("psync2".to_string()),
-    ])
-    .to_resp_string();
-    let _ = stream.write(serial_capa_sync.as_bytes());
-
-    // read replication response
-    let mut buf: [u8; 1024] = [0; 1024];
-    let _ = stream.read(&mut buf);
-    let replconf = String::from_utf8_lossy(&buf);
-    print!(" Received replconf: {}", replconf);
-
-    // send psync
-    let serial_psync: String = RespType::Array(vec![
-        RespType::BulkString("PSYNC".to_string()),
-        RespType::BulkString("?".to_string()),
-        RespType::BulkString("-1".to_string()),
-    ])
-    .to_resp_string();
-    let _ = stream.write(serial_psync.as_bytes());
-
-    // read psync response (replication id and offset)
-    let mut buf: [u8; 1024] = [0; 1024];
-    let _ = stream.read(&mut buf);
-    let _replconf = String::from_utf8_lossy(&buf);
-
-    // read psync response (rdb file)
-    let mut buf: [u8; 1024] = [0; 1024];
-    let _ = stream.read(&mut buf);
-    let _rdb = &buf;
-    println!(" Received rdb: (OUTPUT OMITTED)\n");
-
-    continuous_replication(server_state, stream);
+/*
+Polls the server until a blocked WAIT or WAITAOF can be answered. The lock is
+only held for each check, so the acknowledgements can come in meanwhile.
+*/
+fn wait_for_acks(srv: &Arc<Mutex<ServerState>>, request: &WaitRequest) -> RespType {
+    loop {
+        let timed_out: bool = request
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline);
+        if let Some(reply) = srv.lock().unwrap().wait_reply(request, timed_out) {
+            return reply;
+        }
+        thread::sleep(Duration::from_millis(WAIT_POLL_MS));
+    }
 }
 
-fn continuous_replication(server_state: Arc<Mutex<ServerState>>, mut stream: TcpStream) {
-    // server needs to stay alive to handle replications
-    // minimizes lock contention
+/*
+Polls the server until the diskless sync of the replica on `client_id`
+starts (see `ServerState::replication_cron`). None if the replica is gone.
+*/
+fn wait_for_diskless_sync(
+    srv: &Arc<Mutex<ServerState>>,
+    client_id: u64,
+) -> Option<Arc<DisklessSync>> {
     loop {
-        let mut buf = [0u8; 1024];
-        let msg: RespType = match stream.read(&mut buf) {
-            Ok(0) => break,
-            Ok(size) => {
-                // parse the incoming RESP command
-                let command = String::from_utf8_lossy(&buf[..size]);
-                match parse_resp(&command) {
-                    Ok(r) => {
-                        println!(" slave received command: {:?}", r);
-                        r
-                    }
-                    Err(e) => {
-                        eprintln!(" error parsing replication RESP: {:?}", e);
-                        return;
-                    }
-                }
-            }
-            Err(_) => return,
-        };
-        server_state.lock().unwrap().update_replication_offset(msg.clone());
-        let resp: RespType = server_state.lock().unwrap().execute_resp(msg.clone());
-        println!(" slave sent response: {:?}", resp.to_resp_string());
-        if resp.to_resp_string().contains("ACK") {
-            let _ = stream.write(resp.to_resp_string().as_bytes());
+        match srv.lock().unwrap().replica_diskless_sync(client_id) {
+            Ok(Some(sync)) => return Some(sync),
+            Ok(None) => thread::sleep(Duration::from_millis(WAIT_POLL_MS)),
+            Err(e) => {
+                eprintln!("{}", e);
+                return None;
+            }
         }
-        
     }
 }
 
 fn main() {
-    let mut port: u16 = DEFAULT_PORT;
-    let mut replica_of: Option<ServerAddr> = None;
+    let mut config = Config::default();
 
     let args: Vec<String> = env::args().collect();
 
+    // offline tools for persistence files, see check.rs and rdb_json.rs
+    match args.get(1).map(|arg| arg.as_str()) {
+        Some("check-rdb") => std::process::exit(check::check_rdb_main(&args[2..])),
+        Some("check-aof") => std::process::exit(check::check_aof_main(&args[2..])),
+        Some("rdb-to-json") => std::process::exit(rdb_json::rdb_to_json_main(&args[2..])),
+        Some("json-to-rdb") => std::process::exit(rdb_json::json_to_rdb_main(&args[2..])),
+        _ => {}
+    }
+
     let mut idx: usize = 1; // needs to be one to skip the binary call
     while idx < args.len() {
-        let arg = &args[idx as usize];
+        let arg = &args[idx];
         match arg.as_str() {
             "--port" => {
-                if args.len() > (idx as usize) + 1 {
-                    port = args[(idx + 1) as usize].parse::<u16>().unwrap();
+                if args.len() > idx + 1 {
+                    config.port = args[idx + 1].parse::<u16>().unwrap();
                     idx += 1;
                 } else {
                     eprintln!("Port number not provided");
@@ -192,19 +211,164 @@ fn main() {
                 }
             }
             "--replicaof" => {
-                if args.len() > (idx as usize) + 1 {
+                if args.len() > idx + 1 {
                     // ip + port passed a singular string
-                    let ip_port = args[(idx + 1) as usize].clone();
+                    let ip_port = args[idx + 1].clone();
                     let mut split = ip_port.split(" ");
                     let ip: String = split.next().unwrap().to_string();
                     let port: u16 = split.next().unwrap().parse::<u16>().unwrap();
-                    replica_of = Some(ServerAddr::new(ip, port));
-                    idx += 2;
+                    config.replica_of = Some(ServerAddr::new(ip, port));
+                    idx += 1;
                 } else {
                     eprintln!("Replicaof requires ip and port");
                     return;
                 }
             }
+            "--dir" => {
+                match args.get(idx + 1) {
+                    Some(dir) => config.dir = dir.clone(),
+                    None => {
+                        eprintln!("Dir requires a path");
+                        return;
+                    }
+                }
+                idx += 1;
+            }
+            "--dbfilename" => {
+                match args.get(idx + 1) {
+                    Some(name) => config.dbfilename = name.clone(),
+                    None => {
+                        eprintln!("Dbfilename requires a file name");
+                        return;
+                    }
+                }
+                idx += 1;
+            }
+            "--save" => {
+                match args
+                    .get(idx + 1)
+                    .map(|spec| Config::parse_save_points(spec))
+                {
+                    Some(Ok(save)) => config.save = save,
+                    Some(Err(e)) => {
+                        eprintln!("{}", e);
+                        return;
+                    }
+                    None => {
+                        eprintln!("Save requires save points");
+                        return;
+                    }
+                }
+                idx += 1;
+            }
+            "--databases" => {
+                match args.get(idx + 1).map(|n| n.parse::<usize>()) {
+                    Some(Ok(n)) if n > 0 => config.databases = n,
+                    _ => {
+                        eprintln!("Databases requires a positive number");
+                        return;
+                    }
+                }
+                idx += 1;
+            }
+            "--appendonly"
+            | "--aof-load-truncated"
+            | "--aof-use-rdb-preamble"
+            | "--replica-read-only"
+            | "--replica-serve-stale-data"
+            | "--repl-diskless-sync" => {
+                match args.get(idx + 1).map(|v| Config::parse_yes_no(v)) {
+                    Some(Ok(value)) => match arg.as_str() {
+                        "--appendonly" => config.appendonly = value,
+                        "--aof-load-truncated" => config.aof_load_truncated = value,
+                        "--aof-use-rdb-preamble" => config.aof_use_rdb_preamble = value,
+                        "--replica-read-only" => config.replica_read_only = value,
+                        "--replica-serve-stale-data" => config.replica_serve_stale_data = value,
+                        _ => config.repl_diskless_sync = value,
+                    },
+                    Some(Err(e)) => {
+                        eprintln!("{}", e);
+                        return;
+                    }
+                    None => {
+                        eprintln!("{} requires yes or no", arg);
+                        return;
+                    }
+                }
+                idx += 1;
+            }
+            "--appendfilename" => {
+                match args.get(idx + 1) {
+                    Some(name) => config.appendfilename = name.clone(),
+                    None => {
+                        eprintln!("Appendfilename requires a file name");
+                        return;
+                    }
+                }
+                idx += 1;
+            }
+            "--appenddirname" => {
+                match args.get(idx + 1) {
+                    Some(name) => config.appenddirname = name.clone(),
+                    None => {
+                        eprintln!("Appenddirname requires a directory name");
+                        return;
+                    }
+                }
+                idx += 1;
+            }
+            "--appendfsync" => {
+                match args.get(idx + 1).map(|v| AppendFsync::parse(v)) {
+                    Some(Ok(policy)) => config.appendfsync = policy,
+                    Some(Err(e)) => {
+                        eprintln!("{}", e);
+                        return;
+                    }
+                    None => {
+                        eprintln!("Appendfsync requires always, everysec or no");
+                        return;
+                    }
+                }
+                idx += 1;
+            }
+            "--repl-backlog-size" => {
+                match args.get(idx + 1).map(|v| Config::parse_memory(v)) {
+                    Some(Ok(size)) if size > 0 => config.repl_backlog_size = size,
+                    Some(Err(e)) => {
+                        eprintln!("{}", e);
+                        return;
+                    }
+                    _ => {
+                        eprintln!("Repl-backlog-size requires a positive size");
+                        return;
+                    }
+                }
+                idx += 1;
+            }
+            "--repl-diskless-sync-delay" => {
+                match args.get(idx + 1).map(|v| v.parse::<u64>()) {
+                    Some(Ok(delay)) => config.repl_diskless_sync_delay = delay,
+                    _ => {
+                        eprintln!("Repl-diskless-sync-delay requires a number of seconds");
+                        return;
+                    }
+                }
+                idx += 1;
+            }
+            "--repl-diskless-load" => {
+                match args.get(idx + 1).map(|v| ReplDisklessLoad::parse(v)) {
+                    Some(Ok(load)) => config.repl_diskless_load = load,
+                    Some(Err(e)) => {
+                        eprintln!("{}", e);
+                        return;
+                    }
+                    None => {
+                        eprintln!("Repl-diskless-load requires disabled, on-empty-db or swapdb");
+                        return;
+                    }
+                }
+                idx += 1;
+            }
             _ => {
                 eprintln!("Unknown flag: {}", arg);
                 return;
@@ -213,29 +377,29 @@ fn main() {
         idx += 1;
     }
 
-    let srv = ServerState::new(port, replica_of);
+    let port: u16 = config.port;
+    let mut srv = ServerState::new(config);
+    if let Err(e) = srv.load_data() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
     let server_state = Arc::new(Mutex::new(srv));
     let listener = TcpListener::bind(format!("127.0.0.1:{}", port)).unwrap();
 
-    // needs to request replication if the server is a slave
+    // background housekeeping (active expiry, ...)
+    let cron_state = Arc::clone(&server_state);
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_millis(server::CRON_PERIOD_MS));
+        cron_state.lock().unwrap().server_cron();
+    });
+
+    // the role the server starts with
     let srv_role: Role = server_state.lock().unwrap().get_role();
     println!("Server role: {:?}\n", srv_role);
 
-    // if the server is a slave, then request continuous replication
-    if srv_role == Role::Slave {
-        println!("----- Requesting replication...");
-        let server_state_clone = Arc::clone(&server_state);
-        let replica_of = server_state
-            .lock()
-            .unwrap()
-            .get_replica_of()
-            .clone()
-            .unwrap();
-        std::thread::spawn(move || {
-            request_replication(server_state_clone, port, replica_of._ip, replica_of._port);
-            println!("-No longer replicating from master.");
-        });
-    }
+    // the link with the master, for as long as (and whenever) we are a slave
+    let link_state = Arc::clone(&server_state);
+    thread::spawn(move || master_link::replication_main(link_state, port));
 
     // server state that is shared between threads
     for stream in listener.incoming() {
@@ -0,0 +1,576 @@
+use crate::{
+    client::Client,
+    parser::{parse_resp_prefix, RespType},
+    rdb::{parse_rdb, Rdb},
+    server::{write_file_atomically, ReplState, ServerAddr, ServerState, CRON_PERIOD_MS},
+};
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+/*
+Replica side of replication: the link with the master, run by a dedicated
+thread as a state machine (see `ReplState`):
+
+    Connect -> Connecting -> Handshake -> ReceivePsync -> Transfer -> Connected
+                                                      \-> (CONTINUE) -/
+
+Every reply of the master is checked before moving on. Any failure, the
+master closing the connection, or REPLICAOF shutting it down, drops back to
+Connect, and the next attempt
+waits twice as long as the previous one (up to REPL_RETRY_MAX_MS). Once a
+link got to Connected, the delay starts over. A reconnecting replica asks to
+continue from its offset, so it only needs a new snapshot if the master
+can't serve the missing part from its backlog.
+*/
+
+// delay before the first reconnection attempt, in milliseconds
+const REPL_RETRY_MIN_MS: u64 = 100;
+// upper bound of the reconnection delay, in milliseconds
+const REPL_RETRY_MAX_MS: u64 = 5000;
+// how long the handshake and the transfer wait for the master (repl-timeout)
+const REPL_TIMEOUT_S: u64 = 60;
+// length of the mark that ends a diskless RDB payload
+const RDB_EOF_MARK_SIZE: usize = 40;
+
+/*
+An established link: the master's replies and write stream are read through
+`reader`, which may already hold bytes past the last reply; acknowledgements
+are written to `stream`.
+*/
+struct MasterLink {
+    reader: BufReader<TcpStream>,
+    stream: TcpStream,
+}
+
+/*
+Keeps this server replicating from the master it is configured to follow,
+reconnecting with exponential backoff whenever the link fails. Runs for the
+whole life of the server, idle while it is a master, since REPLICAOF can
+change the role at any time. Never returns.
+*/
+pub fn replication_main(server_state: Arc<Mutex<ServerState>>, self_port: u16) {
+    let mut retry_ms: u64 = REPL_RETRY_MIN_MS;
+    loop {
+        let master: ServerAddr = match server_state.lock().unwrap().get_replica_of() {
+            Some(master) => master,
+            None => {
+                retry_ms = REPL_RETRY_MIN_MS;
+                thread::sleep(Duration::from_millis(CRON_PERIOD_MS));
+                continue;
+            }
+        };
+        let synced = sync_with_master(&server_state, self_port, &master);
+        let result: Result<(), String> = match synced {
+            Ok(link) => {
+                retry_ms = REPL_RETRY_MIN_MS;
+                stream_from_master(&server_state, &master, link)
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            eprintln!(" Master link: {}", e);
+        }
+        if !server_state.lock().unwrap().master_link_closed(&master) {
+            // REPLICAOF: go on with the new master (if any) right away
+            retry_ms = REPL_RETRY_MIN_MS;
+            continue;
+        }
+        println!(
+            " Reconnecting to MASTER {}:{} in {} ms",
+            master._ip, master._port, retry_ms
+        );
+        backoff(&server_state, &master, retry_ms);
+        retry_ms = (retry_ms * 2).min(REPL_RETRY_MAX_MS);
+    }
+}
+
+/*
+Waits `ms` before reconnecting to `master`, unless REPLICAOF changes it
+meanwhile.
+*/
+fn backoff(server_state: &Arc<Mutex<ServerState>>, master: &ServerAddr, ms: u64) {
+    let mut waited: u64 = 0;
+    while waited < ms && server_state.lock().unwrap().following(master) {
+        let step: u64 = CRON_PERIOD_MS.min(ms - waited);
+        thread::sleep(Duration::from_millis(step));
+        waited += step;
+    }
+}
+
+/*
+Connects to the master, goes through the handshake and the PSYNC, and loads
+the snapshot if it sends one.
+*/
+fn sync_with_master(
+    server_state: &Arc<Mutex<ServerState>>,
+    self_port: u16,
+    master: &ServerAddr,
+) -> Result<MasterLink, String> {
+    let set_state = |state: ReplState| -> Result<(), String> {
+        let mut guard = server_state.lock().unwrap();
+        if !guard.following(master) {
+            return Err("REPLICAOF changed the master".to_string());
+        }
+        guard.set_repl_state(state);
+        Ok(())
+    };
+
+    set_state(ReplState::Connecting)?;
+    let mut stream: TcpStream = connect(master)?;
+    server_state
+        .lock()
+        .unwrap()
+        .master_link_connected(master, &stream)?;
+    stream
+        .set_read_timeout(Some(Duration::from_secs(REPL_TIMEOUT_S)))
+        .map_err(|e| format!("Failed to set a timeout on the master link: {}", e))?;
+    let mut reader = BufReader::new(
+        stream
+            .try_clone()
+            .map_err(|e| format!("Failed to clone the master link: {}", e))?,
+    );
+    println!(" Connected to MASTER {}:{}", master._ip, master._port);
+
+    // the master may require AUTH first, anything but an error is fine
+    set_state(ReplState::Handshake)?;
+    send_command(&mut stream, &["PING"])?;
+    let pong: String = read_line(&mut reader)?;
+    if pong.starts_with('-') {
+        return Err(format!("Error reply to PING from master: '{}'", pong));
+    }
+    println!(" Master replied to PING, replication can continue...");
+
+    // older masters don't know these, and can still replicate to us
+    let port: String = self_port.to_string();
+    send_command(&mut stream, &["REPLCONF", "listening-port", &port])?;
+    let reply: String = read_line(&mut reader)?;
+    if reply.starts_with('-') {
+        println!(
+            " (Non critical) Master does not understand REPLCONF listening-port: {}",
+            reply
+        );
+    }
+    send_command(&mut stream, &["REPLCONF", "capa", "eof", "capa", "psync2"])?;
+    let reply: String = read_line(&mut reader)?;
+    if reply.starts_with('-') {
+        println!(
+            " (Non critical) Master does not understand REPLCONF capa: {}",
+            reply
+        );
+    }
+
+    // ask to continue from where we are if we synced before
+    set_state(ReplState::ReceivePsync)?;
+    let (psync_replid, psync_offset) = server_state.lock().unwrap().psync_request();
+    send_command(&mut stream, &["PSYNC", &psync_replid, &psync_offset])?;
+    let reply: String = read_line(&mut reader)?;
+    if let Some(replid) = reply.strip_prefix("+CONTINUE") {
+        // the master streams what we missed, no snapshot
+        let mut guard = server_state.lock().unwrap();
+        if !guard.following(master) {
+            return Err("REPLICAOF changed the master".to_string());
+        }
+        guard.master_continue(replid.trim());
+        guard.set_repl_state(ReplState::Connected);
+        println!(" Partial resynchronization accepted");
+        return finish_sync(reader, stream);
+    }
+    let mut fields = reply.split(' ');
+    let (master_replid, master_offset): (String, u64) = match (
+        fields.next(),
+        fields.next(),
+        fields.next().map(|o| o.parse()),
+    ) {
+        (Some("+FULLRESYNC"), Some(replid), Some(Ok(offset))) => (replid.to_string(), offset),
+        _ if reply.starts_with("-NOMASTERLINK") || reply.starts_with("-LOADING") => {
+            return Err(format!(
+                "Master is currently unable to PSYNC but should be in the future: {}",
+                reply
+            ))
+        }
+        _ => {
+            return Err(format!(
+                "Unexpected reply to PSYNC from master: '{}'",
+                reply
+            ))
+        }
+    };
+    println!(
+        " Full resync from master: {}:{}",
+        master_replid, master_offset
+    );
+
+    // the snapshot is sent as $<len>\r\n<bytes> without a trailing CRLF, or
+    // between $EOF:<mark>\r\n and the mark by a diskless sync, possibly after
+    // newlines the master sends to keep the link alive
+    set_state(ReplState::Transfer)?;
+    let header: String = loop {
+        let line: String = read_line(&mut reader)?;
+        if !line.is_empty() {
+            break line;
+        }
+    };
+    let eof_mark: Option<&str> = header.strip_prefix("$EOF:");
+    let rdb: Vec<u8> = match eof_mark {
+        Some(mark) if mark.len() == RDB_EOF_MARK_SIZE => read_until_mark(&mut reader, mark)?,
+        _ => match header.strip_prefix('$').map(|n| n.parse()) {
+            Some(Ok(len)) => {
+                let mut rdb: Vec<u8> = vec![0u8; len];
+                reader
+                    .read_exact(&mut rdb)
+                    .map_err(|e| format!("I/O error trying to sync with MASTER: {}", e))?;
+                rdb
+            }
+            _ => {
+                return Err(format!(
+                    "Bad protocol from MASTER, the first byte is not '$': '{}'",
+                    header
+                ))
+            }
+        },
+    };
+
+    // unless repl-diskless-load, the snapshot becomes our RDB file first;
+    // either way it is parsed while the old data is still served
+    let rdb_file: Option<PathBuf> = server_state.lock().unwrap().master_rdb_file();
+    if let Some(path) = rdb_file {
+        write_file_atomically(&path, &rdb)?;
+    }
+    let parsed: Rdb = parse_rdb(&rdb).map_err(|e| format!("Bad RDB from master: {}", e))?;
+
+    let mut guard = server_state.lock().unwrap();
+    if !guard.following(master) {
+        return Err("REPLICAOF changed the master".to_string());
+    }
+    let (keys, old) = guard.load_master_rdb(parsed)?;
+    println!(" Received rdb: {} bytes, {} keys loaded\n", rdb.len(), keys);
+    guard.master_full_resync(master_replid, master_offset);
+    guard.set_repl_state(ReplState::Connected);
+    let offset: String = guard.get_replication_offset().to_string();
+    drop(guard);
+    drop(old);
+
+    // after a diskless sync, the master waits to hear we loaded it
+    if eof_mark.is_some() {
+        send_command(&mut stream, &["REPLCONF", "ACK", &offset])?;
+    }
+    finish_sync(reader, stream)
+}
+
+/*
+Reads a diskless RDB payload, up to the `mark` that ends it. The master
+sends nothing after it before our first ACK, so it ends a read.
+*/
+fn read_until_mark(reader: &mut BufReader<TcpStream>, mark: &str) -> Result<Vec<u8>, String> {
+    let mut rdb: Vec<u8> = Vec::new();
+    let mut buf = [0u8; 16 * 1024];
+    while !rdb.ends_with(mark.as_bytes()) {
+        match reader.read(&mut buf) {
+            Ok(0) => return Err("Connection closed by MASTER during the transfer".to_string()),
+            Ok(size) => rdb.extend_from_slice(&buf[..size]),
+            Err(e) => return Err(format!("I/O error trying to sync with MASTER: {}", e)),
+        }
+    }
+    rdb.truncate(rdb.len() - mark.len());
+    Ok(rdb)
+}
+
+/*
+The write stream has no deadline: the master only sends when there are
+writes.
+*/
+fn finish_sync(reader: BufReader<TcpStream>, stream: TcpStream) -> Result<MasterLink, String> {
+    stream
+        .set_read_timeout(None)
+        .map_err(|e| format!("Failed to clear the timeout of the master link: {}", e))?;
+    Ok(MasterLink { reader, stream })
+}
+
+fn connect(master: &ServerAddr) -> Result<TcpStream, String> {
+    let addrs: Vec<SocketAddr> = (master._ip.as_str(), master._port)
+        .to_socket_addrs()
+        .map_err(|e| format!("Unable to resolve MASTER {}: {}", master._ip, e))?
+        .collect();
+    let mut last_error: String = format!("No address found for MASTER {}", master._ip);
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, Duration::from_secs(REPL_TIMEOUT_S)) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = format!("Error condition on socket for SYNC: {}", e),
+        }
+    }
+    Err(last_error)
+}
+
+fn send_command(stream: &mut TcpStream, args: &[&str]) -> Result<(), String> {
+    let command: Vec<RespType> = args
+        .iter()
+        .map(|arg| RespType::BulkString(arg.to_string()))
+        .collect();
+    stream
+        .write_all(&RespType::Array(command).to_resp_bytes())
+        .map_err(|e| format!("Error writing to MASTER: {}", e))
+}
+
+/*
+Reads a reply line of the master, without its CRLF.
+*/
+fn read_line(reader: &mut BufReader<TcpStream>) -> Result<String, String> {
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(0) => Err("Connection closed by MASTER".to_string()),
+        Ok(_) => Ok(line.trim_end_matches(['\r', '\n']).to_string()),
+        Err(e) => Err(format!("Error reading from MASTER: {}", e)),
+    }
+}
+
+/*
+Whether `msg` is the master asking for our offset, the only command of its
+stream that gets a reply.
+*/
+fn is_getack(msg: &RespType) -> bool {
+    match msg {
+        RespType::Array(arr) => matches!(
+            (arr.first(), arr.get(1)),
+            (Some(RespType::BulkString(cmd)), Some(RespType::BulkString(sub)))
+                if cmd.eq_ignore_ascii_case("replconf") && sub.eq_ignore_ascii_case("getack")
+        ),
+        _ => false,
+    }
+}
+
+/*
+Applies the write stream of the master until the link breaks.
+
+The replication offset advances by the exact bytes each command took in the
+stream, so it matches the master's offset whatever the reads cut the stream
+into, and however the commands would serialize again.
+*/
+fn stream_from_master(
+    server_state: &Arc<Mutex<ServerState>>,
+    master: &ServerAddr,
+    link: MasterLink,
+) -> Result<(), String> {
+    let MasterLink {
+        mut reader,
+        mut stream,
+    } = link;
+    // the master's stream SELECTs databases like any other client would
+    let mut master_client = Client::new();
+    master_client.master = true;
+    master_client.db = server_state.lock().unwrap().master_stream_db();
+    // bytes read but not parsed yet: a command may span several reads, and a
+    // read may carry several commands
+    let mut pending: Vec<u8> = Vec::new();
+    loop {
+        let mut buf = [0u8; 1024];
+        match reader.read(&mut buf) {
+            Ok(0) => return Err("Connection closed by MASTER".to_string()),
+            Ok(size) => pending.extend_from_slice(&buf[..size]),
+            Err(e) => return Err(format!("Error reading from MASTER: {}", e)),
+        }
+        for resp in apply_master_stream(server_state, master, &mut master_client, &mut pending)? {
+            println!(" slave sent response: {:?}", resp.to_resp_string());
+            stream
+                .write_all(&resp.to_resp_bytes())
+                .map_err(|e| format!("Error writing to MASTER: {}", e))?;
+        }
+    }
+}
+
+/*
+Applies every complete command at the start of `pending` and removes it,
+leaving a command cut short for the next read. Returns the replies to
+REPLCONF GETACK, the only ones the master reads.
+*/
+fn apply_master_stream(
+    server_state: &Mutex<ServerState>,
+    master: &ServerAddr,
+    master_client: &mut Client,
+    pending: &mut Vec<u8>,
+) -> Result<Vec<RespType>, String> {
+    let mut replies: Vec<RespType> = Vec::new();
+    loop {
+        let (msg, size): (RespType, usize) = match parse_resp_prefix(pending) {
+            Ok(Some(parsed)) => parsed,
+            Ok(None) => return Ok(replies),
+            Err(e) => return Err(format!("Protocol error in the master stream: {}", e)),
+        };
+        let bytes: Vec<u8> = pending.drain(..size).collect();
+        println!(" slave received command: {:?}", msg);
+        let getack: bool = is_getack(&msg);
+
+        // the offset counts what was processed before this command, which
+        // is what REPLCONF GETACK reports
+        let mut guard = server_state.lock().unwrap();
+        if !guard.following(master) {
+            return Err("REPLICAOF changed the master".to_string());
+        }
+        let resp: RespType = guard.execute(master_client, msg);
+        guard.master_stream_consumed(&bytes, master_client.db);
+        drop(guard);
+
+        if getack {
+            replies.push(resp);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn command(args: &[&str]) -> Vec<u8> {
+        let mut out: Vec<u8> = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            out.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+        }
+        out
+    }
+
+    fn bulk(s: &str) -> RespType {
+        RespType::BulkString(s.to_string())
+    }
+
+    /*
+    A replica of `master` that went through a full sync at offset 0.
+    */
+    fn replica(master: &ServerAddr) -> Mutex<ServerState> {
+        let config = Config {
+            replica_of: Some(master.clone()),
+            ..Config::default()
+        };
+        let mut state = ServerState::new(config);
+        state.master_full_resync("a".repeat(40), 0);
+        Mutex::new(state)
+    }
+
+    /*
+    The master stream the tests replay: a SELECT, writes with empty and
+    multibyte values, a GETACK and a write after it. Also returns the offset
+    the GETACK has to report, the bytes that came before it.
+    */
+    fn master_stream() -> (Vec<u8>, u64) {
+        let mut stream: Vec<u8> = command(&["SELECT", "1"]);
+        stream.extend(command(&["SET", "empty", ""]));
+        stream.extend(command(&["SET", "utf8", "héllo wörld"]));
+        let before_getack: u64 = stream.len() as u64;
+        stream.extend(command(&["REPLCONF", "GETACK", "*"]));
+        stream.extend(command(&["SET", "after", "1"]));
+        (stream, before_getack)
+    }
+
+    /*
+    Feeds `stream` in reads of `chunk` bytes, returning the GETACK replies.
+    */
+    fn replay(
+        state: &Mutex<ServerState>,
+        master: &ServerAddr,
+        stream: &[u8],
+        chunk: usize,
+    ) -> Vec<RespType> {
+        let mut master_client = Client::new();
+        master_client.master = true;
+        let mut pending: Vec<u8> = Vec::new();
+        let mut replies: Vec<RespType> = Vec::new();
+        for read in stream.chunks(chunk) {
+            pending.extend_from_slice(read);
+            replies.extend(
+                apply_master_stream(state, master, &mut master_client, &mut pending).unwrap(),
+            );
+        }
+        assert!(pending.is_empty());
+        replies
+    }
+
+    fn assert_applied(state: &Mutex<ServerState>) {
+        let mut guard = state.lock().unwrap();
+        let mut client = Client::new();
+        client.db = 1;
+        for (key, value) in [("empty", ""), ("utf8", "héllo wörld"), ("after", "1")] {
+            let get: RespType = RespType::Array(vec![bulk("GET"), bulk(key)]);
+            assert_eq!(
+                guard.execute(&mut client, get),
+                RespType::SimpleString(value.to_string())
+            );
+        }
+        assert_eq!(guard.master_stream_db(), 1);
+    }
+
+    #[test]
+    fn offset_counts_the_bytes_of_a_fragmented_stream() {
+        let master = ServerAddr::new("127.0.0.1".to_string(), 6379);
+        let (stream, before_getack) = master_stream();
+        for chunk in [1, 2, 7, 16] {
+            let state = replica(&master);
+            let replies: Vec<RespType> = replay(&state, &master, &stream, chunk);
+            assert_eq!(
+                replies,
+                vec![RespType::Array(vec![
+                    bulk("REPLCONF"),
+                    bulk("ACK"),
+                    bulk(&before_getack.to_string()),
+                ])]
+            );
+            assert_eq!(
+                state.lock().unwrap().get_replication_offset(),
+                stream.len() as u64
+            );
+            assert_applied(&state);
+        }
+    }
+
+    #[test]
+    fn offset_counts_the_bytes_of_pipelined_commands() {
+        let master = ServerAddr::new("127.0.0.1".to_string(), 6379);
+        let (stream, before_getack) = master_stream();
+        let state = replica(&master);
+        let replies: Vec<RespType> = replay(&state, &master, &stream, stream.len());
+        assert_eq!(
+            replies,
+            vec![RespType::Array(vec![
+                bulk("REPLCONF"),
+                bulk("ACK"),
+                bulk(&before_getack.to_string()),
+            ])]
+        );
+        assert_eq!(
+            state.lock().unwrap().get_replication_offset(),
+            stream.len() as u64
+        );
+        assert_applied(&state);
+    }
+
+    #[test]
+    fn a_command_cut_short_waits_for_the_next_read() {
+        let master = ServerAddr::new("127.0.0.1".to_string(), 6379);
+        let state = replica(&master);
+        let first: Vec<u8> = command(&["SET", "a", "1"]);
+        let second: Vec<u8> = command(&["SET", "b", "2"]);
+        let mut pending: Vec<u8> = first.clone();
+        pending.extend_from_slice(&second[..second.len() - 3]);
+
+        let mut master_client = Client::new();
+        master_client.master = true;
+        apply_master_stream(&state, &master, &mut master_client, &mut pending).unwrap();
+        assert_eq!(pending, second[..second.len() - 3]);
+        assert_eq!(
+            state.lock().unwrap().get_replication_offset(),
+            first.len() as u64
+        );
+
+        pending.extend_from_slice(&second[second.len() - 3..]);
+        apply_master_stream(&state, &master, &mut master_client, &mut pending).unwrap();
+        assert!(pending.is_empty());
+        assert_eq!(
+            state.lock().unwrap().get_replication_offset(),
+            (first.len() + second.len()) as u64
+        );
+    }
+}
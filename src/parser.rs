@@ -8,6 +8,8 @@ use std::{
 pub enum RespType {
     Array(Vec<RespType>),
     BulkString(String),
+    // a bulk string that isn't valid UTF-8, such as a DUMP payload
+    BulkBytes(Vec<u8>),
     SimpleString(String),
     Error(String),
     Integer(i64),
@@ -19,27 +21,43 @@ impl RespType {
     pub fn to_resp_string(&self) -> String {
         match self {
             RespType::Array(vec) => {
-                if vec.is_empty() {
-                    "$-1\r\n".to_string() // Null array
-                } else {
-                    let elements: String = vec.iter().map(|e| e.to_resp_string()).collect();
-                    format!("*{}\r\n{}", vec.len(), elements)
-                }
+                let elements: String = vec.iter().map(|e| e.to_resp_string()).collect();
+                format!("*{}\r\n{}", vec.len(), elements)
             }
-            RespType::BulkString(str) => {
-                if str.is_empty() {
-                    "$-1\r\n".to_string() // Null bulk string
-                } else {
-                    format!("${}\r\n{}\r\n", str.len(), str)
-                }
+            // an empty string is "$0", a missing one is NullBulkString
+            RespType::BulkString(str) => format!("${}\r\n{}\r\n", str.len(), str),
+            RespType::BulkBytes(bytes) => {
+                format!("${}\r\n{}\r\n", bytes.len(), String::from_utf8_lossy(bytes))
             }
             RespType::SimpleString(str) => format!("+{}\r\n", str),
-            RespType::Error(_) => format!("not implemented yet"),
-            RespType::Integer(_) => todo!(),
+            RespType::Error(str) => format!("-{}\r\n", str),
+            RespType::Integer(i) => format!(":{}\r\n", i),
             RespType::NullBulkString => "$-1\r\n".to_string(),
             RespType::NullArray => "*-1\r\n".to_string(),
         }
     }
+
+    /*
+    Same as `to_resp_string`, but keeps binary bulk strings intact.
+    */
+    pub fn to_resp_bytes(&self) -> Vec<u8> {
+        match self {
+            RespType::Array(vec) => {
+                let mut out: Vec<u8> = format!("*{}\r\n", vec.len()).into_bytes();
+                for e in vec {
+                    out.extend_from_slice(&e.to_resp_bytes());
+                }
+                out
+            }
+            RespType::BulkBytes(bytes) => {
+                let mut out: Vec<u8> = format!("${}\r\n", bytes.len()).into_bytes();
+                out.extend_from_slice(bytes);
+                out.extend_from_slice(b"\r\n");
+                out
+            }
+            _ => self.to_resp_string().into_bytes(),
+        }
+    }
 }
 
 pub fn parse_resp(input: &str) -> Result<RespType, String> {
@@ -47,6 +65,19 @@ pub fn parse_resp(input: &str) -> Result<RespType, String> {
     parse_value(&mut chars)
 }
 
+/*
+Parses every RESP value in `input`, for when several commands arrive in one
+read.
+*/
+pub fn parse_resp_stream(input: &str) -> Result<Vec<RespType>, String> {
+    let mut chars = input.chars().peekable();
+    let mut values: Vec<RespType> = Vec::new();
+    while chars.peek().is_some() {
+        values.push(parse_value(&mut chars)?);
+    }
+    Ok(values)
+}
+
 pub fn parse_value<I>(chars: &mut Peekable<I>) -> Result<RespType, String>
 where
     I: Iterator<Item = char>,
@@ -152,7 +183,7 @@ where
     let mut length_str = String::new();
     while let Some(&c) = chars.peek() {
         // consume the length of the array
-        if !c.is_digit(10) {
+        if !c.is_ascii_digit() {
             break;
         }
         length_str.push(c);
@@ -171,20 +202,70 @@ where
     Ok(RespType::Array(array))
 }
 
-pub fn parse_retain_cmd(resp: &RespType) -> bool {
-    if let RespType::Array(vec) = resp {
-        if vec.len() == 3 {
-            match (&vec[0], &vec[1], &vec[2]) {
-                (
-                    RespType::BulkString(cmd),
-                    RespType::BulkString(arg),
-                    RespType::BulkString(_port),
-                ) if cmd == "REPLCONF" && arg == "listening-port" => {
-                    return true;
+/*
+Parses the RESP value at the start of `input`, working on raw bytes so that
+a value cut short can be told apart from a malformed one. Returns the value
+and the number of bytes it spans, or None if `input` ends before the value
+does.
+*/
+pub fn parse_resp_prefix(input: &[u8]) -> Result<Option<(RespType, usize)>, String> {
+    match input.first() {
+        Some(b'+' | b'-' | b':' | b'$' | b'*') => {}
+        Some(_) => return Err("Invalid RESP type".to_string()),
+        None => return Ok(None),
+    }
+    let line_end: usize = match input.windows(2).position(|w| w == b"\r\n") {
+        Some(pos) => pos,
+        None => return Ok(None),
+    };
+    let line: String = String::from_utf8_lossy(&input[1..line_end]).to_string();
+    let mut consumed: usize = line_end + 2;
+    match input[0] {
+        b'+' => Ok(Some((RespType::SimpleString(line), consumed))),
+        b'-' => Ok(Some((RespType::Error(line), consumed))),
+        b':' => {
+            let i: i64 = line.parse().map_err(|_| "Invalid integer".to_string())?;
+            Ok(Some((RespType::Integer(i), consumed)))
+        }
+        b'$' => {
+            let length: i64 = line
+                .parse()
+                .map_err(|_| "Invalid bulk string length".to_string())?;
+            if length < 0 {
+                return Ok(Some((RespType::NullBulkString, consumed)));
+            }
+            let end: usize = consumed + length as usize;
+            if input.len() < end + 2 {
+                return Ok(None);
+            }
+            if &input[end..end + 2] != b"\r\n" {
+                return Err("Expected CRLF".to_string());
+            }
+            let value: RespType = match String::from_utf8(input[consumed..end].to_vec()) {
+                Ok(s) => RespType::BulkString(s),
+                Err(e) => RespType::BulkBytes(e.into_bytes()),
+            };
+            Ok(Some((value, end + 2)))
+        }
+        b'*' => {
+            let length: i64 = line
+                .parse()
+                .map_err(|_| "Invalid array length".to_string())?;
+            if length < 0 {
+                return Ok(Some((RespType::NullArray, consumed)));
+            }
+            let mut array: Vec<RespType> = Vec::new();
+            for _ in 0..length {
+                match parse_resp_prefix(&input[consumed..])? {
+                    Some((element, size)) => {
+                        array.push(element);
+                        consumed += size;
+                    }
+                    None => return Ok(None),
                 }
-                _ => {}
             }
+            Ok(Some((RespType::Array(array), consumed)))
         }
+        _ => Err("Invalid RESP type".to_string()),
     }
-    false
 }
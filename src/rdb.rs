@@ -0,0 +1,779 @@
+use crate::{crc64::crc64, db::Database};
+use std::fmt;
+
+/*
+Reader and writer for Redis RDB snapshot files.
+
+An RDB file is the "REDIS" magic, a 4 digit version, a sequence of opcodes
+(auxiliary fields, database selectors, resize hints, expire times) each
+followed by their payload, and key-value pairs. It ends with the EOF opcode
+and, since version 5, an 8 byte CRC64 checksum of everything before it.
+
+Only string values are understood so far, which is the only type this server
+can store.
+*/
+
+pub const RDB_MAGIC: &[u8] = b"REDIS";
+
+// the version we write, same as Redis 7.2
+pub const RDB_VERSION: u32 = 11;
+
+// oldest and newest format versions we know how to read
+const RDB_MIN_VERSION: u32 = 1;
+const RDB_MAX_VERSION: u32 = 12;
+
+pub const RDB_OPCODE_FUNCTION2: u8 = 0xF5;
+pub const RDB_OPCODE_MODULE_AUX: u8 = 0xF7;
+pub const RDB_OPCODE_IDLE: u8 = 0xF8;
+pub const RDB_OPCODE_FREQ: u8 = 0xF9;
+pub const RDB_OPCODE_AUX: u8 = 0xFA;
+pub const RDB_OPCODE_RESIZEDB: u8 = 0xFB;
+pub const RDB_OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+pub const RDB_OPCODE_EXPIRETIME: u8 = 0xFD;
+pub const RDB_OPCODE_SELECTDB: u8 = 0xFE;
+pub const RDB_OPCODE_EOF: u8 = 0xFF;
+
+pub const RDB_TYPE_STRING: u8 = 0;
+
+// the two high bits of the first length byte select the length encoding
+const RDB_6BITLEN: u8 = 0;
+const RDB_14BITLEN: u8 = 1;
+const RDB_32BITLEN: u8 = 0x80;
+const RDB_64BITLEN: u8 = 0x81;
+const RDB_ENCVAL: u8 = 3;
+
+// special string encodings, flagged by RDB_ENCVAL
+const RDB_ENC_INT8: u8 = 0;
+const RDB_ENC_INT16: u8 = 1;
+const RDB_ENC_INT32: u8 = 2;
+const RDB_ENC_LZF: u8 = 3;
+
+/*
+A key-value pair read from an RDB file.
+- db: the database it was SELECTed into.
+- expire_ms: absolute expire time in Unix milliseconds, if it has one.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct RdbEntry {
+    pub db: usize,
+    pub key: String,
+    pub value: String,
+    pub expire_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Rdb {
+    pub version: u32,
+    pub aux: Vec<(String, String)>,
+    pub entries: Vec<RdbEntry>,
+}
+
+/*
+A parse failure, with the byte offset at which the file stopped making sense.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct RdbError {
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for RdbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (at offset {})", self.message, self.offset)
+    }
+}
+
+enum Length {
+    Plain(u64),
+    Encoded(u8),
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn error<T>(&self, message: &str) -> Result<T, RdbError> {
+        Err(RdbError {
+            offset: self.pos,
+            message: message.to_string(),
+        })
+    }
+
+    fn read_bytes(&mut self, n: usize) -> Result<&'a [u8], RdbError> {
+        if self.buf.len() - self.pos < n {
+            return self.error("unexpected end of file");
+        }
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, RdbError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u32_le(&mut self) -> Result<u32, RdbError> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn read_u64_le(&mut self) -> Result<u64, RdbError> {
+        let bytes = self.read_bytes(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn read_length_or_encoding(&mut self) -> Result<Length, RdbError> {
+        let first: u8 = self.read_u8()?;
+        match first >> 6 {
+            RDB_6BITLEN => Ok(Length::Plain((first & 0x3F) as u64)),
+            RDB_14BITLEN => {
+                let second: u8 = self.read_u8()?;
+                Ok(Length::Plain(
+                    (((first & 0x3F) as u64) << 8) | second as u64,
+                ))
+            }
+            RDB_ENCVAL => Ok(Length::Encoded(first & 0x3F)),
+            _ => match first {
+                RDB_32BITLEN => {
+                    let bytes = self.read_bytes(4)?;
+                    Ok(Length::Plain(
+                        u32::from_be_bytes(bytes.try_into().unwrap()) as u64
+                    ))
+                }
+                RDB_64BITLEN => {
+                    let bytes = self.read_bytes(8)?;
+                    Ok(Length::Plain(u64::from_be_bytes(bytes.try_into().unwrap())))
+                }
+                _ => self.error(&format!("unknown length encoding 0x{:02x}", first)),
+            },
+        }
+    }
+
+    fn read_length(&mut self) -> Result<u64, RdbError> {
+        match self.read_length_or_encoding()? {
+            Length::Plain(len) => Ok(len),
+            Length::Encoded(_) => self.error("expected a length, got an encoded value"),
+        }
+    }
+
+    fn read_usize(&mut self) -> Result<usize, RdbError> {
+        let len: u64 = self.read_length()?;
+        match usize::try_from(len) {
+            Ok(len) => Ok(len),
+            Err(_) => self.error(&format!("length {} is out of range", len)),
+        }
+    }
+
+    /*
+    Reads a string, expanding integer and LZF encodings back into the bytes
+    they stand for.
+    */
+    fn read_string(&mut self) -> Result<Vec<u8>, RdbError> {
+        match self.read_length_or_encoding()? {
+            Length::Plain(len) => match usize::try_from(len) {
+                Ok(len) => Ok(self.read_bytes(len)?.to_vec()),
+                Err(_) => self.error("string length is out of range"),
+            },
+            Length::Encoded(RDB_ENC_INT8) => Ok((self.read_u8()? as i8).to_string().into_bytes()),
+            Length::Encoded(RDB_ENC_INT16) => {
+                let bytes = self.read_bytes(2)?;
+                Ok(i16::from_le_bytes(bytes.try_into().unwrap())
+                    .to_string()
+                    .into_bytes())
+            }
+            Length::Encoded(RDB_ENC_INT32) => {
+                Ok((self.read_u32_le()? as i32).to_string().into_bytes())
+            }
+            Length::Encoded(RDB_ENC_LZF) => {
+                let compressed_len: usize = self.read_usize()?;
+                let len: usize = self.read_usize()?;
+                let start: usize = self.pos;
+                let compressed = self.read_bytes(compressed_len)?;
+                match lzf_decompress(compressed, len) {
+                    Some(bytes) => Ok(bytes),
+                    None => Err(RdbError {
+                        offset: start,
+                        message: "invalid LZF compressed string".to_string(),
+                    }),
+                }
+            }
+            Length::Encoded(enc) => self.error(&format!("unknown string encoding {}", enc)),
+        }
+    }
+
+    /*
+    Keys and values are kept as Rust strings, so one that isn't valid UTF-8
+    is refused rather than silently altered.
+    */
+    fn read_utf8_string(&mut self) -> Result<String, RdbError> {
+        let start: usize = self.pos;
+        String::from_utf8(self.read_string()?).map_err(|e| RdbError {
+            offset: start,
+            message: format!(
+                "string is not valid UTF-8 (invalid byte at index {}), which is not supported",
+                e.utf8_error().valid_up_to()
+            ),
+        })
+    }
+}
+
+/*
+Decompresses LZF data (as produced by liblzf's lzf_compress) into exactly
+`expected_len` bytes. Returns None if the data is corrupt.
+
+The stream is a sequence of chunks, each starting with a control byte:
+- below 32: a literal run of control + 1 bytes follows;
+- otherwise: a back reference. The top 3 bits are the length - 2 (7 means an
+  extra length byte follows), the low 5 bits and the next byte the distance - 1.
+*/
+pub fn lzf_decompress(input: &[u8], expected_len: usize) -> Option<Vec<u8>> {
+    // don't trust `expected_len` for the allocation, the data may be corrupt
+    let mut out: Vec<u8> = Vec::new();
+    let mut ip: usize = 0;
+    while ip < input.len() {
+        let ctrl: usize = input[ip] as usize;
+        ip += 1;
+        if ctrl < 32 {
+            let run: usize = ctrl + 1;
+            if ip + run > input.len() || out.len() + run > expected_len {
+                return None;
+            }
+            out.extend_from_slice(&input[ip..ip + run]);
+            ip += run;
+        } else {
+            let mut len: usize = ctrl >> 5;
+            if len == 7 {
+                len += *input.get(ip)? as usize;
+                ip += 1;
+            }
+            len += 2;
+            let distance: usize = ((ctrl & 0x1F) << 8) + *input.get(ip)? as usize + 1;
+            ip += 1;
+            if distance > out.len() || out.len() + len > expected_len {
+                return None;
+            }
+            // the reference may overlap what we are writing, copy byte by byte
+            let start: usize = out.len() - distance;
+            for i in 0..len {
+                out.push(out[start + i]);
+            }
+        }
+    }
+    if out.len() != expected_len {
+        return None;
+    }
+    Some(out)
+}
+
+/*
+Parses a whole RDB file held in memory.
+*/
+pub fn parse_rdb(bytes: &[u8]) -> Result<Rdb, RdbError> {
+    parse_rdb_prefix(bytes).map(|(rdb, _)| rdb)
+}
+
+/*
+Parses the RDB snapshot at the start of `bytes` and returns it with the
+number of bytes it took, up to and including the checksum. Whatever follows
+is left to the caller, like the commands after the RDB preamble of an AOF.
+*/
+pub fn parse_rdb_prefix(bytes: &[u8]) -> Result<(Rdb, usize), RdbError> {
+    let mut reader = Reader { buf: bytes, pos: 0 };
+
+    if reader.read_bytes(RDB_MAGIC.len()).ok() != Some(RDB_MAGIC) {
+        return Err(RdbError {
+            offset: 0,
+            message: "wrong signature, not an RDB file".to_string(),
+        });
+    }
+    let version: u32 = match std::str::from_utf8(reader.read_bytes(4)?)
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+    {
+        Some(v) => v,
+        None => return reader.error("invalid RDB version"),
+    };
+    if !(RDB_MIN_VERSION..=RDB_MAX_VERSION).contains(&version) {
+        return reader.error(&format!("can't handle RDB format version {}", version));
+    }
+
+    let mut rdb = Rdb {
+        version,
+        ..Default::default()
+    };
+    let mut db: usize = 0;
+    let mut expire_ms: Option<u64> = None;
+    loop {
+        let opcode_offset: usize = reader.pos;
+        let opcode: u8 = reader.read_u8()?;
+        match opcode {
+            RDB_OPCODE_AUX => {
+                let key: String = reader.read_utf8_string()?;
+                let value: String = reader.read_utf8_string()?;
+                rdb.aux.push((key, value));
+            }
+            RDB_OPCODE_SELECTDB => {
+                db = reader.read_usize()?;
+            }
+            RDB_OPCODE_RESIZEDB => {
+                // only a hint for pre-sizing the hash tables
+                reader.read_length()?;
+                reader.read_length()?;
+            }
+            RDB_OPCODE_EXPIRETIME => {
+                expire_ms = Some(reader.read_u32_le()? as u64 * 1000);
+            }
+            RDB_OPCODE_EXPIRETIME_MS => {
+                expire_ms = Some(reader.read_u64_le()?);
+            }
+            RDB_OPCODE_FREQ => {
+                // LFU frequency of the next key, we don't track it
+                reader.read_u8()?;
+            }
+            RDB_OPCODE_IDLE => {
+                // LRU idle time of the next key, we don't track it
+                reader.read_length()?;
+            }
+            RDB_OPCODE_EOF => {
+                // versions 5 and up end with a checksum, 0 if it was disabled
+                if version >= 5 {
+                    let checksum_offset: usize = reader.pos;
+                    let expected: u64 = reader.read_u64_le()?;
+                    let actual: u64 = crc64(0, &bytes[..checksum_offset]);
+                    if expected != 0 && expected != actual {
+                        return Err(RdbError {
+                            offset: checksum_offset,
+                            message: format!(
+                                "RDB CRC error: expected {:016x}, computed {:016x}",
+                                expected, actual
+                            ),
+                        });
+                    }
+                }
+                return Ok((rdb, reader.pos));
+            }
+            RDB_OPCODE_MODULE_AUX | RDB_OPCODE_FUNCTION2 => {
+                return Err(RdbError {
+                    offset: opcode_offset,
+                    message: format!("unsupported RDB opcode 0x{:02x}", opcode),
+                });
+            }
+            RDB_TYPE_STRING => {
+                let key: String = reader.read_utf8_string()?;
+                let value: String = reader.read_utf8_string()?;
+                rdb.entries.push(RdbEntry {
+                    db,
+                    key,
+                    value,
+                    expire_ms: expire_ms.take(),
+                });
+            }
+            _ => {
+                return Err(RdbError {
+                    offset: opcode_offset,
+                    message: format!("unsupported RDB value type {}", opcode),
+                });
+            }
+        }
+    }
+}
+
+/*
+Builds an RDB file in memory. The header is written on creation, `finish`
+appends the EOF opcode and the CRC64 checksum.
+*/
+pub struct RdbWriter {
+    buf: Vec<u8>,
+}
+
+impl Default for RdbWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RdbWriter {
+    pub fn new() -> Self {
+        let mut buf: Vec<u8> = Vec::new();
+        buf.extend_from_slice(RDB_MAGIC);
+        buf.extend_from_slice(format!("{:04}", RDB_VERSION).as_bytes());
+        RdbWriter { buf }
+    }
+
+    fn write_length(&mut self, len: u64) {
+        if len < (1 << 6) {
+            self.buf.push(len as u8);
+        } else if len < (1 << 14) {
+            self.buf.push((RDB_14BITLEN << 6) | (len >> 8) as u8);
+            self.buf.push(len as u8);
+        } else if len <= u32::MAX as u64 {
+            self.buf.push(RDB_32BITLEN);
+            self.buf.extend_from_slice(&(len as u32).to_be_bytes());
+        } else {
+            self.buf.push(RDB_64BITLEN);
+            self.buf.extend_from_slice(&len.to_be_bytes());
+        }
+    }
+
+    /*
+    Writes a string, using the compact integer encoding when the string is
+    the canonical decimal form of a 32 bit integer (like Redis does).
+    */
+    fn write_string(&mut self, value: &[u8]) {
+        if let Some(int) = std::str::from_utf8(value)
+            .ok()
+            .filter(|s| s.len() <= 11)
+            .and_then(|s| s.parse::<i32>().ok())
+            .filter(|i| i.to_string().as_bytes() == value)
+        {
+            let enc: u8 = RDB_ENCVAL << 6;
+            if let Ok(i) = i8::try_from(int) {
+                self.buf.push(enc | RDB_ENC_INT8);
+                self.buf.extend_from_slice(&i.to_le_bytes());
+            } else if let Ok(i) = i16::try_from(int) {
+                self.buf.push(enc | RDB_ENC_INT16);
+                self.buf.extend_from_slice(&i.to_le_bytes());
+            } else {
+                self.buf.push(enc | RDB_ENC_INT32);
+                self.buf.extend_from_slice(&int.to_le_bytes());
+            }
+            return;
+        }
+        self.write_length(value.len() as u64);
+        self.buf.extend_from_slice(value);
+    }
+
+    pub fn aux(&mut self, key: &str, value: &str) {
+        self.buf.push(RDB_OPCODE_AUX);
+        self.write_string(key.as_bytes());
+        self.write_string(value.as_bytes());
+    }
+
+    /*
+    Starts the section of database `db`, with the sizes as a resize hint for
+    the loader.
+    */
+    pub fn select_db(&mut self, db: usize, size: usize, expires_size: usize) {
+        self.buf.push(RDB_OPCODE_SELECTDB);
+        self.write_length(db as u64);
+        self.buf.push(RDB_OPCODE_RESIZEDB);
+        self.write_length(size as u64);
+        self.write_length(expires_size as u64);
+    }
+
+    pub fn string_entry(&mut self, key: &str, value: &str, expire_ms: Option<u64>) {
+        if let Some(when) = expire_ms {
+            self.buf.push(RDB_OPCODE_EXPIRETIME_MS);
+            self.buf.extend_from_slice(&when.to_le_bytes());
+        }
+        self.buf.push(RDB_TYPE_STRING);
+        self.write_string(key.as_bytes());
+        self.write_string(value.as_bytes());
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.buf.push(RDB_OPCODE_EOF);
+        let checksum: u64 = crc64(0, &self.buf);
+        self.buf.extend_from_slice(&checksum.to_le_bytes());
+        self.buf
+    }
+}
+
+/*
+Serializes every database into an RDB file, with `aux` as auxiliary fields.
+Empty databases are left out, like Redis does.
+*/
+pub fn dump_databases(dbs: &[Database], aux: &[(String, String)]) -> Vec<u8> {
+    let mut writer = RdbWriter::new();
+    for (key, value) in aux {
+        writer.aux(key, value);
+    }
+    for (id, db) in dbs.iter().enumerate() {
+        if db.is_empty() {
+            continue;
+        }
+        writer.select_db(id, db.len(), db.expires.len());
+        for (key, value) in db.dict.iter() {
+            writer.string_entry(key, value, db.expires.get(key).copied());
+        }
+    }
+    writer.finish()
+}
+
+/*
+Serializes a value the way DUMP does: its RDB type and encoding, followed by
+a footer with the RDB version (two bytes) and the CRC64 of everything before
+the checksum itself.
+*/
+pub fn dump_payload(value: &str) -> Vec<u8> {
+    let mut writer = RdbWriter {
+        buf: vec![RDB_TYPE_STRING],
+    };
+    writer.write_string(value.as_bytes());
+    let mut payload: Vec<u8> = writer.buf;
+    payload.extend_from_slice(&(RDB_VERSION as u16).to_le_bytes());
+    let checksum: u64 = crc64(0, &payload);
+    payload.extend_from_slice(&checksum.to_le_bytes());
+    payload
+}
+
+/*
+Checks the footer of a DUMP payload and decodes the value it holds. Payloads
+of an RDB version newer than we can read, or with a bad checksum, are
+rejected.
+*/
+pub fn parse_dump_payload(payload: &[u8]) -> Result<String, String> {
+    let bad_footer = || "DUMP payload version or checksum are wrong".to_string();
+    if payload.len() < 10 {
+        return Err(bad_footer());
+    }
+    let (body, footer) = payload.split_at(payload.len() - 10);
+    let version: u32 = u16::from_le_bytes([footer[0], footer[1]]) as u32;
+    let checksum: u64 = u64::from_le_bytes(footer[2..].try_into().unwrap());
+    if version > RDB_MAX_VERSION || crc64(0, &payload[..payload.len() - 8]) != checksum {
+        return Err(bad_footer());
+    }
+
+    let bad_data = |_| "Bad data format".to_string();
+    let mut reader = Reader { buf: body, pos: 0 };
+    match reader.read_u8().map_err(bad_data)? {
+        RDB_TYPE_STRING => {}
+        _ => return Err("Bad data format".to_string()),
+    }
+    let value: String = reader
+        .read_utf8_string()
+        .map_err(|e| format!("Bad data format: {}", e))?;
+    if reader.pos != body.len() {
+        return Err("Bad data format".to_string());
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /*
+    The empty snapshot a Redis 7.2.0 master sends on a full resync, byte for
+    byte. Its aux fields hold integers in the INT8 and INT32 encodings.
+    */
+    const REDIS_7_2_EMPTY: &[u8] = &[
+        0x52, 0x45, 0x44, 0x49, 0x53, 0x30, 0x30, 0x31, 0x31, 0xfa, 0x09, 0x72, 0x65, 0x64, 0x69,
+        0x73, 0x2d, 0x76, 0x65, 0x72, 0x05, 0x37, 0x2e, 0x32, 0x2e, 0x30, 0xfa, 0x0a, 0x72, 0x65,
+        0x64, 0x69, 0x73, 0x2d, 0x62, 0x69, 0x74, 0x73, 0xc0, 0x40, 0xfa, 0x05, 0x63, 0x74, 0x69,
+        0x6d, 0x65, 0xc2, 0x6d, 0x08, 0xbc, 0x65, 0xfa, 0x08, 0x75, 0x73, 0x65, 0x64, 0x2d, 0x6d,
+        0x65, 0x6d, 0xc2, 0xb0, 0xc4, 0x10, 0x00, 0xfa, 0x08, 0x61, 0x6f, 0x66, 0x2d, 0x62, 0x61,
+        0x73, 0x65, 0xc0, 0x00, 0xff, 0xf0, 0x6e, 0x3b, 0xfe, 0xc0, 0xff, 0x5a, 0xa2,
+    ];
+
+    /*
+    A string of 50 "a"s as Redis stores it with rdbcompression on: LZF (0xc3),
+    9 compressed bytes, 50 once expanded. liblzf emits a literal run of two
+    bytes, a back reference one byte behind that repeats them 46 times, and
+    the two bytes its matcher never looks at as a final literal run.
+    */
+    const LZF_50_AS: &[u8] = &[
+        0xc3, 0x09, 0x32, 0x01, 0x61, 0x61, 0xe0, 0x25, 0x00, 0x01, 0x61, 0x61,
+    ];
+
+    fn reader(buf: &[u8]) -> Reader<'_> {
+        Reader { buf, pos: 0 }
+    }
+
+    fn with_checksum(mut bytes: Vec<u8>) -> Vec<u8> {
+        let checksum: u64 = crc64(0, &bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn a_snapshot_written_by_redis_loads() {
+        let rdb: Rdb = parse_rdb(REDIS_7_2_EMPTY).unwrap();
+        assert_eq!(rdb.version, 11);
+        let aux: Vec<(&str, &str)> = rdb
+            .aux
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        assert_eq!(
+            aux,
+            vec![
+                ("redis-ver", "7.2.0"),
+                ("redis-bits", "64"),
+                ("ctime", "1706821741"),
+                ("used-mem", "1098928"),
+                ("aof-base", "0"),
+            ]
+        );
+        assert!(rdb.entries.is_empty());
+    }
+
+    #[test]
+    fn a_checksum_that_does_not_match_is_refused() {
+        let mut bytes: Vec<u8> = REDIS_7_2_EMPTY.to_vec();
+        // "7.2.0" becomes "7.3.0"
+        bytes[23] = b'3';
+        let e: RdbError = parse_rdb(&bytes).unwrap_err();
+        assert_eq!(e.offset, bytes.len() - 8);
+        assert!(e.message.contains("CRC"));
+
+        // a zero checksum means checksums were turned off
+        let len: usize = bytes.len();
+        bytes[len - 8..].fill(0);
+        assert!(parse_rdb(&bytes).is_ok());
+    }
+
+    #[test]
+    fn lengths_in_every_encoding() {
+        assert_eq!(reader(&[0x0a]).read_length().unwrap(), 10);
+        assert_eq!(reader(&[0x3f]).read_length().unwrap(), 63);
+        // 14 bits: 0b01 then the length big-endian
+        assert_eq!(reader(&[0x41, 0x2c]).read_length().unwrap(), 300);
+        assert_eq!(reader(&[0x7f, 0xff]).read_length().unwrap(), 16383);
+        assert_eq!(
+            reader(&[0x80, 0x00, 0x01, 0x00, 0x00])
+                .read_length()
+                .unwrap(),
+            65536
+        );
+        assert_eq!(
+            reader(&[0x81, 0, 0, 0, 0x01, 0, 0, 0, 0])
+                .read_length()
+                .unwrap(),
+            1 << 32
+        );
+        assert!(reader(&[0x82]).read_length().is_err());
+        assert!(reader(&[0xc0, 0x01]).read_length().is_err());
+        assert!(reader(&[0x41]).read_length().is_err());
+
+        let mut long: Vec<u8> = vec![0x41, 0x2c];
+        long.extend_from_slice(&[b'x'; 300]);
+        let mut r: Reader = reader(&long);
+        assert_eq!(r.read_string().unwrap(), vec![b'x'; 300]);
+        assert_eq!(r.pos, long.len());
+    }
+
+    #[test]
+    fn integer_encoded_strings() {
+        assert_eq!(reader(&[0xc0, 0xf6]).read_string().unwrap(), b"-10");
+        assert_eq!(reader(&[0xc0, 0x7f]).read_string().unwrap(), b"127");
+        assert_eq!(reader(&[0xc1, 0x39, 0x30]).read_string().unwrap(), b"12345");
+        assert_eq!(
+            reader(&[0xc2, 0x60, 0x79, 0xfe, 0xff])
+                .read_string()
+                .unwrap(),
+            b"-100000"
+        );
+        assert!(reader(&[0xc4]).read_string().is_err());
+        assert!(reader(&[0xc2, 0x01]).read_string().is_err());
+    }
+
+    #[test]
+    fn lzf_compressed_strings() {
+        let mut r: Reader = reader(LZF_50_AS);
+        assert_eq!(r.read_string().unwrap(), vec![b'a'; 50]);
+        assert_eq!(r.pos, LZF_50_AS.len());
+
+        // a back reference before the start of the output
+        let mut corrupt: Vec<u8> = LZF_50_AS.to_vec();
+        corrupt[8] = 0x05;
+        let e: RdbError = reader(&corrupt).read_string().unwrap_err();
+        assert_eq!(e.offset, 3);
+        // more data than the announced length
+        let mut corrupt: Vec<u8> = LZF_50_AS.to_vec();
+        corrupt[2] = 0x31;
+        assert!(reader(&corrupt).read_string().is_err());
+        assert_eq!(lzf_decompress(&[0x00], 1), None);
+    }
+
+    #[test]
+    fn databases_resize_hints_and_both_expire_opcodes() {
+        let mut bytes: Vec<u8> = b"REDIS0006".to_vec();
+        bytes.extend_from_slice(&[RDB_OPCODE_SELECTDB, 0]);
+        bytes.extend_from_slice(&[RDB_OPCODE_RESIZEDB, 2, 1]);
+        bytes.extend_from_slice(&[RDB_TYPE_STRING, 1, b'a', 0xc0, 0x01]);
+        bytes.push(RDB_OPCODE_EXPIRETIME);
+        bytes.extend_from_slice(&1_700_000_000u32.to_le_bytes());
+        bytes.extend_from_slice(&[RDB_TYPE_STRING, 1, b'b']);
+        bytes.extend_from_slice(LZF_50_AS);
+        bytes.extend_from_slice(&[RDB_OPCODE_SELECTDB, 0x41, 0x2c]);
+        bytes.push(RDB_OPCODE_EXPIRETIME_MS);
+        bytes.extend_from_slice(&1_700_000_000_123u64.to_le_bytes());
+        bytes.extend_from_slice(&[RDB_OPCODE_IDLE, 0x05, RDB_OPCODE_FREQ, 0x07]);
+        bytes.extend_from_slice(&[RDB_TYPE_STRING, 1, b'c', 1, b'z']);
+        bytes.push(RDB_OPCODE_EOF);
+
+        let rdb: Rdb = parse_rdb(&with_checksum(bytes)).unwrap();
+        assert_eq!(rdb.version, 6);
+        assert_eq!(
+            rdb.entries,
+            vec![
+                RdbEntry {
+                    db: 0,
+                    key: "a".to_string(),
+                    value: "1".to_string(),
+                    expire_ms: None,
+                },
+                RdbEntry {
+                    db: 0,
+                    key: "b".to_string(),
+                    value: "a".repeat(50),
+                    expire_ms: Some(1_700_000_000_000),
+                },
+                RdbEntry {
+                    db: 300,
+                    key: "c".to_string(),
+                    value: "z".to_string(),
+                    expire_ms: Some(1_700_000_000_123),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_versions_and_types_are_refused() {
+        assert!(parse_rdb(b"REDIX0011").is_err());
+        assert!(parse_rdb(b"REDIS0013\xff").is_err());
+        assert!(parse_rdb(b"REDIS00x1\xff").is_err());
+        let e: RdbError = parse_rdb(&with_checksum(b"REDIS0011\x02\x01k".to_vec())).unwrap_err();
+        assert_eq!(e.offset, 9);
+        // the file ends before its EOF opcode
+        assert!(parse_rdb(b"REDIS0011\xfe\x00").is_err());
+    }
+
+    #[test]
+    fn multibyte_utf8_survives_a_round_trip() {
+        let mut writer = RdbWriter::new();
+        writer.select_db(0, 1, 0);
+        writer.string_entry("clé", "héllo wörld ✓", None);
+        let rdb: Rdb = parse_rdb(&writer.finish()).unwrap();
+        assert_eq!(rdb.entries.len(), 1);
+        assert_eq!(rdb.entries[0].key, "clé");
+        assert_eq!(rdb.entries[0].value, "héllo wörld ✓");
+
+        let payload: Vec<u8> = dump_payload("héllo ✓");
+        assert_eq!(parse_dump_payload(&payload).unwrap(), "héllo ✓");
+    }
+
+    #[test]
+    fn a_value_that_is_not_utf8_is_refused_at_its_offset() {
+        let mut bytes: Vec<u8> = b"REDIS0011".to_vec();
+        bytes.extend_from_slice(&[RDB_OPCODE_SELECTDB, 0]);
+        bytes.extend_from_slice(&[RDB_TYPE_STRING, 1, b'k']);
+        let value_offset: usize = bytes.len();
+        bytes.extend_from_slice(&[2, 0xff, 0xfe]);
+        bytes.push(RDB_OPCODE_EOF);
+        bytes.extend_from_slice(&[0; 8]);
+        let e: RdbError = parse_rdb(&bytes).unwrap_err();
+        assert_eq!(e.offset, value_offset);
+        assert!(e.message.contains("UTF-8"));
+
+        let mut payload: Vec<u8> = vec![RDB_TYPE_STRING, 2, 0xff, 0xfe];
+        payload.extend_from_slice(&(RDB_VERSION as u16).to_le_bytes());
+        let checksum: u64 = crc64(0, &payload);
+        payload.extend_from_slice(&checksum.to_le_bytes());
+        assert!(parse_dump_payload(&payload).unwrap_err().contains("UTF-8"));
+    }
+}
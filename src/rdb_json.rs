@@ -0,0 +1,471 @@
+use crate::{
+    config::DEFAULT_DATABASES,
+    rdb::{parse_rdb, Rdb, RdbEntry, RdbWriter},
+};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    io::Write,
+};
+
+/*
+Conversion between RDB files and line-delimited JSON, for diffing snapshots
+and hand-editing fixtures:
+
+    redis-starter-rust rdb-to-json <file.rdb> [file.jsonl]
+    redis-starter-rust json-to-rdb <file.jsonl> <file.rdb>
+
+Each line holds one key as a flat JSON object:
+
+    {"db":0,"key":"foo","type":"string","expire_at_ms":1700000000000,"value":"bar"}
+
+`expire_at_ms` is the time to live as an absolute Unix time in milliseconds
+(null for keys without one), so converting the same snapshot twice gives the
+same output. Aux fields are not carried over; json-to-rdb writes its own,
+and refuses a `db` the server doesn't have or a key listed twice in a db.
+Keys and values have to be valid UTF-8: a snapshot with binary strings is
+refused, as the server refuses to load it, rather than converted with
+altered bytes.
+*/
+
+pub fn rdb_to_json_main(args: &[String]) -> i32 {
+    let (input, output): (&String, Option<&String>) = match args {
+        [input] => (input, None),
+        [input, output] => (input, Some(output)),
+        _ => {
+            eprintln!("Usage: redis-starter-rust rdb-to-json <file.rdb> [file.jsonl]");
+            return 1;
+        }
+    };
+    let bytes: Vec<u8> = match fs::read(input) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("Cannot open {}: {}", input, e);
+            return 1;
+        }
+    };
+    let rdb: Rdb = match parse_rdb(&bytes) {
+        Ok(rdb) => rdb,
+        Err(e) => {
+            eprintln!("Bad RDB file {}: {}", input, e);
+            return 1;
+        }
+    };
+    let json: String = rdb
+        .entries
+        .iter()
+        .map(|entry| entry_to_json(entry) + "\n")
+        .collect();
+    let result = match output {
+        Some(output) => fs::write(output, json),
+        None => std::io::stdout().write_all(json.as_bytes()),
+    };
+    if let Err(e) = result {
+        eprintln!("Failed writing JSON: {}", e);
+        return 1;
+    }
+    0
+}
+
+pub fn json_to_rdb_main(args: &[String]) -> i32 {
+    let (input, output): (&String, &String) = match args {
+        [input, output] => (input, output),
+        _ => {
+            eprintln!("Usage: redis-starter-rust json-to-rdb <file.jsonl> <file.rdb>");
+            return 1;
+        }
+    };
+    let text: String = match fs::read_to_string(input) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("Cannot open {}: {}", input, e);
+            return 1;
+        }
+    };
+
+    let mut dbs: BTreeMap<usize, Vec<RdbEntry>> = BTreeMap::new();
+    let mut seen: HashSet<(usize, String)> = HashSet::new();
+    for (lineno, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: RdbEntry = match entry_from_json(line) {
+            Ok(entry) => entry,
+            Err(e) => {
+                eprintln!("{}:{}: {}", input, lineno + 1, e);
+                return 1;
+            }
+        };
+        if !seen.insert((entry.db, entry.key.clone())) {
+            eprintln!(
+                "{}:{}: duplicate key {} in db {}",
+                input,
+                lineno + 1,
+                json_string(&entry.key),
+                entry.db
+            );
+            return 1;
+        }
+        dbs.entry(entry.db).or_default().push(entry);
+    }
+
+    let mut writer = RdbWriter::new();
+    writer.aux("redis-ver", "7.2.0");
+    writer.aux("redis-bits", "64");
+    for (db, entries) in &dbs {
+        let expires: usize = entries.iter().filter(|e| e.expire_ms.is_some()).count();
+        writer.select_db(*db, entries.len(), expires);
+        for entry in entries {
+            writer.string_entry(&entry.key, &entry.value, entry.expire_ms);
+        }
+    }
+    if let Err(e) = fs::write(output, writer.finish()) {
+        eprintln!("Failed writing {}: {}", output, e);
+        return 1;
+    }
+    0
+}
+
+pub fn entry_to_json(entry: &RdbEntry) -> String {
+    format!(
+        "{{\"db\":{},\"key\":{},\"type\":\"string\",\"expire_at_ms\":{},\"value\":{}}}",
+        entry.db,
+        json_string(&entry.key),
+        entry
+            .expire_ms
+            .map_or("null".to_string(), |when| when.to_string()),
+        json_string(&entry.value)
+    )
+}
+
+/*
+Parses one line written by `entry_to_json`. Fields may come in any order;
+`db` and `expire_at_ms` are optional. `db` must be one of the
+DEFAULT_DATABASES a server has unless configured otherwise.
+*/
+pub fn entry_from_json(line: &str) -> Result<RdbEntry, String> {
+    let mut db: usize = 0;
+    let mut key: Option<String> = None;
+    let mut value: Option<String> = None;
+    let mut expire_ms: Option<u64> = None;
+    for (field, json_value) in parse_flat_object(line)? {
+        match (field.as_str(), json_value) {
+            ("db", JsonValue::Number(n)) if n < DEFAULT_DATABASES as u64 => db = n as usize,
+            ("db", JsonValue::Number(n)) => {
+                return Err(format!(
+                    "db {} is out of range, must be below {}",
+                    n, DEFAULT_DATABASES
+                ))
+            }
+            ("key", JsonValue::String(s)) => key = Some(s),
+            ("value", JsonValue::String(s)) => value = Some(s),
+            ("type", JsonValue::String(t)) if t == "string" => {}
+            ("type", JsonValue::String(t)) => return Err(format!("unsupported type {}", t)),
+            ("expire_at_ms", JsonValue::Number(n)) => expire_ms = Some(n),
+            ("expire_at_ms", JsonValue::Null) => expire_ms = None,
+            (field, _) => return Err(format!("unexpected field or value for {}", field)),
+        }
+    }
+    Ok(RdbEntry {
+        db,
+        key: key.ok_or("missing key")?,
+        value: value.ok_or("missing value")?,
+        expire_ms,
+    })
+}
+
+fn json_string(s: &str) -> String {
+    let mut out: String = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+enum JsonValue {
+    String(String),
+    Number(u64),
+    Null,
+}
+
+/*
+A parser for the one shape of JSON we write: a single object whose values
+are strings, unsigned integers or null.
+*/
+fn parse_flat_object(line: &str) -> Result<Vec<(String, JsonValue)>, String> {
+    let mut chars = line.trim().chars().peekable();
+    let mut fields: Vec<(String, JsonValue)> = Vec::new();
+    if chars.next() != Some('{') {
+        return Err("expected a JSON object".to_string());
+    }
+    skip_whitespace(&mut chars);
+    if chars.peek() == Some(&'}') {
+        chars.next();
+    } else {
+        loop {
+            skip_whitespace(&mut chars);
+            let field: String = parse_json_string(&mut chars)?;
+            skip_whitespace(&mut chars);
+            if chars.next() != Some(':') {
+                return Err(format!("expected ':' after \"{}\"", field));
+            }
+            skip_whitespace(&mut chars);
+            let value: JsonValue = match chars.peek() {
+                Some('"') => JsonValue::String(parse_json_string(&mut chars)?),
+                Some('n') => {
+                    let word: String = chars.by_ref().take(4).collect();
+                    if word != "null" {
+                        return Err(format!("invalid value for \"{}\"", field));
+                    }
+                    JsonValue::Null
+                }
+                Some(c) if c.is_ascii_digit() => {
+                    let mut digits: String = String::new();
+                    while let Some(c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+                        digits.push(*c);
+                        chars.next();
+                    }
+                    // JSON has no leading zeros, and we take no fractions
+                    if (digits.len() > 1 && digits.starts_with('0'))
+                        || chars.peek().is_some_and(|c| matches!(c, '.' | 'e' | 'E'))
+                    {
+                        return Err(format!("invalid number for \"{}\"", field));
+                    }
+                    JsonValue::Number(
+                        digits
+                            .parse()
+                            .map_err(|_| format!("invalid number for \"{}\"", field))?,
+                    )
+                }
+                _ => return Err(format!("invalid value for \"{}\"", field)),
+            };
+            fields.push((field, value));
+            skip_whitespace(&mut chars);
+            match chars.next() {
+                Some(',') => continue,
+                Some('}') => break,
+                _ => return Err("expected ',' or '}'".to_string()),
+            }
+        }
+    }
+    skip_whitespace(&mut chars);
+    if chars.next().is_some() {
+        return Err("trailing characters after the object".to_string());
+    }
+    Ok(fields)
+}
+
+fn skip_whitespace<I>(chars: &mut std::iter::Peekable<I>)
+where
+    I: Iterator<Item = char>,
+{
+    while chars.peek().is_some_and(|c| c.is_whitespace()) {
+        chars.next();
+    }
+}
+
+fn parse_json_string<I>(chars: &mut std::iter::Peekable<I>) -> Result<String, String>
+where
+    I: Iterator<Item = char>,
+{
+    if chars.next() != Some('"') {
+        return Err("expected a string".to_string());
+    }
+    let mut out: String = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(out),
+            Some('\\') => match chars.next() {
+                Some('"') => out.push('"'),
+                Some('\\') => out.push('\\'),
+                Some('/') => out.push('/'),
+                Some('b') => out.push('\u{8}'),
+                Some('f') => out.push('\u{c}'),
+                Some('n') => out.push('\n'),
+                Some('r') => out.push('\r'),
+                Some('t') => out.push('\t'),
+                Some('u') => {
+                    let mut code: u32 = parse_hex4(chars)?;
+                    // characters outside the BMP come as a surrogate pair
+                    if (0xD800..0xDC00).contains(&code) {
+                        if chars.next() != Some('\\') || chars.next() != Some('u') {
+                            return Err("unpaired surrogate in string".to_string());
+                        }
+                        let low: u32 = parse_hex4(chars)?;
+                        if !(0xDC00..0xE000).contains(&low) {
+                            return Err("unpaired surrogate in string".to_string());
+                        }
+                        code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                    }
+                    out.push(char::from_u32(code).ok_or("invalid \\u escape")?);
+                }
+                _ => return Err("invalid escape in string".to_string()),
+            },
+            Some(c) => out.push(c),
+            None => return Err("unterminated string".to_string()),
+        }
+    }
+}
+
+fn parse_hex4<I>(chars: &mut std::iter::Peekable<I>) -> Result<u32, String>
+where
+    I: Iterator<Item = char>,
+{
+    let hex: String = chars.by_ref().take(4).collect();
+    if hex.len() != 4 {
+        return Err("invalid \\u escape".to_string());
+    }
+    u32::from_str_radix(&hex, 16).map_err(|_| "invalid \\u escape".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TempDir;
+
+    fn entry(db: usize, key: &str, value: &str, expire_ms: Option<u64>) -> RdbEntry {
+        RdbEntry {
+            db,
+            key: key.to_string(),
+            value: value.to_string(),
+            expire_ms,
+        }
+    }
+
+    fn path(dir: &TempDir, name: &str) -> String {
+        dir.path().join(name).display().to_string()
+    }
+
+    #[test]
+    fn a_snapshot_survives_rdb_to_json_to_rdb() {
+        let entries: Vec<RdbEntry> = vec![
+            entry(0, "plain", "value", None),
+            entry(0, "quote\"back\\slash", "line\nfeed\ttab\r\u{1}", None),
+            entry(3, "clé ✓ 😀", "", Some(1_700_000_000_123)),
+            entry(15, "12345", "-7", Some(1)),
+        ];
+        let mut writer = RdbWriter::new();
+        for db in [0, 3, 15] {
+            writer.select_db(db, 0, 0);
+            for e in entries.iter().filter(|e| e.db == db) {
+                writer.string_entry(&e.key, &e.value, e.expire_ms);
+            }
+        }
+        let dir = TempDir::new();
+        fs::write(path(&dir, "in.rdb"), writer.finish()).unwrap();
+
+        let args =
+            |names: &[&str]| -> Vec<String> { names.iter().map(|n| path(&dir, n)).collect() };
+        assert_eq!(rdb_to_json_main(&args(&["in.rdb", "dump.jsonl"])), 0);
+        let json: String = fs::read_to_string(path(&dir, "dump.jsonl")).unwrap();
+        assert_eq!(json.lines().count(), entries.len());
+        for (line, e) in json.lines().zip(&entries) {
+            assert_eq!(entry_from_json(line).unwrap(), *e);
+        }
+
+        assert_eq!(json_to_rdb_main(&args(&["dump.jsonl", "out.rdb"])), 0);
+        let rdb: Rdb = parse_rdb(&fs::read(path(&dir, "out.rdb")).unwrap()).unwrap();
+        assert_eq!(rdb.entries, entries);
+        // and converting it again gives the same text
+        assert_eq!(rdb_to_json_main(&args(&["out.rdb", "again.jsonl"])), 0);
+        assert_eq!(fs::read_to_string(path(&dir, "again.jsonl")).unwrap(), json);
+    }
+
+    #[test]
+    fn a_key_listed_twice_in_a_db_is_refused() {
+        let dir = TempDir::new();
+        let json: &str = "{\"db\":1,\"key\":\"k\",\"value\":\"a\"}\n\
+                          {\"db\":2,\"key\":\"k\",\"value\":\"b\"}\n\
+                          {\"key\":\"k\",\"value\":\"c\",\"db\":1}\n";
+        fs::write(path(&dir, "dup.jsonl"), json).unwrap();
+        let args: Vec<String> = vec![path(&dir, "dup.jsonl"), path(&dir, "dup.rdb")];
+        assert_eq!(json_to_rdb_main(&args), 1);
+        assert!(!dir.path().join("dup.rdb").exists());
+    }
+
+    #[test]
+    fn fields_come_in_any_order_with_optional_ones_left_out() {
+        assert_eq!(
+            entry_from_json(" { \"value\" : \"v\" , \"key\" : \"k\" } ").unwrap(),
+            entry(0, "k", "v", None)
+        );
+        assert_eq!(
+            entry_from_json("{\"expire_at_ms\":null,\"db\":0,\"key\":\"k\",\"value\":\"v\"}")
+                .unwrap(),
+            entry(0, "k", "v", None)
+        );
+    }
+
+    #[test]
+    fn string_escapes() {
+        let line: &str = r#"{"key":"a\"b\\c\/d\b\f\n\r\t\u00e9\u2713\ud83d\ude00","value":""}"#;
+        assert_eq!(
+            entry_from_json(line).unwrap().key,
+            "a\"b\\c/d\u{8}\u{c}\n\r\té✓😀"
+        );
+    }
+
+    #[test]
+    fn malformed_lines_are_refused() {
+        for line in [
+            "",
+            "[]",
+            "{",
+            r#"{"key":"k""#,
+            r#"{"key":"k","value":"v",}"#,
+            r#"{"key":"k" "value":"v"}"#,
+            r#"{"key" "k","value":"v"}"#,
+            r#"{"key":"k","value":"v"} x"#,
+            r#"{key:"k","value":"v"}"#,
+            r#"{"key":"k"}"#,
+            r#"{"value":"v"}"#,
+            r#"{"key":"k","value":1}"#,
+            r#"{"key":"k","value":"v","type":"list"}"#,
+            r#"{"key":"k","value":"v","extra":"x"}"#,
+            r#"{"key":"k","value":"v","expire_at_ms":nul}"#,
+            r#"{"key":"k","value":"v","expire_at_ms":"1"}"#,
+            r#"{"key":"k","value":"v","expire_at_ms":1e3}"#,
+            r#"{"key":"unterminated,"value":"v"}"#,
+            r#"{"key":"\q","value":"v"}"#,
+            r#"{"key":"\u12","value":"v"}"#,
+            r#"{"key":"\uzzzz","value":"v"}"#,
+            r#"{"key":"\ud83d","value":"v"}"#,
+            r#"{"key":"\ud83dA","value":"v"}"#,
+            r#"{"key":"\ude00","value":"v"}"#,
+        ] {
+            assert!(entry_from_json(line).is_err(), "{:?} was accepted", line);
+        }
+    }
+
+    #[test]
+    fn db_must_be_an_index_the_server_has() {
+        let line = |db: &str| format!("{{\"db\":{},\"key\":\"k\",\"value\":\"v\"}}", db);
+        assert_eq!(entry_from_json(&line("15")).unwrap().db, 15);
+        for db in [
+            "16",
+            "-1",
+            "1.5",
+            "01",
+            "1e1",
+            "99999999999999999999999",
+            "\"1\"",
+            "null",
+        ] {
+            assert!(
+                entry_from_json(&line(db)).is_err(),
+                "db {} was accepted",
+                db
+            );
+        }
+    }
+}
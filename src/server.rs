@@ -1,4 +1,9 @@
 use crate::{
+    client::{Client, Resync},
+    clock::{Clock, SystemClock},
+    config::Config,
+    db::Database,
+    glob::glob_match_bytes,
     parser::{parse_resp, RespType},
     role,
 };
@@ -8,12 +13,24 @@ use std::{
     io::{Read, Write},
     net::TcpStream,
     sync::{Arc, Mutex},
-    time::{Duration, Instant},
+    time::Instant,
 };
 
 use role::Role;
 
-#[derive(Clone)]
+mod aof;
+mod dump;
+mod expire;
+mod keyspace;
+mod migrate;
+mod persistence;
+mod replication;
+
+pub use persistence::write_file_atomically;
+pub use replication::{DisklessSync, ReplState, SyncSnapshot};
+use replication::{random_replid, ReplBacklog, Replica};
+
+#[derive(Clone, PartialEq)]
 pub struct ServerAddr {
     pub _ip: String,
     pub _port: u16,
@@ -25,51 +42,197 @@ impl ServerAddr {
     }
 }
 
-const EMPTY_RDB_FILE: &str = "524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2";
+// how often the server cron runs, in milliseconds (Redis' default hz of 10)
+pub const CRON_PERIOD_MS: u64 = 100;
+
+// the command changes the dataset, so it is propagated to the AOF and replicas
+const CMD_WRITE: u32 = 1 << 0;
+// the command is allowed on a replica whose link with the master is down,
+// even with replica-serve-stale-data off
+const CMD_STALE: u32 = 1 << 1;
+
+/*
+Every command we know, with its flags (as in the Redis command table).
+*/
+const COMMAND_TABLE: &[(&str, u32)] = &[
+    ("ping", 0),
+    ("echo", 0),
+    ("set", CMD_WRITE),
+    ("get", 0),
+    ("del", CMD_WRITE),
+    ("keys", 0),
+    ("scan", 0),
+    ("select", 0),
+    ("move", CMD_WRITE),
+    ("swapdb", CMD_WRITE),
+    ("flushdb", CMD_WRITE),
+    ("flushall", CMD_WRITE),
+    ("expire", CMD_WRITE),
+    ("pexpire", CMD_WRITE),
+    ("expireat", CMD_WRITE),
+    ("pexpireat", CMD_WRITE),
+    ("ttl", 0),
+    ("pttl", 0),
+    ("expiretime", 0),
+    ("pexpiretime", 0),
+    ("persist", CMD_WRITE),
+    ("dump", 0),
+    ("restore", CMD_WRITE),
+    ("migrate", CMD_WRITE),
+    ("info", CMD_STALE),
+    ("wait", 0),
+    ("waitaof", 0),
+    ("replconf", CMD_STALE),
+    ("psync", 0),
+    ("replicaof", CMD_STALE),
+    ("slaveof", CMD_STALE),
+    ("config", CMD_STALE),
+    ("save", 0),
+    ("bgsave", 0),
+    ("lastsave", 0),
+    ("bgrewriteaof", 0),
+    ("command", CMD_STALE),
+];
+
+fn command_flags(name: &str) -> u32 {
+    COMMAND_TABLE
+        .iter()
+        .find(|(command, _)| *command == name)
+        .map_or(0, |(_, flags)| *flags)
+}
 
-#[derive(Clone)]
 pub struct ServerState {
-    db: HashMap<String, String>,
-    expiry: HashMap<String, Instant>,
+    dbs: Vec<Database>,
+    client: Client,
+    clock: Arc<dyn Clock>,
+    active_expire_db: usize,
+    stat_expired_keys: u64,
+    stat_expired_stale_perc: f64,
+    stat_expired_time_cap_reached_count: u64,
+    stat_fork_time_us: u64,
+    stat_total_forks: u64,
     replication_id: Option<String>,
     replication_offset: Option<u64>,
-    _port: u16,
+    replication_id2: Option<(String, u64)>,
+    repl_backlog: Option<ReplBacklog>,
+    config: Config,
+    persistence: persistence::RdbState,
+    aof: aof::AofState,
     replica_of: Option<ServerAddr>,
+    repl_state: ReplState,
+    repl_down_since: Option<Instant>,
+    master_link: Option<TcpStream>,
+    master_stream_db: usize,
+
+    slave_servers: Vec<Replica>,
+    repl_seldb: Option<usize>,
 
-    slave_servers: Vec<Arc<Mutex<TcpStream>>>,
+    migrate_cached_sockets: HashMap<String, migrate::MigrateSocket>,
+
+    propagate_argv: Option<Vec<RespType>>,
+    loading: bool,
 }
 
 /*
 Data structure for the server state.
-- db: HashMap<String, String> to store key-value pairs.
-- expiry: HashMap<String, Instant> to store expiry time for keys.
+- dbs: Vec<Database>, one per logical database (`--databases`, 16 by
+  default). Each holds the key-value pairs in a Dict (our own hash table so
+  that SCAN can walk it with a stable cursor) and the expiry time of keys as
+  Unix time in milliseconds. Expired keys are removed lazily when accessed,
+  and by the active expire cycle which samples the expiry tables.
+- client: the client whose command is executing, swapped in by `execute` and
+  handed back afterwards. Handlers read its selected database from here.
+- clock: the source of "now" for expiry. Always the system clock outside of
+  tests.
+- config: the startup configuration, as reported by CONFIG GET.
+- persistence: bookkeeping for RDB snapshots (changes since the last save,
+  when it happened, the BGSAVE in progress).
+- stat_fork_time_us / stat_total_forks: how long the last copy of the
+  dataset for a background snapshot took, and how many were taken (see
+  `snapshot_dbs`).
+- aof: the append only file and the BGREWRITEAOF in progress (see aof.rs).
 - replication_id: Option<String> to store the replication id. This
-  value is Some if the server is a master. Otherwise, it is None.
+  value is Some if the server is a master, and random at startup (the id
+  saved with the RDB file loaded becomes replication_id2). Otherwise, it is
+  None until the first sync with the master, which hands down its own.
 - replication_offset: Option<String> to store the replication. Thus
-  value is Some if the server is a master. Otherwise, it is None.
+  value is Some if the server is a master. Otherwise, it is None until the
+  first sync with the master.
+- replication_id2: the previous replication id and the first offset that
+  doesn't belong to its history anymore (PSYNC2).
+- repl_backlog: the end of the write stream, kept for partial resyncs.
+  Created when the first replica attaches.
+- repl_state / repl_down_since: on a replica, the state of the link with
+  the master (see master_link.rs) and since when it is not up.
+- master_link: the connection to the master, for REPLICAOF to shut down.
+- master_stream_db: the database the master's stream has SELECTed, kept
+  across reconnections since a partial resync doesn't SELECT again.
+- slave_servers: the attached replicas, with their sync state and last
+  acknowledged offset. While one is receiving its RDB snapshot the write
+  stream is buffered for it (see replication.rs).
+- repl_seldb: the database the replication stream last SELECTed, so that a
+  SELECT is emitted before any write to a different one.
+- migrate_cached_sockets: open connections to MIGRATE targets, by
+  "host:port" (see migrate.rs).
+- propagate_argv: set by write commands whose effect has to be replicated in
+  another form than they were called with (see `execute_array`).
+- loading: set while the AOF is replayed at startup. The replayed writes
+  are already in the AOF and the history of our replicas, so they are not
+  propagated again.
 */
 impl ServerState {
-    pub fn new(port: u16, replica_of: Option<ServerAddr>) -> Self {
+    pub fn new(config: Config) -> Self {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(config: Config, clock: Arc<dyn Clock>) -> Self {
         let mut repl_id: Option<String> = None;
         let mut repl_offset: Option<u64> = None;
-        match replica_of {
+        match config.replica_of {
             Some(_) => {}
             None => {
-                repl_id = Some("8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb".to_string());
+                repl_id = Some(random_replid());
                 repl_offset = Some(0);
             }
         }
         ServerState {
-            db: HashMap::new(),
-            expiry: HashMap::new(),
-            _port: port,
+            dbs: (0..config.databases).map(|_| Database::new()).collect(),
+            client: Client::new(),
+            clock: clock.clone(),
+            active_expire_db: 0,
+            stat_expired_keys: 0,
+            stat_expired_stale_perc: 0.0,
+            stat_expired_time_cap_reached_count: 0,
+            stat_fork_time_us: 0,
+            stat_total_forks: 0,
             replication_id: repl_id,
             replication_offset: repl_offset,
-            replica_of: replica_of,
+            replication_id2: None,
+            repl_backlog: None,
+            replica_of: config.replica_of.clone(),
+            repl_state: ReplState::Connect,
+            repl_down_since: Some(Instant::now()),
+            master_link: None,
+            master_stream_db: 0,
+            persistence: persistence::RdbState::new(clock.now_ms() / 1000),
+            aof: aof::AofState::new(),
+            config,
             slave_servers: Vec::new(),
+            repl_seldb: None,
+            migrate_cached_sockets: HashMap::new(),
+            propagate_argv: None,
+            loading: false,
         }
     }
 
+    fn db(&self) -> &Database {
+        &self.dbs[self.client.db]
+    }
+
+    fn db_mut(&mut self) -> &mut Database {
+        &mut self.dbs[self.client.db]
+    }
+
     pub fn get_role(&self) -> Role {
         match self.replica_of {
             Some(_) => Role::Slave,
@@ -81,105 +244,226 @@ impl ServerState {
         self.replica_of.clone()
     }
 
-    pub fn retain_slave(&mut self, stream: TcpStream) {
-        println!(
-            "Retaining active slave stream: {}",
-            stream.peer_addr().unwrap()
-        );
-        self.slave_servers.push(Arc::new(Mutex::new(stream))); // Store the stream
+    pub fn get_replication_offset(&self) -> u64 {
+        self.replication_offset.unwrap_or_default()
     }
 
     /*
-    So far, most execuations require the type to be a RespType::Array. Also processes
-    the replication offset.
+    Periodic housekeeping, driven by a dedicated thread every CRON_PERIOD_MS.
     */
-    pub fn execute_resp(&mut self, resp: RespType) -> RespType {
-        match resp.clone() {
-            RespType::Array(arr) => self.execute_array(arr),
-            RespType::BulkString(str) => RespType::BulkString(str),
-            RespType::SimpleString(_) => todo!(),
-            RespType::Error(_) => todo!(),
-            RespType::Integer(_) => todo!(),
-            RespType::NullBulkString => RespType::NullBulkString,
-            RespType::NullArray => RespType::NullArray,
-        }
+    pub fn server_cron(&mut self) {
+        self.active_expire_cycle();
+        self.check_bgsave_done();
+        self.check_save_points();
+        self.aof_cron();
+        self.migrate_close_timedout_sockets();
+        self.replication_cron();
     }
 
-    pub fn update_replication_offset(&mut self, resp: RespType) {
-        match self.replication_offset {
-            Some(offset) => {
-                let new_offset: u64 = resp.to_resp_string().into_bytes().len() as u64;
-                println!("-Replication new_offset: {}", new_offset);
-                self.replication_offset = Some(offset + new_offset);
+    /*
+    Executes a command on behalf of `client`, in the context of the database
+    it has selected.
+    */
+    pub fn execute(&mut self, client: &mut Client, resp: RespType) -> RespType {
+        std::mem::swap(&mut self.client, client);
+        let response: RespType = self.execute_resp(resp);
+        std::mem::swap(&mut self.client, client);
+        response
+    }
+
+    /*
+    A command is a non-empty array of bulk strings. Anything else a client
+    sends is answered with a protocol error instead of being executed.
+    */
+    pub fn execute_resp(&mut self, resp: RespType) -> RespType {
+        match resp {
+            RespType::Array(arr)
+                if !arr.is_empty()
+                    && arr
+                        .iter()
+                        .all(|a| matches!(a, RespType::BulkString(_) | RespType::BulkBytes(_))) =>
+            {
+                self.execute_array(arr)
             }
-            None => {}
+            _ => RespType::Error(
+                "ERR Protocol error: expected a non-empty array of bulk strings".to_string(),
+            ),
         }
     }
 
     /*
     Command is always the first element in the array.
+
+    A write command that changed the dataset (it bumped `dirty`) is then
+    propagated: as called, or in the effective form the handler gave with
+    `rewrite_command`, e.g. a relative time to live made absolute so that
+    replaying it later gives the same result.
      */
     fn execute_array(&mut self, arr: Vec<RespType>) -> RespType {
-        match arr[0].clone() {
-            RespType::BulkString(str) => match str.to_lowercase().as_str() {
-                "ping" => RespType::SimpleString("PONG".to_string()),
-                "echo" => self.execute_resp(arr[1].clone()),
-                "set" => self.handle_set(arr),
-                "get" => self.handle_get(arr),
-                "info" => self.handle_info(arr),
-                "replconf" => self.handle_replconf(arr),
-                "psync" => self.handle_psync(arr),
-                "command" => RespType::Error("Not implemented".to_string()),
-                _ => RespType::Error("ERR unknown command".to_string()),
+        let name: String = match &arr[0] {
+            RespType::BulkString(str) => str.to_lowercase(),
+            _ => return RespType::Error("ERR unknown command".to_string()),
+        };
+        let flags: u32 = command_flags(&name);
+        if let Err(e) = self.check_replica_access(flags) {
+            return e;
+        }
+        let argv: Option<Vec<RespType>> = match flags & CMD_WRITE {
+            0 => None,
+            _ => Some(arr.clone()),
+        };
+        let dirty: u64 = self.persistence.dirty;
+        let response: RespType = self.dispatch(&name, arr);
+
+        let rewritten: Option<Vec<RespType>> = self.propagate_argv.take();
+        if let Some(argv) = argv.filter(|_| self.persistence.dirty > dirty && !self.loading) {
+            self.propagate(rewritten.unwrap_or(argv));
+            self.client.woff = self.replication_offset.unwrap_or_default();
+        }
+        response
+    }
+
+    /*
+    Replaces the arguments a write command is propagated with.
+    */
+    fn rewrite_command(&mut self, argv: Vec<RespType>) {
+        self.propagate_argv = Some(argv);
+    }
+
+    fn dispatch(&mut self, name: &str, arr: Vec<RespType>) -> RespType {
+        match name {
+            "ping" => RespType::SimpleString("PONG".to_string()),
+            "echo" => match arr.get(1) {
+                Some(msg) if arr.len() == 2 => msg.clone(),
+                _ => wrong_arity("echo"),
             },
+            "set" => self.handle_set(arr),
+            "get" => self.handle_get(arr),
+            "keys" => self.handle_keys(arr),
+            "scan" => self.handle_scan(arr),
+            "select" => self.handle_select(arr),
+            "del" => self.handle_del(arr),
+            "move" => self.handle_move(arr),
+            "swapdb" => self.handle_swapdb(arr),
+            "flushdb" => self.handle_flushdb(arr),
+            "flushall" => self.handle_flushall(arr),
+            "expire" => self.handle_expire(arr, "expire", 1000, false),
+            "pexpire" => self.handle_expire(arr, "pexpire", 1, false),
+            "expireat" => self.handle_expire(arr, "expireat", 1000, true),
+            "pexpireat" => self.handle_expire(arr, "pexpireat", 1, true),
+            "ttl" => self.handle_ttl(arr, "ttl", 1000, false),
+            "pttl" => self.handle_ttl(arr, "pttl", 1, false),
+            "expiretime" => self.handle_ttl(arr, "expiretime", 1000, true),
+            "pexpiretime" => self.handle_ttl(arr, "pexpiretime", 1, true),
+            "persist" => self.handle_persist(arr),
+            "dump" => self.handle_dump(arr),
+            "restore" => self.handle_restore(arr),
+            "migrate" => self.handle_migrate(arr),
+            "info" => self.handle_info(arr),
+            "wait" => self.handle_wait(arr),
+            "waitaof" => self.handle_waitaof(arr),
+            "replconf" => self.handle_replconf(arr),
+            "psync" => self.handle_psync(arr),
+            "replicaof" => self.handle_replicaof(arr, "replicaof"),
+            "slaveof" => self.handle_replicaof(arr, "slaveof"),
+            "config" => self.handle_config(arr),
+            "save" => self.handle_save(arr),
+            "bgsave" => self.handle_bgsave(arr),
+            "lastsave" => self.handle_lastsave(arr),
+            "bgrewriteaof" => self.handle_bgrewriteaof(arr),
+            "command" => RespType::Error("Not implemented".to_string()),
             _ => RespType::Error("ERR unknown command".to_string()),
         }
     }
 
+    /*
+    SET key value [EX seconds | PX milliseconds | EXAT unix-time-seconds |
+        PXAT unix-time-milliseconds | KEEPTTL]
+    Without an expire option or KEEPTTL, any previous time to live is
+    discarded. An expire is propagated as an absolute PXAT.
+    */
     fn handle_set(&mut self, arr: Vec<RespType>) -> RespType {
-        let key: String = match self.execute_resp(arr[1].clone()) {
-            RespType::BulkString(s) => s,
-            _ => return RespType::Error("ERR key is not a valid BulkString".to_string()),
+        if arr.len() < 3 {
+            return wrong_arity("set");
+        }
+        let key: String = match arg_string(&arr, 1) {
+            Ok(s) => s,
+            Err(e) => return e,
         };
-        let value: String = match self.execute_resp(arr[2].clone()) {
-            RespType::BulkString(s) => s,
-            _ => return RespType::Error("ERR value is not a valid BulkString".to_string()),
+        let value: String = match arg_string(&arr, 2) {
+            Ok(s) => s,
+            Err(e) => return e,
         };
 
-        println!("-inserted ({}, {})", key, value);
-        if arr.len() == 3 {
-            self.db.insert(key.clone(), value.clone());
-            self.propagate_set(key.clone(), value.clone(), None);
-            return RespType::SimpleString("OK".to_string());
+        let now: i64 = self.clock.now_ms() as i64;
+        let mut expire: Option<i64> = None;
+        let mut keepttl: bool = false;
+        let mut idx: usize = 3;
+        while idx < arr.len() {
+            let option: String = match arg_string(&arr, idx) {
+                Ok(s) => s.to_lowercase(),
+                Err(e) => return e,
+            };
+            match option.as_str() {
+                "keepttl" if expire.is_none() => keepttl = true,
+                "ex" | "px" | "exat" | "pxat"
+                    if expire.is_none() && !keepttl && idx + 1 < arr.len() =>
+                {
+                    idx += 1;
+                    let time: i64 = match arg_i64(&arr, idx) {
+                        Ok(time) => time,
+                        Err(e) => return e,
+                    };
+                    let unit: i64 = if option.starts_with("ex") { 1000 } else { 1 };
+                    let base: i64 = if option.ends_with("at") { 0 } else { now };
+                    expire = match time.checked_mul(unit).and_then(|ms| ms.checked_add(base)) {
+                        Some(when) if time > 0 => Some(when),
+                        _ => {
+                            return RespType::Error(
+                                "ERR invalid expire time in 'set' command".to_string(),
+                            )
+                        }
+                    };
+                }
+                _ => return RespType::Error("ERR syntax error".to_string()),
+            }
+            idx += 1;
         }
-        match arr[3].clone() {
-            RespType::BulkString(str) => match str.to_lowercase().as_str() {
-                "px" => match arr[4].clone() {
-                    RespType::BulkString(str) => {
-                        let expiry: u64 = str.parse().unwrap();
-                        let expiry_time = Instant::now()
-                            .checked_add(Duration::from_millis(expiry))
-                            .unwrap();
-                        self.db.insert(key.clone(), value.clone());
-                        self.expiry.insert(key.clone(), expiry_time);
-                        self.propagate_set(key.clone(), value.clone(), Some(expiry));
-                        RespType::SimpleString("OK".to_string())
-                    }
-                    _ => RespType::Error("ERR value is not a valid RESP type".to_string()),
-                },
-                _ => RespType::Error("ERR value is not a valid RESP type".to_string()),
-            },
-            _ => RespType::Error("ERR value is not a valid RESP type".to_string()),
+
+        println!("-inserted ({}, {})", key, value);
+        self.db_mut().dict.insert(key.clone(), value.clone());
+        match expire {
+            Some(when) => {
+                self.db_mut().expires.insert(key.clone(), when as u64);
+                self.rewrite_command(vec![
+                    RespType::BulkString("SET".to_string()),
+                    RespType::BulkString(key),
+                    RespType::BulkString(value),
+                    RespType::BulkString("PXAT".to_string()),
+                    RespType::BulkString(when.to_string()),
+                ]);
+            }
+            None if !keepttl => {
+                self.db_mut().expires.remove(&key);
+            }
+            None => {}
         }
+        self.persistence.dirty += 1;
+        RespType::SimpleString("OK".to_string())
     }
 
     fn handle_get(&mut self, arr: Vec<RespType>) -> RespType {
-        let key: String = match self.execute_resp(arr[1].clone()) {
-            RespType::BulkString(s) => s,
+        if arr.len() != 2 {
+            return wrong_arity("get");
+        }
+        let key: String = match &arr[1] {
+            RespType::BulkString(s) => s.clone(),
             _ => return RespType::Error("ERR key is not a valid BulkString".to_string()),
         };
-        self.check_expiry();
-        match self.db.get(&key) {
+        if self.expire_if_needed(&key) {
+            return RespType::NullBulkString;
+        }
+        match self.db().dict.get(&key) {
             Some(val) => {
                 RespType::SimpleString(val.clone())
             }
@@ -188,143 +472,283 @@ impl ServerState {
     }
 
     fn handle_info(&self, arr: Vec<RespType>) -> RespType {
-        match arr[1].clone() {
-            RespType::BulkString(str) => match str.to_lowercase().as_str() {
-                "replication" => {
-                    let mut output: Vec<String> = Vec::new();
-                    let role = self.get_role();
-                    output.push(format!("role:{}", role));
-                    if role == Role::Master {
-                        output.push(format!(
-                            "master_replid:{}",
-                            self.replication_id.clone().unwrap()
-                        ));
-                        output.push(format!(
-                            "master_repl_offset:{}",
-                            self.replication_offset.clone().unwrap()
-                        ));
+        let section: String = match arr.get(1) {
+            Some(RespType::BulkString(str)) => str.to_lowercase(),
+            Some(_) => return RespType::Error("ERR unknown subcommand".to_string()),
+            None => "default".to_string(),
+        };
+        let output: Vec<String> = match section.as_str() {
+            "replication" => self.info_replication(),
+            "stats" => self.info_stats(),
+            "persistence" => self.info_persistence(),
+            "default" | "all" | "everything" => {
+                let mut output: Vec<String> = self.info_persistence();
+                output.push(String::new());
+                output.extend(self.info_stats());
+                output.push(String::new());
+                output.extend(self.info_replication());
+                output
+            }
+            _ => return RespType::Error("ERR unknown subcommand".to_string()),
+        };
+        RespType::BulkString(output.join("\n"))
+    }
+
+    fn info_replication(&self) -> Vec<String> {
+        let mut output: Vec<String> = Vec::new();
+        let role = self.get_role();
+        output.push(format!("role:{}", role));
+        if let Some(master) = &self.replica_of {
+            output.extend(self.info_master_link(master));
+        }
+        output.extend(self.info_replicas());
+        if let (Some(replid), Some(offset)) = (&self.replication_id, self.replication_offset) {
+            output.push(format!("master_replid:{}", replid));
+            output.push(format!(
+                "master_replid2:{}",
+                self.replication_id2
+                    .as_ref()
+                    .map_or("0".repeat(40), |(replid2, _)| replid2.clone())
+            ));
+            output.push(format!("master_repl_offset:{}", offset));
+            output.push(format!(
+                "second_repl_offset:{}",
+                self.replication_id2
+                    .as_ref()
+                    .map_or(-1, |(_, offset2)| *offset2 as i64)
+            ));
+            output.extend(self.info_repl_backlog(offset));
+        }
+        output
+    }
+
+    fn info_stats(&self) -> Vec<String> {
+        vec![
+            format!("expired_keys:{}", self.stat_expired_keys),
+            format!(
+                "expired_stale_perc:{:.2}",
+                self.stat_expired_stale_perc * 100.0
+            ),
+            format!(
+                "expired_time_cap_reached_count:{}",
+                self.stat_expired_time_cap_reached_count
+            ),
+            format!("total_forks:{}", self.stat_total_forks),
+            format!("latest_fork_usec:{}", self.stat_fork_time_us),
+        ]
+    }
+
+    /*
+    CONFIG GET parameter [parameter ...]
+    Each parameter is a glob-style pattern matched against the names.
+    */
+    fn handle_config(&mut self, arr: Vec<RespType>) -> RespType {
+        let subcommand: String = match arg_string(&arr, 1) {
+            Ok(s) => s.to_lowercase(),
+            Err(_) => return wrong_arity("config"),
+        };
+        match subcommand.as_str() {
+            "get" => {
+                if arr.len() < 3 {
+                    return wrong_arity("config|get");
+                }
+                let mut patterns: Vec<String> = Vec::new();
+                for idx in 2..arr.len() {
+                    match arg_string(&arr, idx) {
+                        Ok(p) => patterns.push(p),
+                        Err(e) => return e,
                     }
-                    RespType::BulkString(output.join("\n"))
                 }
-                _ => RespType::Error("ERR unknown subcommand".to_string()),
-            },
-            _ => RespType::Error("ERR unknown subcommand".to_string()),
+                let mut output: Vec<RespType> = Vec::new();
+                for (name, value) in self.config.params() {
+                    if patterns
+                        .iter()
+                        .any(|p| glob_match_bytes(p.as_bytes(), name.as_bytes(), true))
+                    {
+                        output.push(RespType::BulkString(name.to_string()));
+                        output.push(RespType::BulkString(value));
+                    }
+                }
+                RespType::Array(output)
+            }
+            _ => RespType::Error(format!(
+                "ERR unknown subcommand '{}'. Try CONFIG HELP.",
+                subcommand
+            )),
         }
     }
 
+    /*
+    REPLCONF option value [option value ...]
+    Sent by replicas: listening-port and capa during the handshake, then ACK
+    with the offset they reached. A master sends GETACK to ask for one.
+    */
     fn handle_replconf(&mut self, arr: Vec<RespType>) -> RespType {
-        // need to extract the information from the arr
-        match arr[1].clone() {
-            RespType::BulkString(str) => match str.to_lowercase().as_str() {
-                "listening-port" => {
-                    let port: u16 = match arr[2].clone() {
-                        RespType::BulkString(str) => str.parse().unwrap(),
-                        _ => {
-                            return RespType::Error(
-                                "ERR port is not a valid BulkString".to_string(),
-                            )
-                        }
-                    };
-                    println!("-Received slave port: {}", port);
-                    RespType::SimpleString("OK".to_string())
-                }
-                "capa" => {
-                    let capa: String = match arr[2].clone() {
-                        RespType::BulkString(str) => str,
-                        _ => {
-                            return RespType::Error(
-                                "ERR capa is not a valid BulkString".to_string(),
-                            )
-                        }
-                    };
-                    if capa == "psync2" {
-                        RespType::SimpleString("OK".to_string())
-                    } else {
-                        RespType::Error("ERR unknown capability".to_string())
+        if arr.len().is_multiple_of(2) {
+            return RespType::Error("ERR syntax error".to_string());
+        }
+        for idx in (1..arr.len()).step_by(2) {
+            let option: String = match arg_string(&arr, idx) {
+                Ok(s) => s.to_lowercase(),
+                Err(e) => return e,
+            };
+            match option.as_str() {
+                "listening-port" => match arg_i64(&arr, idx + 1) {
+                    Ok(port) if (0..=65535).contains(&port) => {
+                        println!("-Received slave port: {}", port);
+                        self.client.repl_listening_port = port as u16;
                     }
-                }
+                    Ok(_) => {
+                        return RespType::Error(
+                            "ERR value is not an integer or out of range".to_string(),
+                        )
+                    }
+                    Err(e) => return e,
+                },
+                "capa" => match arg_string(&arr, idx + 1) {
+                    // capabilities we don't know are ignored
+                    Ok(capa) => self.client.repl_capa.push(capa.to_lowercase()),
+                    Err(e) => return e,
+                },
                 "getack" => {
-                    // this is the only time the offset value changes from None
-                    let offset: u64 = match self.replication_offset {
-                        Some(offset) => offset,
-                        None => 0,
-                    };
+                    let offset: u64 = self.replication_offset.unwrap_or_default();
                     self.replication_offset = Some(offset);
-                    RespType::Array(vec![
+                    let mut ack: Vec<RespType> = vec![
                         RespType::BulkString("REPLCONF".to_string()),
                         RespType::BulkString("ACK".to_string()),
                         RespType::BulkString(offset.to_string()),
-                    ])
+                    ];
+                    // with the AOF on, also tell how much of it is fsynced
+                    if let Some(aof_offset) = self.aof_fsynced_reploff() {
+                        ack.push(RespType::BulkString("FACK".to_string()));
+                        ack.push(RespType::BulkString(aof_offset.to_string()));
+                    }
+                    return RespType::Array(ack);
                 }
-                _ => RespType::Error("ERR unknown subcommand".to_string()),
-            },
-            _ => RespType::Error("ERR unknown subcommand".to_string()),
+                "ack" => {
+                    self.client.skip_reply = true;
+                    self.replica_ack(&arr);
+                    return RespType::SimpleString("OK".to_string());
+                }
+                _ => {
+                    return RespType::Error(format!(
+                        "ERR Unrecognized REPLCONF option: {}",
+                        option
+                    ))
+                }
+            }
         }
+        RespType::SimpleString("OK".to_string())
     }
 
     /*
-    Sync the data from the master to the slave
+    PSYNC replid offset
+    Continues the replica's history from the backlog when possible, and
+    otherwise syncs it from scratch. The connection thread sends the backlog
+    or the RDB snapshot right after this reply (see `start_partial_resync`
+    and `start_full_resync`).
      */
-    fn handle_psync(&mut self, _arr: Vec<RespType>) -> RespType {
-        // will always send this, change on first sync call on ?
+    fn handle_psync(&mut self, arr: Vec<RespType>) -> RespType {
+        if arr.len() != 3 {
+            return wrong_arity("psync");
+        }
+        let replid: String = match arg_string(&arr, 1) {
+            Ok(s) => s,
+            Err(e) => return e,
+        };
+        let psync_offset: i64 = match arg_i64(&arr, 2) {
+            Ok(offset) => offset,
+            Err(e) => return e,
+        };
+        // a replica hands down its master's history, once it has it
+        let link_down: bool =
+            self.replica_of.is_some() && self.repl_state != ReplState::Connected;
+        let master_replid: String = match &self.replication_id {
+            Some(replid) if !link_down => replid.clone(),
+            _ => {
+                return RespType::Error(
+                    "NOMASTERLINK Can't SYNC while not connected with my master".to_string(),
+                )
+            }
+        };
+
+        if self.can_partial_resync(&replid, psync_offset) {
+            self.client.resync = Some(Resync::Partial(psync_offset as u64));
+            return RespType::SimpleString(format!("CONTINUE {}", master_replid));
+        }
+        // answered once the sync starts, see `replication_cron`
+        if self.diskless_sync_for_client() {
+            self.client.resync = Some(Resync::Diskless);
+            return RespType::NullBulkString;
+        }
+        self.client.resync = Some(Resync::Full);
         let out: String = format!(
             "FULLRESYNC {} {}",
-            self.replication_id.clone().unwrap(),
-            self.replication_offset.clone().unwrap()
+            master_replid,
+            self.replication_offset.unwrap_or_default()
         );
         RespType::SimpleString(out)
     }
 
     /*
-    Hard coded empty file.
+    Appends a write command to the AOF and sends it to every connected slave,
+    preceded by a SELECT if it targets a different database than the previous
+    one.
     */
-    pub fn full_resync(&mut self) -> (String, Vec<u8>) {
-        let rdb_bytes: Vec<u8> = hex::decode(EMPTY_RDB_FILE).unwrap();
-        (format!("${}\r\n", rdb_bytes.len()), rdb_bytes)
-    }
+    fn propagate(&mut self, cmd: Vec<RespType>) {
+        // as bytes, so that binary arguments (RESTORE payloads) keep the
+        // length they are announced with
+        let command: Vec<u8> = RespType::Array(cmd).to_resp_bytes();
 
-    pub fn propagate_set(&mut self, key: String, value: String, expiry: Option<u64>) {
-        let mut cmd: Vec<RespType> = vec![
-            RespType::BulkString("SET".to_string()),
-            RespType::BulkString(key.clone()),
-            RespType::BulkString(value.clone()),
-        ];
-        match expiry {
-            Some(time) => {
-                cmd.push(RespType::BulkString("PX".to_string()));
-                cmd.push(RespType::BulkString(time.to_string()));
-            }
-            None => {}
-        }
-        let serialized_command: String = RespType::Array(cmd).to_resp_string();
-        for slave in &self.slave_servers {
-            let mut stream = slave.lock().unwrap();
-            if let Err(e) = stream.write_all(serialized_command.as_bytes()) {
-                eprintln!(
-                    "Failed to send command to slave {}: {:?}",
-                    stream.peer_addr().unwrap(),
-                    e
-                );
-            } else {
-                println!(
-                    "Successfully propagated command to slave {}",
-                    stream.peer_addr().unwrap()
+        // a replica's offset follows its master's stream instead (see
+        // `master_stream_consumed`)
+        if self.replica_of.is_none() {
+            let mut serialized_command: Vec<u8> = Vec::new();
+            if self.repl_seldb != Some(self.client.db) {
+                serialized_command.extend_from_slice(
+                    &RespType::Array(vec![
+                        RespType::BulkString("SELECT".to_string()),
+                        RespType::BulkString(self.client.db.to_string()),
+                    ])
+                    .to_resp_bytes(),
                 );
+                self.repl_seldb = Some(self.client.db);
             }
+            serialized_command.extend_from_slice(&command);
+            self.feed_replicas(&serialized_command);
         }
+
+        // after the offset moved, so that an fsync covers it
+        self.feed_append_only_file(&command);
     }
+}
 
-    fn check_expiry(&mut self) {
-        let now = Instant::now();
-        let mut expired_keys: Vec<String> = Vec::new();
-        for (key, expiry) in self.expiry.iter() {
-            if now > *expiry {
-                expired_keys.push(key.clone());
-            }
-        }
-        for key in expired_keys {
-            self.db.remove(&key);
-            self.expiry.remove(&key);
-        }
+/*
+Returns the argument at `idx` as a String. A missing or non-BulkString argument
+yields the error reply to send back to the client instead.
+*/
+fn arg_string(arr: &[RespType], idx: usize) -> Result<String, RespType> {
+    match arr.get(idx) {
+        Some(RespType::BulkString(s)) => Ok(s.clone()),
+        Some(_) => Err(RespType::Error(
+            "ERR argument is not a valid BulkString".to_string(),
+        )),
+        None => Err(RespType::Error("ERR syntax error".to_string())),
     }
 }
+
+/*
+Same as `arg_string`, but the argument must also parse as a signed integer.
+*/
+fn arg_i64(arr: &[RespType], idx: usize) -> Result<i64, RespType> {
+    arg_string(arr, idx)?
+        .parse::<i64>()
+        .map_err(|_| RespType::Error("ERR value is not an integer or out of range".to_string()))
+}
+
+fn wrong_arity(cmd: &str) -> RespType {
+    RespType::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        cmd
+    ))
+}
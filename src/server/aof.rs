@@ -0,0 +1,1063 @@
+use super::{persistence::write_file_atomically, wrong_arity, ServerState};
+use crate::{
+    aof_manifest::{base_name, incr_name, manifest_name, AofFileType, AofInfo, AofManifest},
+    client::Client,
+    config::AppendFsync,
+    db::Database,
+    parser::{parse_resp_prefix, RespType},
+    rdb::{dump_databases, parse_rdb_prefix, Rdb, RDB_MAGIC},
+};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, Receiver, TryRecvError},
+        Arc,
+    },
+    thread,
+};
+
+/*
+Append only file, in the multi-part layout of Redis 7: `dir`/`appenddirname`
+holds a base file (a snapshot of the dataset, RDB or AOF encoded) and
+numbered incremental files, listed in order by a manifest (see
+aof_manifest.rs). Every write command is appended in RESP to the last
+incremental file, exactly as it is propagated to replicas, and the whole
+sequence is replayed on startup.
+
+BGREWRITEAOF never blocks writers: it first switches writes to a new
+incremental file, then snapshots the dataset into a new base in the
+background. Once the base is written, the manifest is atomically replaced
+with one listing the new base and only the incremental files opened since,
+and the files it no longer lists are deleted.
+*/
+
+// with appendfsync everysec, how often the cron fsyncs the file
+const AOF_FSYNC_PERIOD_MS: u64 = 1000;
+
+/*
+Bookkeeping for the append only file.
+- manifest: the files of the AOF, as last persisted.
+- file: the last incremental file, opened for appending while appendonly is
+  on.
+- seldb: the database the file last SELECTed, like `repl_seldb` for replicas.
+- fsync_pending: writes were made since the last fsync (appendfsync everysec).
+- fsynced_reploff / fsync_in_progress: the replication offset the last
+  background fsync covered, and whether one is running, shared with its
+  thread. WAITAOF reads them through `aof_fsynced_reploff`.
+- rewrite: the BGREWRITEAOF in progress, if any.
+*/
+pub(super) struct AofState {
+    manifest: AofManifest,
+    file: Option<File>,
+    seldb: Option<usize>,
+    last_fsync_ms: u64,
+    fsync_pending: bool,
+    fsynced_reploff: Arc<AtomicU64>,
+    fsync_in_progress: Arc<AtomicBool>,
+    last_write_ok: bool,
+    rewrite: Option<AofRewrite>,
+    lastbgrewrite_ok: bool,
+}
+
+/*
+A background rewrite: the thread reports back the temporary file it wrote,
+which becomes `base`. Incremental files from `first_kept_incr` on hold the
+writes made after the snapshot and stay in the manifest (None if the AOF was
+off, so nothing is kept).
+*/
+struct AofRewrite {
+    done: Receiver<Result<PathBuf, String>>,
+    base: AofInfo,
+    first_kept_incr: Option<u64>,
+}
+
+impl AofState {
+    pub fn new() -> Self {
+        AofState {
+            manifest: AofManifest::default(),
+            file: None,
+            seldb: None,
+            last_fsync_ms: 0,
+            fsync_pending: false,
+            fsynced_reploff: Arc::new(AtomicU64::new(0)),
+            fsync_in_progress: Arc::new(AtomicBool::new(false)),
+            last_write_ok: true,
+            rewrite: None,
+            lastbgrewrite_ok: true,
+        }
+    }
+}
+
+/*
+Serializes the dataset as the commands that rebuild it: a SELECT per non
+empty database and a SET per key, with PXAT for keys with a time to live.
+Keys that are already expired at `now` are left out.
+*/
+fn aof_commands(dbs: &[Database], now: u64) -> Vec<u8> {
+    let mut out: String = String::new();
+    for (id, db) in dbs.iter().enumerate() {
+        if db.is_empty() {
+            continue;
+        }
+        out.push_str(&command(&["SELECT", &id.to_string()]));
+        for (key, value) in db.dict.iter() {
+            let expire: Option<u64> = db.expires.get(key).copied();
+            if expire.is_some_and(|when| when <= now) {
+                continue;
+            }
+            // absolute deadlines, so the TTL doesn't restart on every reload
+            match expire {
+                Some(when) => {
+                    out.push_str(&command(&["SET", key, value, "PXAT", &when.to_string()]))
+                }
+                None => out.push_str(&command(&["SET", key, value])),
+            }
+        }
+    }
+    out.into_bytes()
+}
+
+fn command(args: &[&str]) -> String {
+    RespType::Array(
+        args.iter()
+            .map(|arg| RespType::BulkString(arg.to_string()))
+            .collect(),
+    )
+    .to_resp_string()
+}
+
+/*
+The contents of a base file: an RDB snapshot (with the aof-base aux field
+set) when aof-use-rdb-preamble is on, the equivalent commands otherwise.
+*/
+fn aof_base_bytes(dbs: &[Database], aux: &[(String, String)], rdb: bool, now: u64) -> Vec<u8> {
+    if !rdb {
+        return aof_commands(dbs, now);
+    }
+    let aux: Vec<(String, String)> = aux
+        .iter()
+        .map(|(key, value)| match key.as_str() {
+            "aof-base" => (key.clone(), "1".to_string()),
+            _ => (key.clone(), value.clone()),
+        })
+        .collect();
+    dump_databases(dbs, &aux)
+}
+
+impl ServerState {
+    fn aof_dir(&self) -> PathBuf {
+        self.config.aof_dir()
+    }
+
+    fn aof_manifest_path(&self) -> PathBuf {
+        self.aof_dir()
+            .join(manifest_name(&self.config.appendfilename))
+    }
+
+    /*
+    Startup with appendonly on: loads the dataset from the AOF and opens its
+    last incremental file for appending.
+    - With a manifest, its base and incremental files are replayed in order.
+    - A single-file AOF from an older version is moved into the directory and
+      becomes the base.
+    - Without either, the dataset comes from the RDB snapshot, which is then
+      written out as the first base so nothing is lost on the next restart.
+    */
+    pub(super) fn load_append_only_files(&mut self) -> Result<(), String> {
+        let dir: PathBuf = self.aof_dir();
+        let prefix: String = self.config.appendfilename.clone();
+        let manifest_path: PathBuf = self.aof_manifest_path();
+        let old_aof: PathBuf = self.config.aof_path();
+
+        if manifest_path.exists() {
+            self.aof.manifest = self.read_aof_manifest(&manifest_path)?;
+            let files: Vec<AofInfo> = self.aof.manifest.data_files().cloned().collect();
+            let mut replayed: usize = 0;
+            for (idx, info) in files.iter().enumerate() {
+                replayed += self.load_aof_file(&dir.join(&info.name), idx + 1 == files.len())?;
+            }
+            // the replication offset saved with the base is behind the data
+            if replayed > 0 {
+                self.discard_replication_info();
+            }
+        } else {
+            fs::create_dir_all(&dir)
+                .map_err(|e| format!("Can't create the AOF directory {}: {}", dir.display(), e))?;
+            let base = AofInfo {
+                name: prefix.clone(),
+                seq: 1,
+                file_type: AofFileType::Base,
+            };
+            if old_aof.is_file() {
+                println!("Upgrading {} to a multi-part AOF", old_aof.display());
+                fs::rename(&old_aof, dir.join(&base.name))
+                    .map_err(|e| format!("Failed moving {}: {}", old_aof.display(), e))?;
+                if self.load_aof_file(&dir.join(&base.name), true)? > 0 {
+                    self.discard_replication_info();
+                }
+                self.aof.manifest.set_base(base, None);
+            } else {
+                self.load_rdb_file()?;
+                let rdb: bool = self.config.aof_use_rdb_preamble;
+                let base = AofInfo {
+                    name: base_name(&prefix, 1, rdb),
+                    ..base
+                };
+                let bytes: Vec<u8> =
+                    aof_base_bytes(&self.dbs, &self.rdb_aux(), rdb, self.clock.now_ms());
+                write_file_atomically(&dir.join(&base.name), &bytes)?;
+                self.aof.manifest.set_base(base, None);
+            }
+        }
+
+        if self.aof.manifest.incrs.is_empty() {
+            self.aof.manifest.add_incr(&prefix);
+        }
+        self.persist_aof_manifest()?;
+        self.remove_stale_aof_files();
+        self.open_append_only_file()
+    }
+
+    /*
+    Reads the manifest, recovering from a crash that left it behind the
+    files on disk:
+    - a last line cut short is dropped;
+    - incremental files newer than the last one listed hold writes made after
+      the manifest was written, so they are appended to it.
+    */
+    fn read_aof_manifest(&self, path: &Path) -> Result<AofManifest, String> {
+        let mut text: String = fs::read_to_string(path)
+            .map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+        if !text.is_empty() && !text.ends_with('\n') {
+            let keep: usize = text.rfind('\n').map_or(0, |pos| pos + 1);
+            eprintln!(
+                "!!! Warning: the AOF manifest {} ends with a partial line, ignoring it: {:?}",
+                path.display(),
+                &text[keep..]
+            );
+            text.truncate(keep);
+        }
+        let mut manifest: AofManifest = if text.trim().is_empty() {
+            AofManifest::default()
+        } else {
+            AofManifest::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))?
+        };
+
+        let prefix: &str = &self.config.appendfilename;
+        let mut newer: Vec<u64> = self
+            .aof_dir_entries()
+            .iter()
+            .filter_map(|name| {
+                let seq: u64 = name
+                    .strip_prefix(prefix)?
+                    .strip_prefix('.')?
+                    .strip_suffix(".incr.aof")?
+                    .parse()
+                    .ok()?;
+                (incr_name(prefix, seq) == *name).then_some(seq)
+            })
+            .filter(|seq| *seq > manifest.cur_incr_seq)
+            .collect();
+        newer.sort_unstable();
+        for seq in newer {
+            eprintln!(
+                "!!! Warning: {} is missing from the AOF manifest, adding it back",
+                incr_name(prefix, seq)
+            );
+            manifest.cur_incr_seq = seq - 1;
+            manifest.add_incr(prefix);
+        }
+
+        if manifest.base.is_none() && manifest.incrs.is_empty() {
+            return Err(format!("Found an empty AOF manifest {}", path.display()));
+        }
+        Ok(manifest)
+    }
+
+    fn aof_dir_entries(&self) -> Vec<String> {
+        fs::read_dir(self.aof_dir())
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .filter_map(|entry| entry.file_name().into_string().ok())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /*
+    Replays one file of the AOF. A base file may start with an RDB snapshot,
+    and a single-file AOF from an older version may have commands after it. A
+    file that ends in the middle of a command (the server died while
+    appending) is truncated to its last complete command if it is the last
+    file and aof-load-truncated is on, and refused otherwise. Returns the
+    number of commands replayed.
+    */
+    fn load_aof_file(&mut self, path: &Path, last: bool) -> Result<usize, String> {
+        let bytes: Vec<u8> =
+            fs::read(path).map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+        let mut pos: usize = 0;
+        if bytes.starts_with(RDB_MAGIC) {
+            let (rdb, size): (Rdb, usize) = parse_rdb_prefix(&bytes)
+                .map_err(|e| format!("Bad RDB preamble in {}: {}", path.display(), e))?;
+            let aux: Vec<(String, String)> = rdb.aux.clone();
+            let loaded: usize = self.load_rdb(rdb)?;
+            println!("Loaded {} keys from {}", loaded, path.display());
+            self.restore_replication_info(&aux);
+            pos = size;
+        }
+
+        let mut client: Client = Client::new();
+        let mut commands: usize = 0;
+        while pos < bytes.len() {
+            match parse_resp_prefix(&bytes[pos..]) {
+                Ok(Some((cmd @ RespType::Array(_), size))) => {
+                    // a command refused now was not what the file recorded
+                    if let RespType::Error(e) = self.execute(&mut client, cmd) {
+                        return Err(format!(
+                            "Bad file format reading the append only file {} at offset {}: {}",
+                            path.display(),
+                            pos,
+                            e
+                        ));
+                    }
+                    pos += size;
+                    commands += 1;
+                }
+                Ok(Some(_)) | Err(_) => {
+                    return Err(format!(
+                        "Bad file format reading the append only file {} at offset {}",
+                        path.display(),
+                        pos
+                    ));
+                }
+                Ok(None) => {
+                    if !last || !self.config.aof_load_truncated {
+                        return Err(format!(
+                            "Unexpected end of file reading the append only file {} at offset {}. \
+                             Start with --aof-load-truncated yes to load it anyway",
+                            path.display(),
+                            pos
+                        ));
+                    }
+                    eprintln!(
+                        "!!! Warning: short read while loading the AOF {}. Truncating it to {} bytes",
+                        path.display(),
+                        pos
+                    );
+                    OpenOptions::new()
+                        .write(true)
+                        .open(path)
+                        .and_then(|file| file.set_len(pos as u64))
+                        .map_err(|e| format!("Failed truncating {}: {}", path.display(), e))?;
+                    break;
+                }
+            }
+        }
+        println!("Replayed {} commands from {}", commands, path.display());
+        Ok(commands)
+    }
+
+    /*
+    Atomically replaces the manifest on disk, then deletes the history files.
+    They are not listed in the new manifest, so if we crash before deleting
+    them they are removed as stale on the next startup.
+    */
+    fn persist_aof_manifest(&mut self) -> Result<(), String> {
+        let history: Vec<AofInfo> = std::mem::take(&mut self.aof.manifest.history);
+        write_file_atomically(
+            &self.aof_manifest_path(),
+            self.aof.manifest.to_text().as_bytes(),
+        )?;
+        let dir: PathBuf = self.aof_dir();
+        for info in history {
+            if let Err(e) = fs::remove_file(dir.join(&info.name)) {
+                eprintln!("Failed removing AOF history file {}: {}", info.name, e);
+            }
+        }
+        Ok(())
+    }
+
+    /*
+    Removes what a crash may have left in the AOF directory: temporary files
+    and files of ours the manifest doesn't list.
+    */
+    fn remove_stale_aof_files(&self) {
+        let prefix: &str = &self.config.appendfilename;
+        let manifest: String = manifest_name(prefix);
+        for name in self.aof_dir_entries() {
+            let ours: bool = name.starts_with("temp-") || name.starts_with(&format!("{}.", prefix));
+            let listed: bool =
+                name == manifest || self.aof.manifest.data_files().any(|info| info.name == name);
+            if ours && !listed {
+                println!("Removing stale AOF file {}", name);
+                let _ = fs::remove_file(self.aof_dir().join(name));
+            }
+        }
+    }
+
+    /*
+    Opens the last incremental file for appending. It may end with any
+    database selected, so the next write starts with a SELECT.
+    */
+    fn open_append_only_file(&mut self) -> Result<(), String> {
+        let info: &AofInfo = match self.aof.manifest.incrs.last() {
+            Some(info) => info,
+            None => return Err("The AOF manifest has no incremental file".to_string()),
+        };
+        let path: PathBuf = self.aof_dir().join(&info.name);
+        let file: File = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| format!("Can't open the append-only file {}: {}", path.display(), e))?;
+        self.aof.file = Some(file);
+        self.aof.seldb = None;
+        Ok(())
+    }
+
+    /*
+    Appends a serialized write command to the AOF, preceded by a SELECT if it
+    targets a different database than the previous one.
+    */
+    pub(super) fn feed_append_only_file(&mut self, cmd: &[u8]) {
+        if self.aof.file.is_none() {
+            return;
+        }
+        let mut buf: Vec<u8> = Vec::new();
+        if self.aof.seldb != Some(self.client.db) {
+            buf.extend_from_slice(command(&["SELECT", &self.client.db.to_string()]).as_bytes());
+            self.aof.seldb = Some(self.client.db);
+        }
+        buf.extend_from_slice(cmd);
+
+        let policy: AppendFsync = self.config.appendfsync;
+        let file: &mut File = match self.aof.file.as_mut() {
+            Some(file) => file,
+            None => return,
+        };
+        let result = file.write_all(&buf).and_then(|_| match policy {
+            AppendFsync::Always => file.sync_data(),
+            AppendFsync::Everysec | AppendFsync::No => Ok(()),
+        });
+        match result {
+            Ok(()) => {
+                self.aof.last_write_ok = true;
+                self.aof.fsync_pending = policy == AppendFsync::Everysec;
+            }
+            Err(e) => {
+                eprintln!("Error writing to the AOF file: {}", e);
+                self.aof.last_write_ok = false;
+            }
+        }
+    }
+
+    /*
+    Called from the cron: fsyncs the AOF once per second under appendfsync
+    everysec, and collects the result of a finished BGREWRITEAOF. The fsync
+    runs on its own thread so a slow disk doesn't hold the server lock.
+    */
+    pub(super) fn aof_cron(&mut self) {
+        self.check_aof_rewrite_done();
+
+        let now: u64 = self.clock.now_ms();
+        if !self.aof.fsync_pending
+            || now.saturating_sub(self.aof.last_fsync_ms) < AOF_FSYNC_PERIOD_MS
+        {
+            return;
+        }
+        if let Some(Ok(file)) = self.aof.file.as_ref().map(|file| file.try_clone()) {
+            let offset: u64 = self.replication_offset.unwrap_or_default();
+            let fsynced_reploff: Arc<AtomicU64> = Arc::clone(&self.aof.fsynced_reploff);
+            let in_progress: Arc<AtomicBool> = Arc::clone(&self.aof.fsync_in_progress);
+            in_progress.store(true, Ordering::SeqCst);
+            thread::spawn(move || {
+                match file.sync_data() {
+                    Ok(()) => fsynced_reploff.store(offset, Ordering::SeqCst),
+                    Err(e) => eprintln!("Error fsyncing the AOF file: {}", e),
+                }
+                in_progress.store(false, Ordering::SeqCst);
+            });
+        }
+        self.aof.fsync_pending = false;
+        self.aof.last_fsync_ms = now;
+    }
+
+    /*
+    Background rewrite. Writes switch to a new incremental file and the
+    databases are copied under the same lock, so every write is either in
+    the new base or in the files kept after it. As with BGSAVE, the copy is
+    serialized on another thread.
+    */
+    pub fn rewrite_append_only_file_background(&mut self) -> Result<(), String> {
+        if self.aof.rewrite.is_some() {
+            return Err("Background append only file rewriting already in progress".to_string());
+        }
+        let dir: PathBuf = self.aof_dir();
+        fs::create_dir_all(&dir)
+            .map_err(|e| format!("Can't create the AOF directory {}: {}", dir.display(), e))?;
+        let prefix: String = self.config.appendfilename.clone();
+
+        let mut first_kept_incr: Option<u64> = None;
+        if self.aof.file.is_some() {
+            let incr: AofInfo = self.aof.manifest.add_incr(&prefix);
+            if let Err(e) = self.persist_aof_manifest() {
+                self.aof.manifest.incrs.pop();
+                return Err(e);
+            }
+            self.open_append_only_file()?;
+            first_kept_incr = Some(incr.seq);
+        }
+
+        let rdb: bool = self.config.aof_use_rdb_preamble;
+        let base = AofInfo {
+            name: base_name(&prefix, self.aof.manifest.cur_base_seq + 1, rdb),
+            seq: self.aof.manifest.cur_base_seq + 1,
+            file_type: AofFileType::Base,
+        };
+        let dbs: Vec<Database> = self.snapshot_dbs();
+        let aux: Vec<(String, String)> = self.rdb_aux();
+        let now: u64 = self.clock.now_ms();
+        let tmp_path: PathBuf = dir.join(format!("temp-rewriteaof-bg-{}.aof", std::process::id()));
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let bytes: Vec<u8> = aof_base_bytes(&dbs, &aux, rdb, now);
+            let result = File::create(&tmp_path)
+                .and_then(|mut file| {
+                    file.write_all(&bytes)?;
+                    file.sync_all()
+                })
+                .map(|_| tmp_path.clone())
+                .map_err(|e| format!("Failed writing {}: {}", tmp_path.display(), e));
+            let _ = tx.send(result);
+        });
+        self.aof.rewrite = Some(AofRewrite {
+            done: rx,
+            base,
+            first_kept_incr,
+        });
+        Ok(())
+    }
+
+    /*
+    Completes a finished BGREWRITEAOF: the new base is moved into place, and
+    the manifest switched over to it.
+    */
+    fn check_aof_rewrite_done(&mut self) {
+        let result: Result<PathBuf, String> = match &self.aof.rewrite {
+            Some(rewrite) => match rewrite.done.try_recv() {
+                Ok(result) => result,
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => {
+                    Err("Background AOF rewrite thread terminated".to_string())
+                }
+            },
+            None => return,
+        };
+        let rewrite: AofRewrite = self.aof.rewrite.take().unwrap();
+        let base_path: PathBuf = self.aof_dir().join(&rewrite.base.name);
+        let result: Result<(), String> = result
+            .and_then(|tmp_path| {
+                fs::rename(&tmp_path, &base_path).map_err(|e| {
+                    let _ = fs::remove_file(&tmp_path);
+                    format!("Failed renaming {}: {}", tmp_path.display(), e)
+                })
+            })
+            .and_then(|_| {
+                self.aof
+                    .manifest
+                    .set_base(rewrite.base, rewrite.first_kept_incr);
+                self.persist_aof_manifest()
+            });
+        match result {
+            Ok(()) => {
+                println!("Background AOF rewrite finished successfully");
+                self.aof.lastbgrewrite_ok = true;
+            }
+            Err(e) => {
+                eprintln!("Background AOF rewrite error: {}", e);
+                self.aof.lastbgrewrite_ok = false;
+            }
+        }
+    }
+
+    /*
+    The replication offset up to which the write stream is known to be on
+    disk in the AOF, or None when appendonly is off. Under appendfsync always
+    every write is fsynced as it happens, so that is the current offset, as
+    it is under everysec when no fsync is pending. Under appendfsync no the
+    operating system decides, so we never know.
+    */
+    pub(super) fn aof_fsynced_reploff(&self) -> Option<u64> {
+        self.aof.file.as_ref()?;
+        let synced: bool = match self.config.appendfsync {
+            AppendFsync::Always => true,
+            AppendFsync::Everysec => {
+                !self.aof.fsync_pending && !self.aof.fsync_in_progress.load(Ordering::SeqCst)
+            }
+            AppendFsync::No => false,
+        };
+        if synced {
+            return self.replication_offset.or(Some(0));
+        }
+        Some(self.aof.fsynced_reploff.load(Ordering::SeqCst))
+    }
+
+    pub(super) fn info_aof(&self) -> Vec<String> {
+        let status = |ok: bool| if ok { "ok" } else { "err" };
+        vec![
+            format!("aof_enabled:{}", self.aof.file.is_some() as u8),
+            format!(
+                "aof_rewrite_in_progress:{}",
+                self.aof.rewrite.is_some() as u8
+            ),
+            format!(
+                "aof_last_bgrewrite_status:{}",
+                status(self.aof.lastbgrewrite_ok)
+            ),
+            format!("aof_last_write_status:{}", status(self.aof.last_write_ok)),
+        ]
+    }
+
+    /*
+    BGREWRITEAOF
+    */
+    pub(super) fn handle_bgrewriteaof(&mut self, arr: Vec<RespType>) -> RespType {
+        if arr.len() != 1 {
+            return wrong_arity("bgrewriteaof");
+        }
+        match self.rewrite_append_only_file_background() {
+            Ok(()) => {
+                RespType::SimpleString("Background append only file rewriting started".to_string())
+            }
+            Err(e) => RespType::Error(format!("ERR {}", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::{Clock, ManualClock},
+        config::Config,
+        rdb::RdbWriter,
+        test_dir::TempDir,
+    };
+    use std::time::Duration;
+
+    const START_MS: u64 = 1_700_000_000_000;
+
+    fn config(dir: &TempDir) -> Config {
+        Config {
+            dir: dir.path().display().to_string(),
+            appendonly: true,
+            ..Config::default()
+        }
+    }
+
+    fn command(args: &[&str]) -> Vec<u8> {
+        let args: Vec<RespType> = args
+            .iter()
+            .map(|arg| RespType::BulkString(arg.to_string()))
+            .collect();
+        RespType::Array(args).to_resp_bytes()
+    }
+
+    fn run(state: &mut ServerState, args: &[&str]) -> RespType {
+        run_as(state, &mut Client::new(), args)
+    }
+
+    fn run_as(state: &mut ServerState, client: &mut Client, args: &[&str]) -> RespType {
+        let cmd: Vec<RespType> = args
+            .iter()
+            .map(|arg| RespType::BulkString(arg.to_string()))
+            .collect();
+        state.execute(client, RespType::Array(cmd))
+    }
+
+    fn load(config: Config, clock: &Arc<ManualClock>) -> ServerState {
+        let mut state = ServerState::with_clock(config, clock.clone() as Arc<dyn Clock>);
+        state.load_data().unwrap();
+        state
+    }
+
+    fn client_on(state: &mut ServerState, db: usize) -> Client {
+        let mut client = Client::new();
+        run_as(state, &mut client, &["SELECT", &db.to_string()]);
+        client
+    }
+
+    fn get(state: &mut ServerState, key: &str) -> RespType {
+        run(state, &["GET", key])
+    }
+
+    /*
+    Writes `files` (name, commands) into the AOF directory, next to a manifest
+    with the given text.
+    */
+    fn write_aof_dir(dir: &TempDir, manifest: &str, files: &[(&str, &[&[&str]])]) -> PathBuf {
+        let aof_dir: PathBuf = dir.path().join("appendonlydir");
+        fs::create_dir_all(&aof_dir).unwrap();
+        fs::write(aof_dir.join("appendonly.aof.manifest"), manifest).unwrap();
+        for (name, commands) in files {
+            let bytes: Vec<u8> = commands.iter().flat_map(|args| command(args)).collect();
+            fs::write(aof_dir.join(name), bytes).unwrap();
+        }
+        aof_dir
+    }
+
+    fn read_manifest(aof_dir: &Path) -> String {
+        fs::read_to_string(aof_dir.join("appendonly.aof.manifest")).unwrap()
+    }
+
+    #[test]
+    fn a_partial_last_manifest_line_is_dropped() {
+        let dir = TempDir::new();
+        let aof_dir: PathBuf = write_aof_dir(
+            &dir,
+            "file appendonly.aof.1.base.aof seq 1 type b\n\
+             file appendonly.aof.1.incr.aof seq 1 type i\n\
+             file appendonly.aof.2.in",
+            &[
+                ("appendonly.aof.1.base.aof", &[&["SET", "a", "1"]]),
+                ("appendonly.aof.1.incr.aof", &[&["SET", "b", "2"]]),
+            ],
+        );
+
+        let mut state = ServerState::new(config(&dir));
+        state.load_data().unwrap();
+        assert_eq!(
+            get(&mut state, "a"),
+            RespType::SimpleString("1".to_string())
+        );
+        assert_eq!(
+            get(&mut state, "b"),
+            RespType::SimpleString("2".to_string())
+        );
+        assert_eq!(
+            read_manifest(&aof_dir),
+            "file appendonly.aof.1.base.aof seq 1 type b\n\
+             file appendonly.aof.1.incr.aof seq 1 type i\n"
+        );
+    }
+
+    #[test]
+    fn incremental_files_missing_from_the_manifest_are_added_back() {
+        let dir = TempDir::new();
+        let aof_dir: PathBuf = write_aof_dir(
+            &dir,
+            "file appendonly.aof.1.base.aof seq 1 type b\n\
+             file appendonly.aof.1.incr.aof seq 1 type i\n",
+            &[
+                ("appendonly.aof.1.base.aof", &[&["SET", "a", "1"]]),
+                ("appendonly.aof.1.incr.aof", &[&["SET", "a", "2"]]),
+                ("appendonly.aof.2.incr.aof", &[&["SET", "a", "3"]]),
+                ("appendonly.aof.3.incr.aof", &[&["SET", "a", "4"]]),
+            ],
+        );
+
+        let mut state = ServerState::new(config(&dir));
+        state.load_data().unwrap();
+        assert_eq!(
+            get(&mut state, "a"),
+            RespType::SimpleString("4".to_string())
+        );
+        assert_eq!(
+            read_manifest(&aof_dir),
+            "file appendonly.aof.1.base.aof seq 1 type b\n\
+             file appendonly.aof.1.incr.aof seq 1 type i\n\
+             file appendonly.aof.2.incr.aof seq 2 type i\n\
+             file appendonly.aof.3.incr.aof seq 3 type i\n"
+        );
+        // new writes go to the last one
+        run(&mut state, &["SET", "a", "5"]);
+        let last: Vec<u8> = fs::read(aof_dir.join("appendonly.aof.3.incr.aof")).unwrap();
+        assert!(last.ends_with(&command(&["SET", "a", "5"])));
+    }
+
+    #[test]
+    fn only_our_unlisted_files_are_removed_as_stale() {
+        let dir = TempDir::new();
+        write_aof_dir(
+            &dir,
+            "file appendonly.aof.2.base.aof seq 2 type b\n\
+             file appendonly.aof.1.base.aof seq 1 type h\n\
+             file appendonly.aof.3.incr.aof seq 3 type i\n",
+            &[
+                ("appendonly.aof.1.base.aof", &[]),
+                ("appendonly.aof.2.base.aof", &[&["SET", "a", "1"]]),
+                ("appendonly.aof.2.incr.aof", &[]),
+                ("appendonly.aof.3.incr.aof", &[]),
+                ("temp-rewriteaof-bg-42.aof", &[]),
+                ("other.aof.1.incr.aof", &[]),
+                ("notes.txt", &[]),
+            ],
+        );
+
+        let mut state = ServerState::new(config(&dir));
+        state.load_data().unwrap();
+        let mut names: Vec<String> = state.aof_dir_entries();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                "appendonly.aof.2.base.aof",
+                "appendonly.aof.3.incr.aof",
+                "appendonly.aof.manifest",
+                "notes.txt",
+                "other.aof.1.incr.aof",
+            ]
+        );
+        assert_eq!(
+            get(&mut state, "a"),
+            RespType::SimpleString("1".to_string())
+        );
+    }
+
+    #[test]
+    fn an_old_aof_keeps_the_commands_after_its_rdb_preamble() {
+        let dir = TempDir::new();
+        let mut writer = RdbWriter::new();
+        writer.select_db(0, 1, 0);
+        writer.string_entry("a", "1", None);
+        let mut bytes: Vec<u8> = writer.finish();
+        bytes.extend(command(&["SELECT", "0"]));
+        bytes.extend(command(&["SET", "b", "2"]));
+        let complete: usize = bytes.len();
+        let last: Vec<u8> = command(&["SET", "c", "3"]);
+        bytes.extend(&last[..last.len() - 3]);
+        fs::write(dir.path().join("appendonly.aof"), &bytes).unwrap();
+
+        let mut state = ServerState::new(config(&dir));
+        state.load_data().unwrap();
+        assert_eq!(
+            get(&mut state, "a"),
+            RespType::SimpleString("1".to_string())
+        );
+        assert_eq!(
+            get(&mut state, "b"),
+            RespType::SimpleString("2".to_string())
+        );
+        assert_eq!(get(&mut state, "c"), RespType::NullBulkString);
+        let base: PathBuf = dir.path().join("appendonlydir").join("appendonly.aof");
+        assert_eq!(fs::metadata(base).unwrap().len(), complete as u64);
+    }
+
+    #[test]
+    fn writes_are_replayed_from_the_incremental_file() {
+        let dir = TempDir::new();
+        let clock = Arc::new(ManualClock::new(START_MS));
+        let mut state = load(config(&dir), &clock);
+        run(&mut state, &["SET", "a", "1"]);
+        run(&mut state, &["SET", "b", "2", "PX", "5000"]);
+        let mut db2: Client = client_on(&mut state, 2);
+        run_as(&mut state, &mut db2, &["SET", "c", "3"]);
+        run(&mut state, &["DEL", "a"]);
+        drop(state);
+
+        clock.advance(1000);
+        let mut state = load(config(&dir), &clock);
+        assert_eq!(get(&mut state, "a"), RespType::NullBulkString);
+        assert_eq!(
+            get(&mut state, "b"),
+            RespType::SimpleString("2".to_string())
+        );
+        assert_eq!(run(&mut state, &["PTTL", "b"]), RespType::Integer(4000));
+        assert_eq!(get(&mut state, "c"), RespType::NullBulkString);
+        let mut db2: Client = client_on(&mut state, 2);
+        assert_eq!(
+            run_as(&mut state, &mut db2, &["GET", "c"]),
+            RespType::SimpleString("3".to_string())
+        );
+    }
+
+    /*
+    A base and one incr, with the last command of the incr cut short.
+    Returns the path of the incr and its length up to the last full command.
+    */
+    fn write_truncated_incr(dir: &TempDir) -> (PathBuf, u64) {
+        let aof_dir: PathBuf = write_aof_dir(
+            dir,
+            "file appendonly.aof.1.base.aof seq 1 type b\n\
+             file appendonly.aof.1.incr.aof seq 1 type i\n",
+            &[
+                ("appendonly.aof.1.base.aof", &[&["SET", "a", "1"]]),
+                ("appendonly.aof.1.incr.aof", &[&["SET", "b", "2"]]),
+            ],
+        );
+        let incr: PathBuf = aof_dir.join("appendonly.aof.1.incr.aof");
+        let complete: u64 = fs::metadata(&incr).unwrap().len();
+        let last: Vec<u8> = command(&["SET", "c", "3"]);
+        let mut file = OpenOptions::new().append(true).open(&incr).unwrap();
+        file.write_all(&last[..last.len() - 3]).unwrap();
+        (incr, complete)
+    }
+
+    #[test]
+    fn a_truncated_last_command_is_dropped_with_aof_load_truncated() {
+        let dir = TempDir::new();
+        let (incr, complete): (PathBuf, u64) = write_truncated_incr(&dir);
+
+        let mut state = ServerState::new(Config {
+            aof_load_truncated: true,
+            ..config(&dir)
+        });
+        state.load_data().unwrap();
+        assert_eq!(
+            get(&mut state, "a"),
+            RespType::SimpleString("1".to_string())
+        );
+        assert_eq!(
+            get(&mut state, "b"),
+            RespType::SimpleString("2".to_string())
+        );
+        assert_eq!(get(&mut state, "c"), RespType::NullBulkString);
+        assert_eq!(fs::metadata(&incr).unwrap().len(), complete);
+    }
+
+    #[test]
+    fn a_truncated_last_command_fails_the_load_without_aof_load_truncated() {
+        let dir = TempDir::new();
+        let (incr, complete): (PathBuf, u64) = write_truncated_incr(&dir);
+        let size: u64 = fs::metadata(&incr).unwrap().len();
+
+        let mut state = ServerState::new(Config {
+            aof_load_truncated: false,
+            ..config(&dir)
+        });
+        let err: String = state.load_data().unwrap_err();
+        assert!(err.contains("Unexpected end of file"), "{}", err);
+        assert!(err.contains(&format!("at offset {}", complete)), "{}", err);
+        assert_eq!(fs::metadata(&incr).unwrap().len(), size);
+    }
+
+    #[test]
+    fn a_truncated_file_before_the_last_one_always_fails_the_load() {
+        let dir = TempDir::new();
+        let aof_dir: PathBuf = write_aof_dir(
+            &dir,
+            "file appendonly.aof.1.base.aof seq 1 type b\n\
+             file appendonly.aof.1.incr.aof seq 1 type i\n\
+             file appendonly.aof.2.incr.aof seq 2 type i\n",
+            &[
+                ("appendonly.aof.1.base.aof", &[&["SET", "a", "1"]]),
+                ("appendonly.aof.2.incr.aof", &[&["SET", "c", "3"]]),
+            ],
+        );
+        let last: Vec<u8> = command(&["SET", "b", "2"]);
+        fs::write(
+            aof_dir.join("appendonly.aof.1.incr.aof"),
+            &last[..last.len() - 3],
+        )
+        .unwrap();
+
+        let mut state = ServerState::new(Config {
+            aof_load_truncated: true,
+            ..config(&dir)
+        });
+        let err: String = state.load_data().unwrap_err();
+        assert!(err.contains("Unexpected end of file"), "{}", err);
+    }
+
+    fn wait_for_rewrite(state: &mut ServerState) {
+        for _ in 0..500 {
+            state.aof_cron();
+            if state.aof.rewrite.is_none() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("BGREWRITEAOF did not finish");
+    }
+
+    /*
+    Rewrites a dataset spread over two databases, with TTLs and an already
+    expired key, writes once more after the rewrite started, and checks that
+    a restart at a later time sees the same keys and the same deadlines.
+    */
+    fn check_rewrite_reloads(rdb: bool) {
+        let dir = TempDir::new();
+        let config = Config {
+            aof_use_rdb_preamble: rdb,
+            ..config(&dir)
+        };
+        let clock = Arc::new(ManualClock::new(START_MS));
+        let mut state = load(config.clone(), &clock);
+        run(&mut state, &["SET", "plain", "1"]);
+        run(&mut state, &["SET", "ttl", "2", "PX", "5000"]);
+        run(&mut state, &["SET", "gone", "3", "PX", "100"]);
+        let mut db3: Client = client_on(&mut state, 3);
+        run_as(&mut state, &mut db3, &["SET", "other", "4", "PX", "60000"]);
+        clock.advance(200);
+
+        state.rewrite_append_only_file_background().unwrap();
+        run(&mut state, &["SET", "after", "5"]);
+        wait_for_rewrite(&mut state);
+
+        let aof_dir: PathBuf = dir.path().join("appendonlydir");
+        let manifest: String = read_manifest(&aof_dir);
+        let base: &str = if rdb {
+            "appendonly.aof.2.base.rdb"
+        } else {
+            "appendonly.aof.2.base.aof"
+        };
+        assert!(
+            manifest.contains(&format!("file {} seq 2 type b", base)),
+            "{}",
+            manifest
+        );
+        let mut listed: Vec<String> = manifest
+            .lines()
+            .map(|line| line.split(' ').nth(1).unwrap().to_string())
+            .collect();
+        listed.push("appendonly.aof.manifest".to_string());
+        listed.sort();
+        let mut on_disk: Vec<String> = fs::read_dir(&aof_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        on_disk.sort();
+        assert_eq!(on_disk, listed);
+        if !rdb {
+            let text: String = fs::read_to_string(aof_dir.join(base)).unwrap();
+            let deadline: String = (START_MS + 5000).to_string();
+            assert!(text.contains("PXAT"), "{}", text);
+            assert!(text.contains(&deadline), "{}", text);
+            assert!(!text.contains("gone"), "{}", text);
+        }
+        drop(state);
+
+        clock.advance(1000);
+        let mut state = load(config, &clock);
+        assert_eq!(
+            get(&mut state, "plain"),
+            RespType::SimpleString("1".to_string())
+        );
+        assert_eq!(run(&mut state, &["PTTL", "plain"]), RespType::Integer(-1));
+        assert_eq!(run(&mut state, &["PTTL", "ttl"]), RespType::Integer(3800));
+        assert_eq!(get(&mut state, "gone"), RespType::NullBulkString);
+        assert_eq!(
+            get(&mut state, "after"),
+            RespType::SimpleString("5".to_string())
+        );
+        let mut db3: Client = client_on(&mut state, 3);
+        assert_eq!(
+            run_as(&mut state, &mut db3, &["PTTL", "other"]),
+            RespType::Integer(58800)
+        );
+    }
+
+    #[test]
+    fn a_rewritten_rdb_base_reloads_to_the_same_dataset() {
+        check_rewrite_reloads(true);
+    }
+
+    #[test]
+    fn a_rewritten_command_base_reloads_to_the_same_dataset() {
+        check_rewrite_reloads(false);
+    }
+}
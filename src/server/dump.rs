@@ -0,0 +1,389 @@
+use super::{arg_i64, arg_string, wrong_arity, ServerState};
+use crate::{
+    parser::RespType,
+    rdb::{dump_payload, parse_dump_payload},
+};
+
+/*
+DUMP and RESTORE: single values in the serialization format of Redis
+(see `rdb::dump_payload`), so keys can be copied to and from real Redis.
+*/
+
+/*
+Returns the argument at `idx` as raw bytes, whether or not it is valid UTF-8.
+*/
+fn arg_bytes(arr: &[RespType], idx: usize) -> Result<Vec<u8>, RespType> {
+    match arr.get(idx) {
+        Some(RespType::BulkBytes(bytes)) => Ok(bytes.clone()),
+        _ => arg_string(arr, idx).map(String::into_bytes),
+    }
+}
+
+impl ServerState {
+    /*
+    DUMP key
+    */
+    pub(super) fn handle_dump(&mut self, arr: Vec<RespType>) -> RespType {
+        if arr.len() != 2 {
+            return wrong_arity("dump");
+        }
+        let key: String = match arg_string(&arr, 1) {
+            Ok(s) => s,
+            Err(e) => return e,
+        };
+        if self.expire_if_needed(&key) {
+            return RespType::NullBulkString;
+        }
+        match self.db().dict.get(&key) {
+            Some(value) => RespType::BulkBytes(dump_payload(value)),
+            None => RespType::NullBulkString,
+        }
+    }
+
+    /*
+    RESTORE key ttl serialized-value [REPLACE] [ABSTTL] [IDLETIME seconds] [FREQ frequency]
+    `ttl` is in milliseconds, relative unless ABSTTL is given, and 0 means no
+    expire. We don't track LRU/LFU, so IDLETIME and FREQ are only validated.
+    The write is propagated as a SET (with PXAT), which replicas and the AOF
+    can apply without the binary payload.
+    */
+    pub(super) fn handle_restore(&mut self, arr: Vec<RespType>) -> RespType {
+        if arr.len() < 4 {
+            return wrong_arity("restore");
+        }
+        let key: String = match arg_string(&arr, 1) {
+            Ok(s) => s,
+            Err(e) => return e,
+        };
+        let ttl: i64 = match arg_i64(&arr, 2) {
+            Ok(ttl) => ttl,
+            Err(e) => return e,
+        };
+        let payload: Vec<u8> = match arg_bytes(&arr, 3) {
+            Ok(bytes) => bytes,
+            Err(e) => return e,
+        };
+
+        let mut replace: bool = false;
+        let mut absttl: bool = false;
+        let mut idletime: Option<i64> = None;
+        let mut freq: Option<i64> = None;
+        let mut idx: usize = 4;
+        while idx < arr.len() {
+            let option: String = match arg_string(&arr, idx) {
+                Ok(s) => s.to_lowercase(),
+                Err(e) => return e,
+            };
+            match option.as_str() {
+                "replace" => replace = true,
+                "absttl" => absttl = true,
+                "idletime" if freq.is_none() && idx + 1 < arr.len() => {
+                    idx += 1;
+                    match arg_i64(&arr, idx) {
+                        Ok(idle) if idle >= 0 => idletime = Some(idle),
+                        Ok(_) => {
+                            return RespType::Error(
+                                "ERR Invalid IDLETIME value, must be >= 0".to_string(),
+                            )
+                        }
+                        Err(e) => return e,
+                    }
+                }
+                "freq" if idletime.is_none() && idx + 1 < arr.len() => {
+                    idx += 1;
+                    match arg_i64(&arr, idx) {
+                        Ok(f) if (0..=255).contains(&f) => freq = Some(f),
+                        Ok(_) => {
+                            return RespType::Error(
+                                "ERR Invalid FREQ value, must be >= 0 and <= 255".to_string(),
+                            )
+                        }
+                        Err(e) => return e,
+                    }
+                }
+                _ => return RespType::Error("ERR syntax error".to_string()),
+            }
+            idx += 1;
+        }
+        if ttl < 0 {
+            return RespType::Error("ERR Invalid TTL value, must be >= 0".to_string());
+        }
+
+        let exists: bool = !self.expire_if_needed(&key) && self.db().dict.contains_key(&key);
+        if exists && !replace {
+            return RespType::Error("BUSYKEY Target key name already exists.".to_string());
+        }
+        let value: String = match parse_dump_payload(&payload) {
+            Ok(value) => value,
+            Err(e) => return RespType::Error(format!("ERR {}", e)),
+        };
+
+        let now: u64 = self.clock.now_ms();
+        let expire: Option<u64> = match ttl as u64 {
+            0 => None,
+            ttl if absttl => Some(ttl),
+            ttl => Some(now.saturating_add(ttl)),
+        };
+        if expire.is_some_and(|when| when <= now) {
+            // restoring an already expired key only deletes what it replaces
+            if exists {
+                self.db_mut().remove(&key);
+                self.persistence.dirty += 1;
+                self.rewrite_command(vec![
+                    RespType::BulkString("DEL".to_string()),
+                    RespType::BulkString(key),
+                ]);
+            }
+            return RespType::SimpleString("OK".to_string());
+        }
+
+        self.db_mut().dict.insert(key.clone(), value.clone());
+        let mut set: Vec<RespType> = vec![
+            RespType::BulkString("SET".to_string()),
+            RespType::BulkString(key.clone()),
+            RespType::BulkString(value),
+        ];
+        match expire {
+            Some(when) => {
+                self.db_mut().expires.insert(key, when);
+                set.push(RespType::BulkString("PXAT".to_string()));
+                set.push(RespType::BulkString(when.to_string()));
+            }
+            None => {
+                self.db_mut().expires.remove(&key);
+            }
+        }
+        self.persistence.dirty += 1;
+        self.rewrite_command(set);
+        RespType::SimpleString("OK".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::Client,
+        clock::{Clock, ManualClock},
+        config::Config,
+    };
+    use std::sync::Arc;
+
+    const START_MS: u64 = 1_700_000_000_000;
+
+    /*
+    What Redis 6 answers to DUMP for the value "10": type 0, the string as
+    INT8, RDB version 9 and the CRC64 of all that.
+    */
+    const REDIS_DUMP_OF_10: &[u8] = b"\x00\xc0\n\t\x00\xbem\x06\x89Z(\x00\n";
+
+    fn server() -> (ServerState, Arc<ManualClock>) {
+        let clock: Arc<ManualClock> = Arc::new(ManualClock::new(START_MS));
+        let state = ServerState::with_clock(Config::default(), clock.clone() as Arc<dyn Clock>);
+        (state, clock)
+    }
+
+    fn bulk(s: &str) -> RespType {
+        RespType::BulkString(s.to_string())
+    }
+
+    fn run(state: &mut ServerState, args: Vec<RespType>) -> RespType {
+        state.execute(&mut Client::new(), RespType::Array(args))
+    }
+
+    fn restore(
+        state: &mut ServerState,
+        key: &str,
+        ttl: u64,
+        payload: &[u8],
+        opts: &[&str],
+    ) -> RespType {
+        let mut args: Vec<RespType> = vec![
+            bulk("RESTORE"),
+            bulk(key),
+            bulk(&ttl.to_string()),
+            RespType::BulkBytes(payload.to_vec()),
+        ];
+        args.extend(opts.iter().map(|opt| bulk(opt)));
+        run(state, args)
+    }
+
+    fn dump(state: &mut ServerState, key: &str) -> Vec<u8> {
+        match run(state, vec![bulk("DUMP"), bulk(key)]) {
+            RespType::BulkBytes(payload) => payload,
+            reply => panic!("unexpected DUMP reply {:?}", reply),
+        }
+    }
+
+    fn get(state: &mut ServerState, key: &str) -> RespType {
+        run(state, vec![bulk("GET"), bulk(key)])
+    }
+
+    fn pttl(state: &mut ServerState, key: &str) -> RespType {
+        run(state, vec![bulk("PTTL"), bulk(key)])
+    }
+
+    fn ok() -> RespType {
+        RespType::SimpleString("OK".to_string())
+    }
+
+    #[test]
+    fn dump_and_restore_copy_a_value() {
+        let (mut state, _) = server();
+        run(&mut state, vec![bulk("SET"), bulk("k"), bulk("héllo ✓")]);
+        let payload: Vec<u8> = dump(&mut state, "k");
+        assert_eq!(restore(&mut state, "copy", 0, &payload, &[]), ok());
+        assert_eq!(
+            get(&mut state, "copy"),
+            RespType::SimpleString("héllo ✓".to_string())
+        );
+        assert_eq!(pttl(&mut state, "copy"), RespType::Integer(-1));
+        assert_eq!(
+            run(&mut state, vec![bulk("DUMP"), bulk("missing")]),
+            RespType::NullBulkString
+        );
+    }
+
+    #[test]
+    fn payloads_are_compatible_with_redis() {
+        let (mut state, _) = server();
+        assert_eq!(restore(&mut state, "k", 0, REDIS_DUMP_OF_10, &[]), ok());
+        assert_eq!(
+            get(&mut state, "k"),
+            RespType::SimpleString("10".to_string())
+        );
+        // the same body, only our RDB version and so the checksum differ
+        let payload: Vec<u8> = dump(&mut state, "k");
+        assert_eq!(payload[..3], REDIS_DUMP_OF_10[..3]);
+        assert_eq!(payload.len(), REDIS_DUMP_OF_10.len());
+    }
+
+    #[test]
+    fn a_damaged_payload_is_refused() {
+        let (mut state, _) = server();
+        let refused = RespType::Error("ERR DUMP payload version or checksum are wrong".to_string());
+
+        let mut bad_checksum: Vec<u8> = REDIS_DUMP_OF_10.to_vec();
+        bad_checksum[2] = b'9';
+        assert_eq!(restore(&mut state, "k", 0, &bad_checksum, &[]), refused);
+
+        // a version from the future, with a checksum that matches it
+        let mut newer: Vec<u8> = REDIS_DUMP_OF_10[..3].to_vec();
+        newer.extend_from_slice(&99u16.to_le_bytes());
+        let checksum: u64 = crate::crc64::crc64(0, &newer);
+        newer.extend_from_slice(&checksum.to_le_bytes());
+        assert_eq!(restore(&mut state, "k", 0, &newer, &[]), refused);
+
+        assert_eq!(restore(&mut state, "k", 0, b"short", &[]), refused);
+        assert_eq!(get(&mut state, "k"), RespType::NullBulkString);
+    }
+
+    #[test]
+    fn an_existing_key_is_only_replaced_when_asked() {
+        let (mut state, _) = server();
+        run(
+            &mut state,
+            vec![bulk("SET"), bulk("k"), bulk("old"), bulk("EX"), bulk("100")],
+        );
+        assert_eq!(
+            restore(&mut state, "k", 0, REDIS_DUMP_OF_10, &[]),
+            RespType::Error("BUSYKEY Target key name already exists.".to_string())
+        );
+        assert_eq!(
+            get(&mut state, "k"),
+            RespType::SimpleString("old".to_string())
+        );
+
+        assert_eq!(
+            restore(&mut state, "k", 0, REDIS_DUMP_OF_10, &["REPLACE"]),
+            ok()
+        );
+        assert_eq!(
+            get(&mut state, "k"),
+            RespType::SimpleString("10".to_string())
+        );
+        // the replaced key's TTL goes with it
+        assert_eq!(pttl(&mut state, "k"), RespType::Integer(-1));
+    }
+
+    #[test]
+    fn ttls_are_relative_unless_absttl_is_given() {
+        let (mut state, clock) = server();
+        assert_eq!(
+            restore(&mut state, "rel", 5000, REDIS_DUMP_OF_10, &[]),
+            ok()
+        );
+        assert_eq!(pttl(&mut state, "rel"), RespType::Integer(5000));
+
+        let deadline: u64 = START_MS + 3000;
+        assert_eq!(
+            restore(&mut state, "abs", deadline, REDIS_DUMP_OF_10, &["absttl"]),
+            ok()
+        );
+        assert_eq!(pttl(&mut state, "abs"), RespType::Integer(3000));
+
+        clock.advance(3001);
+        assert_eq!(get(&mut state, "abs"), RespType::NullBulkString);
+        assert_eq!(pttl(&mut state, "rel"), RespType::Integer(1999));
+    }
+
+    #[test]
+    fn a_ttl_in_the_past_only_deletes() {
+        let (mut state, _) = server();
+        assert_eq!(
+            restore(&mut state, "k", START_MS - 1, REDIS_DUMP_OF_10, &["ABSTTL"]),
+            ok()
+        );
+        assert_eq!(get(&mut state, "k"), RespType::NullBulkString);
+
+        run(&mut state, vec![bulk("SET"), bulk("k"), bulk("old")]);
+        assert_eq!(
+            restore(
+                &mut state,
+                "k",
+                START_MS,
+                REDIS_DUMP_OF_10,
+                &["ABSTTL", "REPLACE"]
+            ),
+            ok()
+        );
+        assert_eq!(get(&mut state, "k"), RespType::NullBulkString);
+    }
+
+    #[test]
+    fn idletime_and_freq_are_validated() {
+        let (mut state, _) = server();
+        assert_eq!(
+            restore(&mut state, "a", 0, REDIS_DUMP_OF_10, &["IDLETIME", "100"]),
+            ok()
+        );
+        assert_eq!(
+            restore(&mut state, "b", 0, REDIS_DUMP_OF_10, &["FREQ", "255"]),
+            ok()
+        );
+        assert_eq!(
+            restore(&mut state, "c", 0, REDIS_DUMP_OF_10, &["IDLETIME", "-1"]),
+            RespType::Error("ERR Invalid IDLETIME value, must be >= 0".to_string())
+        );
+        assert_eq!(
+            restore(&mut state, "c", 0, REDIS_DUMP_OF_10, &["FREQ", "256"]),
+            RespType::Error("ERR Invalid FREQ value, must be >= 0 and <= 255".to_string())
+        );
+        // they are two ways to set the same thing, only one is allowed
+        assert_eq!(
+            restore(
+                &mut state,
+                "c",
+                0,
+                REDIS_DUMP_OF_10,
+                &["IDLETIME", "1", "FREQ", "1"]
+            ),
+            RespType::Error("ERR syntax error".to_string())
+        );
+        assert_eq!(
+            restore(&mut state, "c", 0, REDIS_DUMP_OF_10, &["IDLETIME"]),
+            RespType::Error("ERR syntax error".to_string())
+        );
+        assert_eq!(get(&mut state, "c"), RespType::NullBulkString);
+    }
+}
@@ -0,0 +1,511 @@
+use super::{arg_i64, arg_string, wrong_arity, ServerState, CRON_PERIOD_MS};
+use crate::parser::RespType;
+use std::time::Instant;
+
+/*
+TTL command family: EXPIRE, PEXPIRE, EXPIREAT, PEXPIREAT, TTL, PTTL,
+EXPIRETIME, PEXPIRETIME and PERSIST.
+
+Every setter is propagated to the slaves as an absolute PEXPIREAT so that a
+slave applying it late does not push the deadline further out (or as a DEL
+when the deadline already passed).
+
+Expired keys are reclaimed in two ways, like Redis does:
+- lazily, by `expire_if_needed` whenever a command touches the key;
+- actively, by `active_expire_cycle` which runs from the server cron and
+  samples the expiry table so that keys nobody reads are freed as well.
+
+Either way the deletion is propagated as a DEL, so that the AOF and the
+replicas drop the key at the same point of the write stream. Replicas never
+expire keys on their own: an expired key is only hidden from their clients
+until the DEL of their master arrives, and the master's stream (like the AOF
+being replayed) still sees it.
+*/
+
+// keys sampled per round of the active expire cycle
+const ACTIVE_EXPIRE_CYCLE_KEYS_PER_LOOP: usize = 20;
+
+// keep sampling while more than this percentage of the sample was expired
+const ACTIVE_EXPIRE_CYCLE_ACCEPTABLE_STALE: usize = 25;
+
+// share of each cron period the active expire cycle may use, in percent
+const ACTIVE_EXPIRE_CYCLE_SLOW_TIME_PERC: u64 = 25;
+
+#[derive(Default)]
+struct ExpireFlags {
+    nx: bool,
+    xx: bool,
+    gt: bool,
+    lt: bool,
+}
+
+impl ServerState {
+    pub(super) fn is_expired(&self, key: &str) -> bool {
+        if self.client.master || self.loading {
+            return false;
+        }
+        match self.db().expires.get(key) {
+            Some(when) => self.clock.now_ms() > *when,
+            None => false,
+        }
+    }
+
+    /*
+    Lazy expiry: deletes `key` if its time to live has elapsed. Returns true if
+    the key expired, in which case the caller must treat it as missing: a
+    replica keeps it until its master deletes it.
+    */
+    pub(super) fn expire_if_needed(&mut self, key: &str) -> bool {
+        if !self.is_expired(key) {
+            return false;
+        }
+        if self.replica_of.is_some() {
+            return true;
+        }
+        self.db_mut().remove(key);
+        self.stat_expired_keys += 1;
+        self.propagate_expired(self.client.db, key);
+        true
+    }
+
+    /*
+    Propagates the expiry of `key` in database `db` as a DEL.
+    */
+    fn propagate_expired(&mut self, db: usize, key: &str) {
+        let selected: usize = std::mem::replace(&mut self.client.db, db);
+        self.propagate(vec![
+            RespType::BulkString("DEL".to_string()),
+            RespType::BulkString(key.to_string()),
+        ]);
+        self.client.db = selected;
+    }
+
+    /*
+    Active expiry, called from the server cron. Each round walks the expiry
+    table of each database from where it left off until
+    ACTIVE_EXPIRE_CYCLE_KEYS_PER_LOOP keys have been sampled, deleting the
+    expired ones. Another round follows as long as more than
+    ACTIVE_EXPIRE_CYCLE_ACCEPTABLE_STALE percent of the sample was expired,
+    unless the time budget for this cron tick is used up.
+    */
+    pub(super) fn active_expire_cycle(&mut self) {
+        // a replica waits for the DELs of its master
+        if self.replica_of.is_some() {
+            return;
+        }
+        let start = Instant::now();
+        let time_limit_us: u128 =
+            (CRON_PERIOD_MS * ACTIVE_EXPIRE_CYCLE_SLOW_TIME_PERC * 10) as u128;
+        let mut total_sampled: usize = 0;
+        let mut total_expired: usize = 0;
+
+        // resume from the database after the one we stopped at last time, so a
+        // database full of expired keys can't starve the others
+        'dbs: for _ in 0..self.dbs.len() {
+            let id: usize = self.active_expire_db;
+            self.active_expire_db = (id + 1) % self.dbs.len();
+
+            loop {
+                if self.dbs[id].expires.is_empty() {
+                    self.dbs[id].expires_cursor = 0;
+                    break;
+                }
+                let now: u64 = self.clock.now_ms();
+                let (sampled, expired_keys) =
+                    self.dbs[id].expire_sample(now, ACTIVE_EXPIRE_CYCLE_KEYS_PER_LOOP);
+                let expired: usize = expired_keys.len();
+                for key in expired_keys {
+                    self.propagate_expired(id, &key);
+                }
+                self.stat_expired_keys += expired as u64;
+                total_sampled += sampled;
+                total_expired += expired;
+
+                if start.elapsed().as_micros() > time_limit_us {
+                    self.stat_expired_time_cap_reached_count += 1;
+                    break 'dbs;
+                }
+                if sampled == 0 || expired * 100 <= sampled * ACTIVE_EXPIRE_CYCLE_ACCEPTABLE_STALE {
+                    break;
+                }
+            }
+        }
+
+        // running average of how stale the keys we look at are
+        let current_perc: f64 = if total_sampled > 0 {
+            total_expired as f64 / total_sampled as f64
+        } else {
+            0.0
+        };
+        self.stat_expired_stale_perc = current_perc * 0.05 + self.stat_expired_stale_perc * 0.95;
+    }
+
+    /*
+    Returns the absolute expire time of `key` in Unix milliseconds, if it has one.
+    */
+    fn expire_time_ms(&self, key: &str) -> Option<i64> {
+        self.db().expires.get(key).map(|when| *when as i64)
+    }
+
+    /*
+    EXPIRE / PEXPIRE / EXPIREAT / PEXPIREAT key time [NX | XX | GT | LT]
+    `unit_ms` is the number of milliseconds in one unit of `time`, and `absolute`
+    tells whether `time` is a Unix timestamp or relative to now.
+    */
+    pub(super) fn handle_expire(
+        &mut self,
+        arr: Vec<RespType>,
+        cmd: &str,
+        unit_ms: i64,
+        absolute: bool,
+    ) -> RespType {
+        if arr.len() < 3 {
+            return wrong_arity(cmd);
+        }
+        let key: String = match arg_string(&arr, 1) {
+            Ok(s) => s,
+            Err(e) => return e,
+        };
+        let when: i64 = match arg_i64(&arr, 2) {
+            Ok(t) => t,
+            Err(e) => return e,
+        };
+
+        let mut flags = ExpireFlags::default();
+        for idx in 3..arr.len() {
+            let option: String = match arg_string(&arr, idx) {
+                Ok(s) => s,
+                Err(e) => return e,
+            };
+            match option.to_lowercase().as_str() {
+                "nx" => flags.nx = true,
+                "xx" => flags.xx = true,
+                "gt" => flags.gt = true,
+                "lt" => flags.lt = true,
+                _ => return RespType::Error(format!("ERR Unsupported option {}", option)),
+            }
+        }
+        if flags.nx && (flags.xx || flags.gt || flags.lt) {
+            return RespType::Error(
+                "ERR NX and XX, GT or LT options at the same time are not compatible".to_string(),
+            );
+        }
+        if flags.gt && flags.lt {
+            return RespType::Error(
+                "ERR GT and LT options at the same time are not compatible".to_string(),
+            );
+        }
+
+        let now_ms: i64 = self.clock.now_ms() as i64;
+        let when_ms: Option<i64> = match when.checked_mul(unit_ms) {
+            Some(ms) if absolute => Some(ms),
+            Some(ms) => ms.checked_add(now_ms),
+            None => None,
+        };
+        let when_ms: i64 = match when_ms {
+            Some(ms) => ms,
+            None => {
+                return RespType::Error(format!("ERR invalid expire time in '{}' command", cmd))
+            }
+        };
+
+        if self.expire_if_needed(&key) || !self.db().dict.contains_key(&key) {
+            return RespType::Integer(0);
+        }
+
+        // a key without a time to live behaves as if it expires infinitely late
+        let current: Option<i64> = self.expire_time_ms(&key);
+        let rejected: bool = (flags.nx && current.is_some())
+            || (flags.xx && current.is_none())
+            || (flags.gt && current.is_none_or(|cur| when_ms <= cur))
+            || (flags.lt && current.is_some_and(|cur| when_ms >= cur));
+        if rejected {
+            return RespType::Integer(0);
+        }
+
+        // the master (or the AOF) decides when the key is gone
+        if when_ms <= now_ms && self.replica_of.is_none() && !self.loading {
+            self.db_mut().remove(&key);
+            self.rewrite_command(vec![
+                RespType::BulkString("DEL".to_string()),
+                RespType::BulkString(key),
+            ]);
+        } else {
+            self.db_mut().expires.insert(key.clone(), when_ms as u64);
+            self.rewrite_command(vec![
+                RespType::BulkString("PEXPIREAT".to_string()),
+                RespType::BulkString(key),
+                RespType::BulkString(when_ms.to_string()),
+            ]);
+        }
+        self.persistence.dirty += 1;
+        RespType::Integer(1)
+    }
+
+    /*
+    TTL / PTTL / EXPIRETIME / PEXPIRETIME key
+    Replies -2 if the key does not exist and -1 if it has no time to live.
+    */
+    pub(super) fn handle_ttl(
+        &mut self,
+        arr: Vec<RespType>,
+        cmd: &str,
+        unit_ms: i64,
+        absolute: bool,
+    ) -> RespType {
+        if arr.len() != 2 {
+            return wrong_arity(cmd);
+        }
+        let key: String = match arg_string(&arr, 1) {
+            Ok(s) => s,
+            Err(e) => return e,
+        };
+        if self.expire_if_needed(&key) || !self.db().dict.contains_key(&key) {
+            return RespType::Integer(-2);
+        }
+        let when_ms: i64 = match self.expire_time_ms(&key) {
+            Some(ms) => ms,
+            None => return RespType::Integer(-1),
+        };
+        if absolute {
+            return RespType::Integer(when_ms / unit_ms);
+        }
+        // like Redis, round the remaining time to the nearest unit
+        let remaining: i64 = (when_ms - self.clock.now_ms() as i64).max(0);
+        RespType::Integer((remaining + unit_ms / 2) / unit_ms)
+    }
+
+    /*
+    PERSIST key
+    */
+    pub(super) fn handle_persist(&mut self, arr: Vec<RespType>) -> RespType {
+        if arr.len() != 2 {
+            return wrong_arity("persist");
+        }
+        let key: String = match arg_string(&arr, 1) {
+            Ok(s) => s,
+            Err(e) => return e,
+        };
+        if self.expire_if_needed(&key) || !self.db().dict.contains_key(&key) {
+            return RespType::Integer(0);
+        }
+        if self.db_mut().expires.remove(&key).is_none() {
+            return RespType::Integer(0);
+        }
+        self.persistence.dirty += 1;
+        RespType::Integer(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::Client,
+        clock::{Clock, ManualClock},
+        config::Config,
+        server::ServerAddr,
+    };
+    use std::sync::Arc;
+
+    // an arbitrary wall-clock time the tests start at
+    const START_MS: u64 = 1_700_000_000_000;
+
+    fn server() -> (ServerState, Arc<ManualClock>) {
+        let clock: Arc<ManualClock> = Arc::new(ManualClock::new(START_MS));
+        let state = ServerState::with_clock(Config::default(), clock.clone() as Arc<dyn Clock>);
+        (state, clock)
+    }
+
+    fn run(state: &mut ServerState, args: &[&str]) -> RespType {
+        run_as(state, &mut Client::new(), args)
+    }
+
+    fn run_as(state: &mut ServerState, client: &mut Client, args: &[&str]) -> RespType {
+        let cmd: Vec<RespType> = args
+            .iter()
+            .map(|arg| RespType::BulkString(arg.to_string()))
+            .collect();
+        state.execute(client, RespType::Array(cmd))
+    }
+
+    #[test]
+    fn ttl_and_pttl_count_down_with_the_clock() {
+        let (mut state, clock) = server();
+        run(&mut state, &["SET", "k", "v"]);
+        assert_eq!(run(&mut state, &["TTL", "k"]), RespType::Integer(-1));
+        assert_eq!(run(&mut state, &["TTL", "missing"]), RespType::Integer(-2));
+
+        assert_eq!(
+            run(&mut state, &["EXPIRE", "k", "10"]),
+            RespType::Integer(1)
+        );
+        assert_eq!(run(&mut state, &["TTL", "k"]), RespType::Integer(10));
+        assert_eq!(run(&mut state, &["PTTL", "k"]), RespType::Integer(10_000));
+        assert_eq!(
+            run(&mut state, &["PEXPIRETIME", "k"]),
+            RespType::Integer((START_MS + 10_000) as i64)
+        );
+
+        clock.advance(2_600);
+        assert_eq!(run(&mut state, &["PTTL", "k"]), RespType::Integer(7_400));
+        // rounded to the nearest second
+        assert_eq!(run(&mut state, &["TTL", "k"]), RespType::Integer(7));
+
+        assert_eq!(run(&mut state, &["PERSIST", "k"]), RespType::Integer(1));
+        assert_eq!(run(&mut state, &["PTTL", "k"]), RespType::Integer(-1));
+    }
+
+    #[test]
+    fn expire_options_compare_with_the_current_time_to_live() {
+        let (mut state, _clock) = server();
+        run(&mut state, &["SET", "k", "v"]);
+
+        // no time to live yet: XX and GT refuse, NX and LT accept
+        assert_eq!(
+            run(&mut state, &["EXPIRE", "k", "100", "XX"]),
+            RespType::Integer(0)
+        );
+        assert_eq!(
+            run(&mut state, &["EXPIRE", "k", "100", "GT"]),
+            RespType::Integer(0)
+        );
+        assert_eq!(
+            run(&mut state, &["EXPIRE", "k", "100", "NX"]),
+            RespType::Integer(1)
+        );
+        assert_eq!(
+            run(&mut state, &["EXPIRE", "k", "200", "NX"]),
+            RespType::Integer(0)
+        );
+
+        assert_eq!(
+            run(&mut state, &["EXPIRE", "k", "50", "GT"]),
+            RespType::Integer(0)
+        );
+        assert_eq!(
+            run(&mut state, &["EXPIRE", "k", "150", "GT"]),
+            RespType::Integer(1)
+        );
+        assert_eq!(
+            run(&mut state, &["EXPIRE", "k", "150", "LT"]),
+            RespType::Integer(0)
+        );
+        assert_eq!(
+            run(&mut state, &["EXPIRE", "k", "120", "LT"]),
+            RespType::Integer(1)
+        );
+        assert_eq!(
+            run(&mut state, &["EXPIRE", "k", "90", "XX"]),
+            RespType::Integer(1)
+        );
+        assert_eq!(run(&mut state, &["TTL", "k"]), RespType::Integer(90));
+
+        run(&mut state, &["PERSIST", "k"]);
+        assert_eq!(
+            run(&mut state, &["EXPIRE", "k", "10", "LT"]),
+            RespType::Integer(1)
+        );
+
+        assert!(matches!(
+            run(&mut state, &["EXPIRE", "k", "10", "NX", "XX"]),
+            RespType::Error(_)
+        ));
+        assert!(matches!(
+            run(&mut state, &["EXPIRE", "k", "10", "GT", "LT"]),
+            RespType::Error(_)
+        ));
+    }
+
+    #[test]
+    fn a_key_is_expired_lazily_once_its_deadline_passed() {
+        let (mut state, clock) = server();
+        run(&mut state, &["SET", "k", "v"]);
+        run(&mut state, &["PEXPIRE", "k", "100"]);
+
+        // the deadline itself is still within the time to live
+        clock.advance(100);
+        assert_eq!(
+            run(&mut state, &["GET", "k"]),
+            RespType::SimpleString("v".to_string())
+        );
+
+        clock.advance(1);
+        assert!(state.dbs[0].dict.contains_key("k"));
+        assert_eq!(run(&mut state, &["GET", "k"]), RespType::NullBulkString);
+        assert!(!state.dbs[0].dict.contains_key("k"));
+        assert!(state.dbs[0].expires.is_empty());
+        assert_eq!(state.stat_expired_keys, 1);
+    }
+
+    #[test]
+    fn expire_in_the_past_deletes_the_key() {
+        let (mut state, _clock) = server();
+        run(&mut state, &["SET", "k", "v"]);
+        let past: String = (START_MS / 1000 - 1).to_string();
+        assert_eq!(
+            run(&mut state, &["EXPIREAT", "k", &past]),
+            RespType::Integer(1)
+        );
+        assert!(!state.dbs[0].dict.contains_key("k"));
+    }
+
+    #[test]
+    fn the_active_cycle_frees_keys_nobody_reads() {
+        let (mut state, clock) = server();
+        for idx in 0..50 {
+            let key: String = format!("k{}", idx);
+            run(&mut state, &["SET", &key, "v", "PX", "1000"]);
+        }
+        run(&mut state, &["SET", "kept", "v"]);
+
+        state.active_expire_cycle();
+        assert_eq!(state.dbs[0].dict.len(), 51);
+
+        clock.advance(1_001);
+        state.active_expire_cycle();
+        assert_eq!(state.dbs[0].dict.len(), 1);
+        assert_eq!(state.stat_expired_keys, 50);
+    }
+
+    #[test]
+    fn a_replica_hides_expired_keys_until_its_master_deletes_them() {
+        let clock: Arc<ManualClock> = Arc::new(ManualClock::new(START_MS));
+        let config = Config {
+            replica_of: Some(ServerAddr::new("127.0.0.1".to_string(), 6379)),
+            ..Config::default()
+        };
+        let mut state = ServerState::with_clock(config, clock.clone() as Arc<dyn Clock>);
+        let mut master = Client::new();
+        master.master = true;
+        let deadline: String = (START_MS + 100).to_string();
+        run_as(
+            &mut state,
+            &mut master,
+            &["SET", "k", "v", "PXAT", &deadline],
+        );
+        run_as(&mut state, &mut master, &["SET", "j", "v"]);
+
+        clock.advance(200);
+        assert_eq!(run(&mut state, &["GET", "k"]), RespType::NullBulkString);
+        assert_eq!(run(&mut state, &["TTL", "k"]), RespType::Integer(-2));
+        state.active_expire_cycle();
+        assert!(state.dbs[0].dict.contains_key("k"));
+        assert_eq!(state.stat_expired_keys, 0);
+
+        // a late PEXPIREAT of the master sets the deadline, the DEL follows
+        let past: String = (START_MS + 50).to_string();
+        assert_eq!(
+            run_as(&mut state, &mut master, &["PEXPIREAT", "j", &past]),
+            RespType::Integer(1)
+        );
+        assert!(state.dbs[0].dict.contains_key("j"));
+        assert_eq!(run(&mut state, &["GET", "j"]), RespType::NullBulkString);
+
+        assert_eq!(
+            run_as(&mut state, &mut master, &["DEL", "k", "j"]),
+            RespType::Integer(2)
+        );
+        assert!(state.dbs[0].is_empty());
+    }
+}
@@ -0,0 +1,491 @@
+use super::{arg_i64, arg_string, wrong_arity, ServerState};
+use crate::{db::Database, glob::glob_match, parser::RespType};
+use std::thread;
+
+/*
+Keyspace commands: enumeration (KEYS, SCAN), deletion (DEL) and the
+management of logical databases (SELECT, MOVE, SWAPDB, FLUSHDB, FLUSHALL).
+*/
+impl ServerState {
+    /*
+    Parses the database index at `idx`, checking that it is in range.
+    */
+    fn arg_db_index(&self, arr: &[RespType], idx: usize) -> Result<usize, RespType> {
+        let index: i64 = arg_i64(arr, idx)?;
+        if index < 0 || index as usize >= self.dbs.len() {
+            return Err(RespType::Error("ERR DB index is out of range".to_string()));
+        }
+        Ok(index as usize)
+    }
+
+    /*
+    Parses the optional ASYNC | SYNC argument of FLUSHDB and FLUSHALL.
+    Returns whether the flush should happen asynchronously.
+    */
+    fn arg_flush_async(arr: &[RespType], cmd: &str) -> Result<bool, RespType> {
+        match arr.len() {
+            1 => Ok(false),
+            2 => match arg_string(arr, 1)?.to_lowercase().as_str() {
+                "async" => Ok(true),
+                "sync" => Ok(false),
+                _ => Err(RespType::Error("ERR syntax error".to_string())),
+            },
+            _ => Err(wrong_arity(cmd)),
+        }
+    }
+
+    /*
+    Empties the given databases. With `lazy`, the old contents are dropped on
+    a background thread so that freeing a large keyspace doesn't stall the
+    other clients.
+    */
+    fn flush_dbs(&mut self, ids: Vec<usize>, lazy: bool) {
+        let old: Vec<Database> = ids
+            .into_iter()
+            .map(|id| std::mem::take(&mut self.dbs[id]))
+            .collect();
+        if lazy {
+            thread::spawn(move || drop(old));
+        }
+    }
+
+    /*
+    SELECT index
+    */
+    pub(super) fn handle_select(&mut self, arr: Vec<RespType>) -> RespType {
+        if arr.len() != 2 {
+            return wrong_arity("select");
+        }
+        match self.arg_db_index(&arr, 1) {
+            Ok(id) => {
+                self.client.db = id;
+                RespType::SimpleString("OK".to_string())
+            }
+            Err(e) => e,
+        }
+    }
+
+    /*
+    DEL key [key ...]
+    Replies with the number of keys that existed.
+    */
+    pub(super) fn handle_del(&mut self, arr: Vec<RespType>) -> RespType {
+        if arr.len() < 2 {
+            return wrong_arity("del");
+        }
+        let mut deleted: i64 = 0;
+        for idx in 1..arr.len() {
+            let key: String = match arg_string(&arr, idx) {
+                Ok(s) => s,
+                Err(e) => return e,
+            };
+            if !self.expire_if_needed(&key) && self.db_mut().remove(&key).is_some() {
+                deleted += 1;
+            }
+        }
+        self.persistence.dirty += deleted as u64;
+        RespType::Integer(deleted)
+    }
+
+    /*
+    MOVE key db
+    Moves the key (and its time to live) from the selected database to `db`,
+    unless it already exists there.
+    */
+    pub(super) fn handle_move(&mut self, arr: Vec<RespType>) -> RespType {
+        if arr.len() != 3 {
+            return wrong_arity("move");
+        }
+        let key: String = match arg_string(&arr, 1) {
+            Ok(s) => s,
+            Err(e) => return e,
+        };
+        let dst: usize = match self.arg_db_index(&arr, 2) {
+            Ok(id) => id,
+            Err(e) => return e,
+        };
+        let src: usize = self.client.db;
+        if src == dst {
+            return RespType::Error("ERR source and destination objects are the same".to_string());
+        }
+
+        if self.expire_if_needed(&key) || !self.db().dict.contains_key(&key) {
+            return RespType::Integer(0);
+        }
+        self.client.db = dst;
+        let exists_in_dst: bool = !self.expire_if_needed(&key) && self.db().dict.contains_key(&key);
+        self.client.db = src;
+        if exists_in_dst {
+            return RespType::Integer(0);
+        }
+
+        let expire: Option<u64> = self.dbs[src].expires.get(&key).copied();
+        let value: String = self.dbs[src].remove(&key).unwrap();
+        self.dbs[dst].dict.insert(key.clone(), value);
+        if let Some(when) = expire {
+            self.dbs[dst].expires.insert(key.clone(), when);
+        }
+        self.persistence.dirty += 1;
+        RespType::Integer(1)
+    }
+
+    /*
+    SWAPDB index1 index2
+    Clients keep their selected index, so they immediately see the other
+    database's data.
+    */
+    pub(super) fn handle_swapdb(&mut self, arr: Vec<RespType>) -> RespType {
+        if arr.len() != 3 {
+            return wrong_arity("swapdb");
+        }
+        let first: usize = match self.arg_db_index(&arr, 1) {
+            Ok(id) => id,
+            Err(e) => return e,
+        };
+        let second: usize = match self.arg_db_index(&arr, 2) {
+            Ok(id) => id,
+            Err(e) => return e,
+        };
+        self.dbs.swap(first, second);
+        self.persistence.dirty += 1;
+        RespType::SimpleString("OK".to_string())
+    }
+
+    /*
+    FLUSHDB [ASYNC | SYNC]
+    */
+    pub(super) fn handle_flushdb(&mut self, arr: Vec<RespType>) -> RespType {
+        let lazy: bool = match Self::arg_flush_async(&arr, "flushdb") {
+            Ok(lazy) => lazy,
+            Err(e) => return e,
+        };
+        self.flush_dbs(vec![self.client.db], lazy);
+        self.persistence.dirty += 1;
+        RespType::SimpleString("OK".to_string())
+    }
+
+    /*
+    FLUSHALL [ASYNC | SYNC]
+    */
+    pub(super) fn handle_flushall(&mut self, arr: Vec<RespType>) -> RespType {
+        let lazy: bool = match Self::arg_flush_async(&arr, "flushall") {
+            Ok(lazy) => lazy,
+            Err(e) => return e,
+        };
+        self.flush_dbs((0..self.dbs.len()).collect(), lazy);
+        self.persistence.dirty += 1;
+        RespType::SimpleString("OK".to_string())
+    }
+
+    /*
+    Every value we store is a string for now.
+    */
+    pub(super) fn key_type(&self, key: &str) -> &'static str {
+        if self.db().dict.contains_key(key) {
+            "string"
+        } else {
+            "none"
+        }
+    }
+
+    /*
+    KEYS pattern
+    Walks the whole keyspace in one go; prefer SCAN on large databases.
+    */
+    pub(super) fn handle_keys(&mut self, arr: Vec<RespType>) -> RespType {
+        if arr.len() != 2 {
+            return wrong_arity("keys");
+        }
+        let pattern: String = match arg_string(&arr, 1) {
+            Ok(s) => s,
+            Err(e) => return e,
+        };
+        let all_keys: bool = pattern == "*";
+        let keys: Vec<RespType> = self
+            .db()
+            .dict
+            .keys()
+            .filter(|key| all_keys || glob_match(&pattern, key))
+            .filter(|key| !self.is_expired(key))
+            .map(|key| RespType::BulkString(key.clone()))
+            .collect();
+        RespType::Array(keys)
+    }
+
+    /*
+    SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]
+    The cursor is the reverse-binary bucket cursor of `Dict::scan`, so a full
+    iteration returns every key that existed for its whole duration, even if
+    the table was resized in between calls.
+    */
+    pub(super) fn handle_scan(&mut self, arr: Vec<RespType>) -> RespType {
+        if arr.len() < 2 {
+            return wrong_arity("scan");
+        }
+        let cursor: u64 = match arg_string(&arr, 1).map(|s| s.parse::<u64>()) {
+            Ok(Ok(c)) => c,
+            Ok(Err(_)) => return RespType::Error("ERR invalid cursor".to_string()),
+            Err(e) => return e,
+        };
+
+        let mut pattern: Option<String> = None;
+        let mut count: usize = 10;
+        let mut type_filter: Option<String> = None;
+        let mut idx: usize = 2;
+        while idx < arr.len() {
+            let option: String = match arg_string(&arr, idx) {
+                Ok(s) => s.to_lowercase(),
+                Err(e) => return e,
+            };
+            let value: String = match arg_string(&arr, idx + 1) {
+                Ok(s) => s,
+                Err(e) => return e,
+            };
+            match option.as_str() {
+                "match" => pattern = Some(value).filter(|p| p != "*"),
+                "count" => match value.parse::<usize>() {
+                    Ok(c) if c >= 1 => count = c,
+                    Ok(_) => return RespType::Error("ERR syntax error".to_string()),
+                    Err(_) => {
+                        return RespType::Error(
+                            "ERR value is not an integer or out of range".to_string(),
+                        )
+                    }
+                },
+                "type" => type_filter = Some(value.to_lowercase()),
+                _ => return RespType::Error("ERR syntax error".to_string()),
+            }
+            idx += 2;
+        }
+
+        // like Redis, bound the work of a single call when most buckets are empty
+        let mut keys: Vec<String> = Vec::new();
+        let mut next_cursor: u64 = cursor;
+        let mut max_iterations: usize = count.saturating_mul(10);
+        loop {
+            next_cursor = self
+                .db()
+                .dict
+                .scan(next_cursor, |key, _| keys.push(key.clone()));
+            max_iterations -= 1;
+            if next_cursor == 0 || max_iterations == 0 || keys.len() >= count {
+                break;
+            }
+        }
+
+        let keys: Vec<RespType> = keys
+            .into_iter()
+            .filter(|key| match &pattern {
+                Some(p) => glob_match(p, key),
+                None => true,
+            })
+            .filter(|key| match &type_filter {
+                Some(t) => self.key_type(key) == t,
+                None => true,
+            })
+            .filter(|key| !self.is_expired(key))
+            .map(RespType::BulkString)
+            .collect();
+
+        RespType::Array(vec![
+            RespType::BulkString(next_cursor.to_string()),
+            RespType::Array(keys),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::Client,
+        clock::{Clock, ManualClock},
+        config::Config,
+    };
+    use std::{collections::HashSet, sync::Arc};
+
+    fn run_as(state: &mut ServerState, client: &mut Client, args: &[&str]) -> RespType {
+        let cmd: Vec<RespType> = args
+            .iter()
+            .map(|arg| RespType::BulkString(arg.to_string()))
+            .collect();
+        state.execute(client, RespType::Array(cmd))
+    }
+
+    fn run(state: &mut ServerState, args: &[&str]) -> RespType {
+        run_as(state, &mut Client::new(), args)
+    }
+
+    /*
+    A client that has SELECTed `db`.
+    */
+    fn client_on(state: &mut ServerState, db: usize) -> Client {
+        let mut client = Client::new();
+        run_as(state, &mut client, &["SELECT", &db.to_string()]);
+        client
+    }
+
+    fn string(s: &str) -> RespType {
+        RespType::SimpleString(s.to_string())
+    }
+
+    /*
+    Runs one SCAN call, adding the keys it returned to `seen`. Returns the
+    cursor to continue from.
+    */
+    fn scan_once(state: &mut ServerState, cursor: &str, seen: &mut HashSet<String>) -> String {
+        let RespType::Array(reply) = run(state, &["SCAN", cursor, "COUNT", "5"]) else {
+            panic!("SCAN did not reply with an array");
+        };
+        let [RespType::BulkString(next), RespType::Array(keys)] = reply.as_slice() else {
+            panic!("unexpected SCAN reply {:?}", reply);
+        };
+        for key in keys {
+            let RespType::BulkString(key) = key else {
+                panic!("unexpected SCAN key {:?}", key);
+            };
+            seen.insert(key.clone());
+        }
+        next.clone()
+    }
+
+    #[test]
+    fn scan_returns_every_key_present_while_the_keyspace_is_resized() {
+        let mut state = ServerState::new(Config::default());
+        for i in 0..200 {
+            run(&mut state, &["SET", &format!("kept:{}", i), "v"]);
+        }
+        for i in 0..2000 {
+            run(&mut state, &["SET", &format!("gone:{}", i), "v"]);
+        }
+
+        let mut seen: HashSet<String> = HashSet::new();
+        let mut cursor: String = scan_once(&mut state, "0", &mut seen);
+        for i in 0..2000 {
+            run(&mut state, &["DEL", &format!("gone:{}", i)]);
+        }
+        cursor = scan_once(&mut state, &cursor, &mut seen);
+        for i in 0..5000 {
+            run(&mut state, &["SET", &format!("new:{}", i), "v"]);
+        }
+        while cursor != "0" {
+            cursor = scan_once(&mut state, &cursor, &mut seen);
+        }
+
+        for i in 0..200 {
+            assert!(
+                seen.contains(&format!("kept:{}", i)),
+                "kept:{} was missed",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn move_takes_the_time_to_live_along() {
+        let clock: Arc<ManualClock> = Arc::new(ManualClock::new(1_700_000_000_000));
+        let mut state = ServerState::with_clock(Config::default(), clock as Arc<dyn Clock>);
+        let mut db0: Client = client_on(&mut state, 0);
+        let mut db1: Client = client_on(&mut state, 1);
+        run_as(&mut state, &mut db0, &["SET", "k", "v", "PX", "5000"]);
+        run_as(&mut state, &mut db0, &["SET", "plain", "v"]);
+
+        assert_eq!(
+            run_as(&mut state, &mut db0, &["MOVE", "k", "1"]),
+            RespType::Integer(1)
+        );
+        assert_eq!(
+            run_as(&mut state, &mut db0, &["GET", "k"]),
+            RespType::NullBulkString
+        );
+        assert_eq!(run_as(&mut state, &mut db1, &["GET", "k"]), string("v"));
+        assert_eq!(
+            run_as(&mut state, &mut db1, &["PTTL", "k"]),
+            RespType::Integer(5000)
+        );
+        assert!(state.dbs[0].expires.is_empty());
+
+        run_as(&mut state, &mut db0, &["MOVE", "plain", "1"]);
+        assert_eq!(
+            run_as(&mut state, &mut db1, &["PTTL", "plain"]),
+            RespType::Integer(-1)
+        );
+    }
+
+    #[test]
+    fn move_leaves_an_existing_key_alone() {
+        let mut state = ServerState::new(Config::default());
+        let mut db0: Client = client_on(&mut state, 0);
+        let mut db1: Client = client_on(&mut state, 1);
+        run_as(&mut state, &mut db0, &["SET", "k", "from"]);
+        run_as(&mut state, &mut db1, &["SET", "k", "to"]);
+
+        assert_eq!(
+            run_as(&mut state, &mut db0, &["MOVE", "k", "1"]),
+            RespType::Integer(0)
+        );
+        assert_eq!(run_as(&mut state, &mut db0, &["GET", "k"]), string("from"));
+        assert_eq!(run_as(&mut state, &mut db1, &["GET", "k"]), string("to"));
+        assert_eq!(
+            run_as(&mut state, &mut db0, &["MOVE", "missing", "1"]),
+            RespType::Integer(0)
+        );
+        assert_eq!(
+            run_as(&mut state, &mut db0, &["MOVE", "k", "0"]),
+            RespType::Error("ERR source and destination objects are the same".to_string())
+        );
+    }
+
+    #[test]
+    fn db_indexes_out_of_range_are_refused() {
+        let mut state = ServerState::new(Config::default());
+        let out_of_range = RespType::Error("ERR DB index is out of range".to_string());
+        run(&mut state, &["SET", "k", "v"]);
+        for args in [
+            &["MOVE", "k", "16"][..],
+            &["MOVE", "k", "-1"],
+            &["SWAPDB", "0", "16"],
+            &["SWAPDB", "-1", "0"],
+            &["SELECT", "16"],
+        ] {
+            assert_eq!(run(&mut state, args), out_of_range, "{:?}", args);
+        }
+        assert_eq!(run(&mut state, &["GET", "k"]), string("v"));
+    }
+
+    #[test]
+    fn swapdb_is_seen_by_clients_on_either_db() {
+        let mut state = ServerState::new(Config::default());
+        let mut db0: Client = client_on(&mut state, 0);
+        let mut db5: Client = client_on(&mut state, 5);
+        run_as(&mut state, &mut db0, &["SET", "k", "zero"]);
+        run_as(&mut state, &mut db5, &["SET", "k", "five"]);
+
+        // another client swaps them under both
+        assert_eq!(run(&mut state, &["SWAPDB", "0", "5"]), string("OK"));
+        assert_eq!(run_as(&mut state, &mut db0, &["GET", "k"]), string("five"));
+        assert_eq!(run_as(&mut state, &mut db5, &["GET", "k"]), string("zero"));
+        assert_eq!(run(&mut state, &["SWAPDB", "3", "3"]), string("OK"));
+    }
+
+    #[test]
+    fn flushdb_empties_one_db_and_flushall_every_one() {
+        let mut state = ServerState::new(Config::default());
+        let mut db0: Client = client_on(&mut state, 0);
+        let mut db1: Client = client_on(&mut state, 1);
+        run_as(&mut state, &mut db0, &["SET", "a", "1", "EX", "100"]);
+        run_as(&mut state, &mut db1, &["SET", "b", "2"]);
+
+        assert_eq!(run_as(&mut state, &mut db0, &["FLUSHDB"]), string("OK"));
+        assert!(state.dbs[0].dict.is_empty() && state.dbs[0].expires.is_empty());
+        assert_eq!(run_as(&mut state, &mut db1, &["GET", "b"]), string("2"));
+
+        run_as(&mut state, &mut db0, &["SET", "a", "1"]);
+        assert_eq!(run(&mut state, &["FLUSHALL", "ASYNC"]), string("OK"));
+        assert!(state.dbs.iter().all(|db| db.dict.is_empty()));
+        assert_eq!(
+            run(&mut state, &["FLUSHALL", "LATER"]),
+            RespType::Error("ERR syntax error".to_string())
+        );
+    }
+}
@@ -0,0 +1,358 @@
+use super::{arg_i64, arg_string, wrong_arity, ServerState};
+use crate::{parser::RespType, rdb::dump_payload};
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+/*
+MIGRATE: moves keys to another instance by sending it RESTORE commands with
+their DUMP payloads, then deletes them here once the target acknowledged.
+
+Like Redis, the command runs synchronously (the server is locked while it
+talks to the target), and connections to targets are cached so that moving
+many keys one call at a time doesn't pay a connect per key. A cached
+connection unused for MIGRATE_SOCKET_CACHE_TTL is closed by the cron.
+*/
+
+// maximum number of cached connections, one per target
+const MIGRATE_SOCKET_CACHE_ITEMS: usize = 64;
+
+// seconds a cached connection may stay idle
+const MIGRATE_SOCKET_CACHE_TTL: u64 = 10;
+
+/*
+- reader: a buffered handle on the same socket, for the replies.
+- last_dbid: the database the target has selected on this connection, so
+  SELECT is only sent when it changes.
+*/
+pub(super) struct MigrateSocket {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    last_dbid: Option<usize>,
+    last_use_time: Instant,
+}
+
+/*
+Where an I/O error happened, for the error reply.
+*/
+enum MigrateError {
+    Write(io::Error),
+    Read(io::Error),
+}
+
+struct MigrateOptions {
+    copy: bool,
+    replace: bool,
+    // (username, password), sent with AUTH before anything else
+    auth: Option<(Option<String>, String)>,
+    keys: Vec<String>,
+}
+
+impl ServerState {
+    /*
+    MIGRATE host port key|"" destination-db timeout [COPY] [REPLACE]
+        [AUTH password | AUTH2 username password] [KEYS key [key ...]]
+    `timeout` is in milliseconds and bounds every network operation.
+    */
+    pub(super) fn handle_migrate(&mut self, arr: Vec<RespType>) -> RespType {
+        if arr.len() < 6 {
+            return wrong_arity("migrate");
+        }
+        let host: String = match arg_string(&arr, 1) {
+            Ok(s) => s,
+            Err(e) => return e,
+        };
+        let port: u16 = match arg_i64(&arr, 2) {
+            Ok(port) if (0..=65535).contains(&port) => port as u16,
+            Ok(_) => return RespType::Error("ERR Invalid TCP port".to_string()),
+            Err(e) => return e,
+        };
+        let key: String = match arg_string(&arr, 3) {
+            Ok(s) => s,
+            Err(e) => return e,
+        };
+        let dbid: usize = match arg_i64(&arr, 4) {
+            Ok(db) if db >= 0 => db as usize,
+            Ok(_) => {
+                return RespType::Error("ERR value is out of range, must be positive".to_string())
+            }
+            Err(e) => return e,
+        };
+        let timeout: Duration = match arg_i64(&arr, 5) {
+            Ok(ms) if ms > 0 => Duration::from_millis(ms as u64),
+            Ok(_) => Duration::from_millis(1000),
+            Err(e) => return e,
+        };
+        let mut options: MigrateOptions = match Self::migrate_options(&arr) {
+            Ok(options) => options,
+            Err(e) => return e,
+        };
+        if options.keys.is_empty() {
+            options.keys.push(key);
+        } else if !key.is_empty() {
+            return RespType::Error(
+                "ERR When using MIGRATE KEYS option, the key argument must be set to the empty string"
+                    .to_string(),
+            );
+        }
+
+        // only keys that (still) exist are sent
+        let mut keys: Vec<String> = Vec::new();
+        for key in options.keys.drain(..) {
+            if !self.expire_if_needed(&key) && self.db().dict.contains_key(&key) {
+                keys.push(key);
+            }
+        }
+        if keys.is_empty() {
+            return RespType::SimpleString("NOKEY".to_string());
+        }
+
+        let target: String = format!("{}:{}", host, port);
+        let mut retried: bool = false;
+        loop {
+            match self.migrate_keys(&target, dbid, timeout, &keys, &options) {
+                Ok(reply) => return reply,
+                Err((error, may_retry)) => {
+                    // the target closed a cached connection: try once with a new one
+                    self.migrate_cached_sockets.remove(&target);
+                    let timed_out: bool = match &error {
+                        MigrateError::Write(e) | MigrateError::Read(e) => matches!(
+                            e.kind(),
+                            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                        ),
+                    };
+                    if may_retry && !retried && !timed_out {
+                        retried = true;
+                        continue;
+                    }
+                    return match error {
+                        MigrateError::Write(_) => RespType::Error(
+                            "IOERR error or timeout writing to target instance".to_string(),
+                        ),
+                        MigrateError::Read(_) => RespType::Error(
+                            "IOERR error or timeout reading to target instance".to_string(),
+                        ),
+                    };
+                }
+            }
+        }
+    }
+
+    fn migrate_options(arr: &[RespType]) -> Result<MigrateOptions, RespType> {
+        let syntax_error = || RespType::Error("ERR syntax error".to_string());
+        let mut options = MigrateOptions {
+            copy: false,
+            replace: false,
+            auth: None,
+            keys: Vec::new(),
+        };
+        let mut idx: usize = 6;
+        while idx < arr.len() {
+            match arg_string(arr, idx)?.to_lowercase().as_str() {
+                "copy" => options.copy = true,
+                "replace" => options.replace = true,
+                "auth" => {
+                    let password: String = arg_string(arr, idx + 1).map_err(|_| syntax_error())?;
+                    options.auth = Some((None, password));
+                    idx += 1;
+                }
+                "auth2" => {
+                    let username: String = arg_string(arr, idx + 1).map_err(|_| syntax_error())?;
+                    let password: String = arg_string(arr, idx + 2).map_err(|_| syntax_error())?;
+                    options.auth = Some((Some(username), password));
+                    idx += 2;
+                }
+                "keys" => {
+                    for key_idx in idx + 1..arr.len() {
+                        options.keys.push(arg_string(arr, key_idx)?);
+                    }
+                    if options.keys.is_empty() {
+                        return Err(syntax_error());
+                    }
+                    break;
+                }
+                _ => return Err(syntax_error()),
+            }
+            idx += 1;
+        }
+        Ok(options)
+    }
+
+    /*
+    Returns the cached connection to `target`, connecting if there is none.
+    When the cache is full, an arbitrary connection is dropped to make room.
+    */
+    fn migrate_get_socket(
+        &mut self,
+        target: &str,
+        timeout: Duration,
+    ) -> Result<&mut MigrateSocket, RespType> {
+        if !self.migrate_cached_sockets.contains_key(target) {
+            if self.migrate_cached_sockets.len() >= MIGRATE_SOCKET_CACHE_ITEMS {
+                let victim: String = self.migrate_cached_sockets.keys().next().unwrap().clone();
+                self.migrate_cached_sockets.remove(&victim);
+            }
+            let connect_error =
+                || RespType::Error("IOERR error or timeout connecting to the client".to_string());
+            let addr: SocketAddr = target
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut addrs| addrs.next())
+                .ok_or_else(connect_error)?;
+            let stream: TcpStream =
+                TcpStream::connect_timeout(&addr, timeout).map_err(|_| connect_error())?;
+            let reader = BufReader::new(stream.try_clone().map_err(|_| connect_error())?);
+            self.migrate_cached_sockets.insert(
+                target.to_string(),
+                MigrateSocket {
+                    stream,
+                    reader,
+                    last_dbid: None,
+                    last_use_time: Instant::now(),
+                },
+            );
+        }
+        let socket: &mut MigrateSocket = self.migrate_cached_sockets.get_mut(target).unwrap();
+        socket.last_use_time = Instant::now();
+        let _ = socket.stream.set_write_timeout(Some(timeout));
+        let _ = socket.stream.set_read_timeout(Some(timeout));
+        Ok(socket)
+    }
+
+    /*
+    Sends AUTH, SELECT and one RESTORE per key in a single write, then reads
+    the replies in order. Keys the target restored are deleted here, unless
+    COPY was given. On an I/O error, also returns whether nothing was
+    acknowledged yet, in which case the whole transfer may be retried.
+    */
+    fn migrate_keys(
+        &mut self,
+        target: &str,
+        dbid: usize,
+        timeout: Duration,
+        keys: &[String],
+        options: &MigrateOptions,
+    ) -> Result<RespType, (MigrateError, bool)> {
+        let select: bool = match self.migrate_get_socket(target, timeout) {
+            Ok(socket) => socket.last_dbid != Some(dbid),
+            Err(e) => return Ok(e),
+        };
+
+        let now: u64 = self.clock.now_ms();
+        let mut commands: Vec<Vec<RespType>> = Vec::new();
+        if let Some((username, password)) = &options.auth {
+            let mut cmd: Vec<RespType> = vec![RespType::BulkString("AUTH".to_string())];
+            if let Some(username) = username {
+                cmd.push(RespType::BulkString(username.clone()));
+            }
+            cmd.push(RespType::BulkString(password.clone()));
+            commands.push(cmd);
+        }
+        if select {
+            commands.push(vec![
+                RespType::BulkString("SELECT".to_string()),
+                RespType::BulkString(dbid.to_string()),
+            ]);
+        }
+        for key in keys {
+            let value: &String = self.db().dict.get(key).unwrap();
+            // the target gets what is left of the time to live
+            let ttl: u64 = match self.db().expires.get(key) {
+                Some(when) => when.saturating_sub(now).max(1),
+                None => 0,
+            };
+            let mut cmd: Vec<RespType> = vec![
+                RespType::BulkString("RESTORE".to_string()),
+                RespType::BulkString(key.clone()),
+                RespType::BulkString(ttl.to_string()),
+                RespType::BulkBytes(dump_payload(value)),
+            ];
+            if options.replace {
+                cmd.push(RespType::BulkString("REPLACE".to_string()));
+            }
+            commands.push(cmd);
+        }
+        let payload: Vec<u8> = commands
+            .into_iter()
+            .flat_map(|cmd| RespType::Array(cmd).to_resp_bytes())
+            .collect();
+
+        let socket: &mut MigrateSocket = self.migrate_cached_sockets.get_mut(target).unwrap();
+        socket
+            .stream
+            .write_all(&payload)
+            .map_err(|e| (MigrateError::Write(e), true))?;
+
+        // replies come in the order of the commands: AUTH, SELECT, RESTOREs
+        let mut first_error: Option<String> = None;
+        let mut replies: usize = 0;
+        let mut next_reply = |socket: &mut MigrateSocket| {
+            let reply =
+                read_reply(&mut socket.reader).map_err(|e| (MigrateError::Read(e), replies == 0));
+            replies += 1;
+            reply
+        };
+        if options.auth.is_some() {
+            if let Some(error) = next_reply(socket)?.strip_prefix('-') {
+                first_error.get_or_insert(error.to_string());
+            }
+        }
+        if select {
+            match next_reply(socket)?.strip_prefix('-') {
+                Some(error) => {
+                    socket.last_dbid = None;
+                    first_error.get_or_insert(error.to_string());
+                }
+                None => socket.last_dbid = Some(dbid),
+            }
+        }
+        let mut migrated: Vec<String> = Vec::new();
+        for key in keys {
+            match next_reply(socket)?.strip_prefix('-') {
+                Some(error) => {
+                    first_error.get_or_insert(error.to_string());
+                }
+                None => migrated.push(key.clone()),
+            }
+        }
+
+        if !options.copy && !migrated.is_empty() {
+            let mut del: Vec<RespType> = vec![RespType::BulkString("DEL".to_string())];
+            for key in migrated {
+                self.db_mut().remove(&key);
+                del.push(RespType::BulkString(key));
+                self.persistence.dirty += 1;
+            }
+            self.rewrite_command(del);
+        }
+        Ok(match first_error {
+            Some(error) => {
+                RespType::Error(format!("ERR Target instance replied with error: {}", error))
+            }
+            None => RespType::SimpleString("OK".to_string()),
+        })
+    }
+
+    /*
+    Closes cached MIGRATE connections that have been idle for too long.
+    */
+    pub(super) fn migrate_close_timedout_sockets(&mut self) {
+        self.migrate_cached_sockets.retain(|_, socket| {
+            socket.last_use_time.elapsed() <= Duration::from_secs(MIGRATE_SOCKET_CACHE_TTL)
+        });
+    }
+}
+
+/*
+Reads one reply line from the target; our commands only get simple string
+or error replies.
+*/
+fn read_reply(reader: &mut BufReader<TcpStream>) -> io::Result<String> {
+    let mut line: String = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(line.trim_end().to_string())
+}
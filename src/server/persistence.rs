@@ -0,0 +1,503 @@
+use super::{arg_string, wrong_arity, ServerState};
+use crate::{
+    db::Database,
+    parser::RespType,
+    rdb::{dump_databases, parse_rdb, Rdb},
+};
+use std::{
+    fs,
+    io::{ErrorKind, Write},
+    path::Path,
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+    time::Instant,
+};
+
+/*
+Persistence: loading the dataset from an RDB snapshot, and writing snapshots
+with SAVE, BGSAVE and the configured save points.
+*/
+
+// after a failed BGSAVE, wait this long before save points may try again
+const BGSAVE_RETRY_DELAY_SECS: u64 = 5;
+
+/*
+Bookkeeping for RDB snapshots.
+- dirty: number of changes since the last successful save.
+- lastsave: Unix time (seconds) of the last successful save.
+- bgsave: the BGSAVE thread in progress, if any. It reports back once the
+  file is written, and `dirty_before_bgsave` remembers how many of the
+  changes were part of its snapshot.
+*/
+pub(super) struct RdbState {
+    pub dirty: u64,
+    dirty_before_bgsave: u64,
+    lastsave: u64,
+    lastbgsave_try: u64,
+    lastbgsave_ok: bool,
+    bgsave: Option<Receiver<Result<(), String>>>,
+}
+
+impl RdbState {
+    pub fn new(now_secs: u64) -> Self {
+        RdbState {
+            dirty: 0,
+            dirty_before_bgsave: 0,
+            lastsave: now_secs,
+            lastbgsave_try: 0,
+            lastbgsave_ok: true,
+            bgsave: None,
+        }
+    }
+}
+
+/*
+Writes `bytes` to `path` through a temporary file in the same directory, so
+that readers never see a half-written snapshot.
+*/
+pub fn write_file_atomically(path: &Path, bytes: &[u8]) -> Result<(), String> {
+    let dir: &Path = path.parent().unwrap_or(Path::new("."));
+    let tmp_path = dir.join(format!(
+        "temp-{}-{:?}.rdb",
+        std::process::id(),
+        thread::current().id()
+    ));
+    let result = fs::File::create(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(bytes)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp_path, path));
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(format!("Failed saving {}: {}", path.display(), e));
+    }
+    Ok(())
+}
+
+impl ServerState {
+    /*
+    Loads the dataset at startup. With appendonly on, the AOF is the source
+    of truth (see aof.rs).
+    */
+    pub fn load_data(&mut self) -> Result<(), String> {
+        if !self.config.appendonly {
+            return self.load_rdb_file();
+        }
+        self.loading = true;
+        let result: Result<(), String> = self.load_append_only_files();
+        self.loading = false;
+        result?;
+        // replaying the AOF went through the regular write path
+        self.persistence.dirty = 0;
+        self.repl_seldb = None;
+        Ok(())
+    }
+
+    /*
+    Loads `dir`/`dbfilename` into the keyspace, if the file exists. A missing
+    file just means we start empty; a corrupt one is an error.
+    */
+    pub fn load_rdb_file(&mut self) -> Result<(), String> {
+        let path = self.config.rdb_path();
+        let bytes: Vec<u8> = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(format!("Error reading {}: {}", path.display(), e)),
+        };
+        let rdb: Rdb =
+            parse_rdb(&bytes).map_err(|e| format!("Bad RDB file {}: {}", path.display(), e))?;
+        let aux: Vec<(String, String)> = rdb.aux.clone();
+        let loaded: usize = self.load_rdb(rdb)?;
+        println!("Loaded {} keys from {}", loaded, path.display());
+        self.restore_replication_info(&aux);
+        Ok(())
+    }
+
+    /*
+    Inserts every entry of `rdb` into its database. Keys that already expired
+    while the server was down are skipped. Returns the number of keys loaded.
+    */
+    pub fn load_rdb(&mut self, rdb: Rdb) -> Result<usize, String> {
+        let now: u64 = self.clock.now_ms();
+        let mut loaded: usize = 0;
+        for entry in rdb.entries {
+            if entry.db >= self.dbs.len() {
+                return Err(format!(
+                    "RDB file uses database {} but the server is configured with {} databases",
+                    entry.db,
+                    self.dbs.len()
+                ));
+            }
+            if entry.expire_ms.is_some_and(|when| when < now) {
+                continue;
+            }
+            let db = &mut self.dbs[entry.db];
+            if let Some(when) = entry.expire_ms {
+                db.expires.insert(entry.key.clone(), when);
+            }
+            db.dict.insert(entry.key, entry.value);
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+
+    /*
+    Auxiliary fields written at the start of every snapshot.
+    */
+    pub(super) fn rdb_aux(&self) -> Vec<(String, String)> {
+        let mut aux: Vec<(String, String)> = vec![
+            ("redis-ver".to_string(), "7.2.0".to_string()),
+            ("redis-bits".to_string(), "64".to_string()),
+            (
+                "ctime".to_string(),
+                (self.clock.now_ms() / 1000).to_string(),
+            ),
+            ("used-mem".to_string(), "0".to_string()),
+            ("aof-base".to_string(), "0".to_string()),
+        ];
+        // our replicas apply the forwarded stream from this database on
+        if self.replica_of.is_some() {
+            aux.push((
+                "repl-stream-db".to_string(),
+                self.master_stream_db.to_string(),
+            ));
+        }
+        // the history the dataset belongs to, restored on load
+        if let (Some(replid), Some(offset)) = (&self.replication_id, self.replication_offset) {
+            aux.push(("repl-id".to_string(), replid.clone()));
+            aux.push(("repl-offset".to_string(), offset.to_string()));
+        }
+        aux
+    }
+
+    /*
+    Serializes the whole dataset into an RDB file held in memory.
+    */
+    pub fn rdb_snapshot(&self) -> Vec<u8> {
+        dump_databases(&self.dbs, &self.rdb_aux())
+    }
+
+    fn save_done(&mut self, saved_dirty: u64) {
+        self.persistence.dirty -= saved_dirty.min(self.persistence.dirty);
+        self.persistence.lastsave = self.clock.now_ms() / 1000;
+    }
+
+    /*
+    Synchronous save, blocking every other client until the file is written.
+    */
+    pub fn rdb_save(&mut self) -> Result<(), String> {
+        let bytes: Vec<u8> = self.rdb_snapshot();
+        write_file_atomically(&self.config.rdb_path(), &bytes)?;
+        let saved_dirty: u64 = self.persistence.dirty;
+        self.save_done(saved_dirty);
+        Ok(())
+    }
+
+    /*
+    Copies the databases for a background snapshot (BGSAVE, BGREWRITEAOF, a
+    full sync), which plays the role of Redis' fork(): it freezes a
+    consistent view of the dataset, that is then serialized and written
+    without holding up the command path.
+
+    The copy itself is not free though. It is taken under the lock and takes
+    time proportional to the dataset, during which every client waits, as
+    they do during a fork() in Redis. INFO stats reports how long the last
+    one took as latest_fork_usec.
+    */
+    pub(super) fn snapshot_dbs(&mut self) -> Vec<Database> {
+        let start: Instant = Instant::now();
+        let dbs: Vec<Database> = self.dbs.clone();
+        self.stat_fork_time_us = start.elapsed().as_micros() as u64;
+        self.stat_total_forks += 1;
+        dbs
+    }
+
+    /*
+    Background save, from a copy of the dataset (see `snapshot_dbs`).
+    */
+    pub fn rdb_bgsave(&mut self) -> Result<(), String> {
+        if self.persistence.bgsave.is_some() {
+            return Err("Background save already in progress".to_string());
+        }
+        let dbs: Vec<Database> = self.snapshot_dbs();
+        let aux: Vec<(String, String)> = self.rdb_aux();
+        let path = self.config.rdb_path();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let bytes: Vec<u8> = dump_databases(&dbs, &aux);
+            let _ = tx.send(write_file_atomically(&path, &bytes));
+        });
+        self.persistence.bgsave = Some(rx);
+        self.persistence.dirty_before_bgsave = self.persistence.dirty;
+        self.persistence.lastbgsave_try = self.clock.now_ms() / 1000;
+        Ok(())
+    }
+
+    /*
+    Called from the cron: collects the result of a finished BGSAVE.
+    */
+    pub(super) fn check_bgsave_done(&mut self) {
+        let result: Result<(), String> = match &self.persistence.bgsave {
+            Some(rx) => match rx.try_recv() {
+                Ok(result) => result,
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => {
+                    Err("Background saving thread terminated".to_string())
+                }
+            },
+            None => return,
+        };
+        self.persistence.bgsave = None;
+        match result {
+            Ok(()) => {
+                println!("Background saving terminated with success");
+                self.persistence.lastbgsave_ok = true;
+                let saved_dirty: u64 = self.persistence.dirty_before_bgsave;
+                self.save_done(saved_dirty);
+            }
+            Err(e) => {
+                eprintln!("Background saving error: {}", e);
+                self.persistence.lastbgsave_ok = false;
+            }
+        }
+    }
+
+    /*
+    Called from the cron: starts a BGSAVE when any `save <seconds> <changes>`
+    point is reached.
+    */
+    pub(super) fn check_save_points(&mut self) {
+        if self.persistence.bgsave.is_some() {
+            return;
+        }
+        let now: u64 = self.clock.now_ms() / 1000;
+        if !self.persistence.lastbgsave_ok
+            && now.saturating_sub(self.persistence.lastbgsave_try) < BGSAVE_RETRY_DELAY_SECS
+        {
+            return;
+        }
+        let since_save: u64 = now.saturating_sub(self.persistence.lastsave);
+        let reached: Option<(u64, u64)> = self
+            .config
+            .save
+            .iter()
+            .find(|(seconds, changes)| self.persistence.dirty >= *changes && since_save >= *seconds)
+            .copied();
+        if let Some((seconds, changes)) = reached {
+            println!("{} changes in {} seconds. Saving...", changes, seconds);
+            if let Err(e) = self.rdb_bgsave() {
+                eprintln!("{}", e);
+            }
+        }
+    }
+
+    pub(super) fn info_persistence(&self) -> Vec<String> {
+        let mut output: Vec<String> = vec![
+            format!("rdb_changes_since_last_save:{}", self.persistence.dirty),
+            format!(
+                "rdb_bgsave_in_progress:{}",
+                self.persistence.bgsave.is_some() as u8
+            ),
+            format!("rdb_last_save_time:{}", self.persistence.lastsave),
+            format!(
+                "rdb_last_bgsave_status:{}",
+                if self.persistence.lastbgsave_ok {
+                    "ok"
+                } else {
+                    "err"
+                }
+            ),
+        ];
+        output.extend(self.info_aof());
+        output
+    }
+
+    /*
+    SAVE
+    */
+    pub(super) fn handle_save(&mut self, arr: Vec<RespType>) -> RespType {
+        if arr.len() != 1 {
+            return wrong_arity("save");
+        }
+        if self.persistence.bgsave.is_some() {
+            return RespType::Error("ERR Background save already in progress".to_string());
+        }
+        match self.rdb_save() {
+            Ok(()) => RespType::SimpleString("OK".to_string()),
+            Err(e) => RespType::Error(format!("ERR {}", e)),
+        }
+    }
+
+    /*
+    BGSAVE [SCHEDULE]
+    */
+    pub(super) fn handle_bgsave(&mut self, arr: Vec<RespType>) -> RespType {
+        if arr.len() > 2 {
+            return wrong_arity("bgsave");
+        }
+        if arr.len() == 2 {
+            match arg_string(&arr, 1) {
+                Ok(s) if s.eq_ignore_ascii_case("schedule") => {}
+                Ok(_) => return RespType::Error("ERR syntax error".to_string()),
+                Err(e) => return e,
+            }
+        }
+        match self.rdb_bgsave() {
+            Ok(()) => RespType::SimpleString("Background saving started".to_string()),
+            Err(e) => RespType::Error(format!("ERR {}", e)),
+        }
+    }
+
+    /*
+    LASTSAVE
+    */
+    pub(super) fn handle_lastsave(&mut self, arr: Vec<RespType>) -> RespType {
+        if arr.len() != 1 {
+            return wrong_arity("lastsave");
+        }
+        RespType::Integer(self.persistence.lastsave as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::Client,
+        clock::{Clock, ManualClock},
+        config::Config,
+        rdb::RdbEntry,
+        test_dir::TempDir,
+    };
+    use std::{sync::Arc, time::Duration};
+
+    const START_MS: u64 = 1_700_000_000_000;
+
+    fn server(dir: &TempDir, save: Vec<(u64, u64)>) -> (ServerState, Arc<ManualClock>) {
+        let config = Config {
+            dir: dir.path().display().to_string(),
+            save,
+            ..Config::default()
+        };
+        let clock: Arc<ManualClock> = Arc::new(ManualClock::new(START_MS));
+        let state = ServerState::with_clock(config, clock.clone() as Arc<dyn Clock>);
+        (state, clock)
+    }
+
+    fn run_as(state: &mut ServerState, client: &mut Client, args: &[&str]) -> RespType {
+        let cmd: Vec<RespType> = args
+            .iter()
+            .map(|arg| RespType::BulkString(arg.to_string()))
+            .collect();
+        state.execute(client, RespType::Array(cmd))
+    }
+
+    fn run(state: &mut ServerState, args: &[&str]) -> RespType {
+        run_as(state, &mut Client::new(), args)
+    }
+
+    fn wait_for_bgsave(state: &mut ServerState) {
+        while state.persistence.bgsave.is_some() {
+            thread::sleep(Duration::from_millis(1));
+            state.check_bgsave_done();
+        }
+    }
+
+    fn entry(db: usize, key: &str, value: &str, expire_ms: Option<u64>) -> RdbEntry {
+        RdbEntry {
+            db,
+            key: key.to_string(),
+            value: value.to_string(),
+            expire_ms,
+        }
+    }
+
+    #[test]
+    fn a_snapshot_keeps_every_db_and_expire() {
+        let dir = TempDir::new();
+        let (mut state, _) = server(&dir, vec![]);
+        let mut client: Client = Client::new();
+        run_as(&mut state, &mut client, &["SET", "a", "1"]);
+        run_as(&mut state, &mut client, &["SET", "b", &"x".repeat(20000)]);
+        run_as(&mut state, &mut client, &["SELECT", "15"]);
+        run_as(&mut state, &mut client, &["SET", "c", "-42"]);
+        let deadline: String = (START_MS + 60_000).to_string();
+        run_as(&mut state, &mut client, &["PEXPIREAT", "c", &deadline]);
+
+        let mut entries: Vec<RdbEntry> = parse_rdb(&state.rdb_snapshot()).unwrap().entries;
+        entries.sort_by(|x, y| x.key.cmp(&y.key));
+        assert_eq!(
+            entries,
+            vec![
+                entry(0, "a", "1", None),
+                entry(0, "b", &"x".repeat(20000), None),
+                entry(15, "c", "-42", Some(START_MS + 60_000)),
+            ]
+        );
+
+        // and a server started on the saved file has the same dataset
+        assert_eq!(
+            run(&mut state, &["SAVE"]),
+            RespType::SimpleString("OK".to_string())
+        );
+        let (mut restarted, _) = server(&dir, vec![]);
+        restarted.load_data().unwrap();
+        assert_eq!(restarted.dbs[0].dict.len(), 2);
+        let mut client: Client = Client::new();
+        run_as(&mut restarted, &mut client, &["SELECT", "15"]);
+        assert_eq!(
+            run_as(&mut restarted, &mut client, &["PTTL", "c"]),
+            RespType::Integer(60_000)
+        );
+    }
+
+    #[test]
+    fn bgsave_only_takes_schedule() {
+        let dir = TempDir::new();
+        let (mut state, _) = server(&dir, vec![]);
+        assert_eq!(
+            run(&mut state, &["BGSAVE", "foo"]),
+            RespType::Error("ERR syntax error".to_string())
+        );
+        assert!(state.persistence.bgsave.is_none());
+        assert_eq!(
+            run(&mut state, &["BGSAVE", "Schedule"]),
+            RespType::SimpleString("Background saving started".to_string())
+        );
+        wait_for_bgsave(&mut state);
+        assert!(dir.path().join("dump.rdb").exists());
+    }
+
+    #[test]
+    fn a_save_point_starts_a_bgsave_once_both_limits_are_reached() {
+        let dir = TempDir::new();
+        let (mut state, clock) = server(&dir, vec![(60, 2)]);
+        run(&mut state, &["SET", "a", "1"]);
+        clock.advance(61_000);
+        state.check_save_points();
+        assert!(state.persistence.bgsave.is_none());
+
+        run(&mut state, &["SET", "b", "2"]);
+        state.check_save_points();
+        assert!(state.persistence.bgsave.is_some());
+        // a write made while it runs is not part of the snapshot
+        run(&mut state, &["SET", "c", "3"]);
+        wait_for_bgsave(&mut state);
+        assert_eq!(state.persistence.dirty, 1);
+        assert_eq!(state.persistence.lastsave, (START_MS + 61_000) / 1000);
+        let saved: Rdb = parse_rdb(&fs::read(dir.path().join("dump.rdb")).unwrap()).unwrap();
+        assert_eq!(saved.entries.len(), 2);
+
+        // the next point counts from that save
+        run(&mut state, &["SET", "d", "4"]);
+        clock.advance(59_000);
+        state.check_save_points();
+        assert!(state.persistence.bgsave.is_none());
+        clock.advance(1_000);
+        state.check_save_points();
+        assert!(state.persistence.bgsave.is_some());
+        wait_for_bgsave(&mut state);
+        assert_eq!(state.persistence.dirty, 0);
+    }
+}
@@ -0,0 +1,1704 @@
+use super::{arg_i64, arg_string, wrong_arity, ServerAddr, ServerState, CMD_STALE, CMD_WRITE};
+use crate::{
+    client::{Client, WaitRequest},
+    config::ReplDisklessLoad,
+    db::Database,
+    parser::RespType,
+    rdb::{dump_databases, Rdb},
+};
+use std::{
+    collections::hash_map::RandomState,
+    fs::File,
+    hash::{BuildHasher, Hasher},
+    io::{Read, Write},
+    net::{Shutdown, TcpStream},
+    path::PathBuf,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/*
+Master side of replication: the replicas attached to this server and how the
+write stream reaches them.
+
+A replica is attached when its PSYNC is answered with FULLRESYNC. From that
+moment on it must see every write that is not part of its snapshot, but the
+snapshot itself has not been sent yet. So until `replica_online` the writes
+are kept in `pending`, and flushed right after the RDB payload. The snapshot
+is a copy of the databases taken under the lock, like BGSAVE does (see
+`snapshot_dbs`); its connection thread serializes it (wait_bgsave) and
+sends it (send_bulk).
+
+With repl-diskless-sync, replicas that can read it (capa eof) wait up to
+repl-diskless-sync-delay seconds for others to arrive, so that a single
+snapshot serves them all. The cron takes it, and only then are they
+answered FULLRESYNC, with the offset it corresponds to. The RDB is sent
+without knowing its length upfront, between `$EOF:<mark>` and the mark, and
+the write stream starts once the replica acknowledged it loaded it.
+
+A replica whose connection fails is dropped, and has to sync again.
+
+Every byte of the write stream also goes to the replication backlog, and
+advances the replication offset. A replica that reconnects asks to continue
+from its own offset (PSYNC <replid> <offset>), and if the backlog still
+holds everything from there on, it is answered with CONTINUE and sent just
+those bytes instead of a new snapshot. The replication id names the history
+the offsets count in; after a replica is promoted, its master's id stays
+valid as `replication_id2` up to the offset of the switch (PSYNC2), so the
+other replicas of the old master can continue from the new one.
+
+A replica can have replicas of its own. It forwards the stream of its master
+to them byte for byte, under the master's replication id, so the whole chain
+shares one history and the same offsets.
+
+Replicas acknowledge the offset they processed (and fsynced to their AOF)
+with REPLCONF ACK, when asked with REPLCONF GETACK. WAIT and WAITAOF block a
+client until enough of them reached its last write.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum ReplicaState {
+    // waiting for a diskless sync to start, the snapshot will have the writes
+    WaitBgsaveStart,
+    // the snapshot is being serialized, buffer the write stream
+    WaitBgsave,
+    // the RDB payload is being sent, buffer the write stream
+    SendBulk,
+    // receives the write stream as it happens
+    Online,
+}
+
+/*
+Replica side: where the link with the master is, as Redis' repl_state.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplState {
+    // waiting to (re)connect
+    Connect,
+    // the TCP connection is being established
+    Connecting,
+    // PING and REPLCONF exchanged, each reply checked
+    Handshake,
+    // PSYNC sent, waiting for FULLRESYNC or CONTINUE
+    ReceivePsync,
+    // receiving the RDB payload
+    Transfer,
+    // applying the write stream
+    Connected,
+}
+
+/*
+A new replication id: 40 hex characters from the OS entropy source. Where
+there is none, the time hashed with RandomState keys stands in: those are
+seeded from the OS once per thread, then incremented for each new one.
+*/
+pub(super) fn random_replid() -> String {
+    let mut bytes: [u8; 20] = [0; 20];
+    if File::open("/dev/urandom")
+        .and_then(|mut file| file.read_exact(&mut bytes))
+        .is_err()
+    {
+        let nanos: u128 = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        for chunk in bytes.chunks_mut(8) {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u128(nanos);
+            chunk.copy_from_slice(&hasher.finish().to_le_bytes()[..chunk.len()]);
+        }
+    }
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl ReplicaState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ReplicaState::WaitBgsaveStart | ReplicaState::WaitBgsave => "wait_bgsave",
+            ReplicaState::SendBulk => "send_bulk",
+            ReplicaState::Online => "online",
+        }
+    }
+}
+
+/*
+A consistent copy of the dataset for a FULLRESYNC, serialized by the
+connection thread outside the lock.
+*/
+pub struct SyncSnapshot {
+    dbs: Vec<Database>,
+    aux: Vec<(String, String)>,
+}
+
+impl SyncSnapshot {
+    /*
+    The RDB payload, framed as `$<len>\r\n<bytes>` (no trailing CRLF).
+    */
+    pub fn to_payload(&self) -> Vec<u8> {
+        let rdb: Vec<u8> = dump_databases(&self.dbs, &self.aux);
+        let mut payload: Vec<u8> = format!("${}\r\n", rdb.len()).into_bytes();
+        payload.extend_from_slice(&rdb);
+        payload
+    }
+}
+
+/*
+A diskless sync, shared by the replicas that were waiting when it started:
+the point of the history its snapshot was taken at, and the mark that ends
+the RDB. The first of their connection threads to get there serializes it.
+*/
+pub struct DisklessSync {
+    replid: String,
+    offset: u64,
+    mark: String,
+    snapshot: SyncSnapshot,
+    rdb: OnceLock<Vec<u8>>,
+}
+
+impl DisklessSync {
+    /*
+    The FULLRESYNC reply, deferred until now.
+    */
+    pub fn reply(&self) -> Vec<u8> {
+        RespType::SimpleString(format!("FULLRESYNC {} {}", self.replid, self.offset))
+            .to_resp_bytes()
+    }
+
+    /*
+    `$EOF:<mark>\r\n`, the RDB, then the mark again.
+    */
+    pub fn write_payload(&self, stream: &mut TcpStream) -> std::io::Result<()> {
+        let rdb: &[u8] = self
+            .rdb
+            .get_or_init(|| dump_databases(&self.snapshot.dbs, &self.snapshot.aux));
+        stream.write_all(format!("$EOF:{}\r\n", self.mark).as_bytes())?;
+        stream.write_all(rdb)?;
+        stream.write_all(self.mark.as_bytes())
+    }
+}
+
+/*
+The last `repl-backlog-size` bytes of the write stream, in a circular
+buffer.
+- idx: where the next byte goes.
+- histlen: how many bytes of `buf` are valid. They end at the current
+  replication offset.
+*/
+pub(super) struct ReplBacklog {
+    buf: Vec<u8>,
+    idx: usize,
+    histlen: usize,
+}
+
+impl ReplBacklog {
+    fn new(size: usize) -> Self {
+        ReplBacklog {
+            buf: vec![0; size],
+            idx: 0,
+            histlen: 0,
+        }
+    }
+
+    fn feed(&mut self, mut bytes: &[u8]) {
+        let size: usize = self.buf.len();
+        while !bytes.is_empty() {
+            let len: usize = (size - self.idx).min(bytes.len());
+            self.buf[self.idx..self.idx + len].copy_from_slice(&bytes[..len]);
+            self.idx = (self.idx + len) % size;
+            self.histlen = (self.histlen + len).min(size);
+            bytes = &bytes[len..];
+        }
+    }
+
+    /*
+    Replication offset of the first byte held, given the current offset.
+    */
+    fn first_byte_offset(&self, repl_offset: u64) -> u64 {
+        repl_offset + 1 - self.histlen as u64
+    }
+
+    /*
+    The bytes from replication offset `from` up to `repl_offset`, or None if
+    the backlog doesn't go back that far (or `from` is in the future).
+    */
+    fn since(&self, from: u64, repl_offset: u64) -> Option<Vec<u8>> {
+        let first: u64 = self.first_byte_offset(repl_offset);
+        if from < first || from > repl_offset + 1 {
+            return None;
+        }
+        let size: usize = self.buf.len();
+        let skip: usize = (from - first) as usize;
+        let start: usize = (self.idx + size - self.histlen + skip) % size;
+        let len: usize = self.histlen - skip;
+        let mut bytes: Vec<u8> = Vec::with_capacity(len);
+        if start + len <= size {
+            bytes.extend_from_slice(&self.buf[start..start + len]);
+        } else {
+            bytes.extend_from_slice(&self.buf[start..]);
+            bytes.extend_from_slice(&self.buf[..start + len - size]);
+        }
+        Some(bytes)
+    }
+}
+
+/*
+- addr: the IP address the replica connected from; listening_port and capa
+  are what it announced with REPLCONF.
+- ack_offset / aof_ack_offset: the offsets the replica last acknowledged as
+  processed and as fsynced to its AOF, at `ack_time`.
+- attach_time: when it sent PSYNC, for repl-diskless-sync-delay.
+- diskless: the diskless sync it is part of, once it started.
+- start_on_ack: the write stream waits for its first ACK, which tells that
+  it loaded the RDB (diskless syncs).
+*/
+pub(super) struct Replica {
+    pub client_id: u64,
+    pub stream: TcpStream,
+    pub addr: String,
+    pub listening_port: u16,
+    pub capa: Vec<String>,
+    pub state: ReplicaState,
+    pub pending: Vec<u8>,
+    pub ack_offset: u64,
+    pub aof_ack_offset: u64,
+    pub ack_time: Instant,
+    pub attach_time: Instant,
+    pub diskless: Option<Arc<DisklessSync>>,
+    pub start_on_ack: bool,
+}
+
+impl Replica {
+    fn new(client: &Client, stream: TcpStream, pending: Vec<u8>) -> Self {
+        Replica {
+            client_id: client.id,
+            addr: stream
+                .peer_addr()
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or_else(|_| "?".to_string()),
+            stream,
+            listening_port: client.repl_listening_port,
+            capa: client.repl_capa.clone(),
+            state: ReplicaState::WaitBgsave,
+            pending,
+            ack_offset: 0,
+            aof_ack_offset: 0,
+            ack_time: Instant::now(),
+            attach_time: Instant::now(),
+            diskless: None,
+            start_on_ack: false,
+        }
+    }
+
+    fn name(&self) -> String {
+        format!("{}:{}", self.addr, self.listening_port)
+    }
+}
+
+impl ServerState {
+    /*
+    Appends already serialized commands to the stream of every replica, and
+    to the backlog (there is none until a first replica attaches). The
+    offset counts the stream even when nobody receives it, so WAITAOF can
+    tell writes apart.
+    */
+    pub(super) fn feed_replicas(&mut self, bytes: &[u8]) {
+        if let Some(offset) = self.replication_offset {
+            self.replication_offset = Some(offset + bytes.len() as u64);
+        }
+        if let Some(backlog) = self.repl_backlog.as_mut() {
+            backlog.feed(bytes);
+        }
+        self.slave_servers
+            .retain_mut(|replica| match replica.state {
+                ReplicaState::WaitBgsaveStart => true,
+                ReplicaState::WaitBgsave | ReplicaState::SendBulk => {
+                    replica.pending.extend_from_slice(bytes);
+                    true
+                }
+                ReplicaState::Online => match replica.stream.write_all(bytes) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        eprintln!("Connection with replica {} lost: {}", replica.name(), e);
+                        false
+                    }
+                },
+            });
+    }
+
+    /*
+    Copies the dataset for a FULLRESYNC and attaches `stream` as a replica
+    whose writes are buffered until `replica_online`. Both happen under the
+    same lock, so every write is either in the snapshot or in the buffer.
+    */
+    pub fn start_full_resync(&mut self, client: &Client, stream: TcpStream) -> SyncSnapshot {
+        if self.repl_backlog.is_none() {
+            self.repl_backlog = Some(ReplBacklog::new(self.config.repl_backlog_size));
+        }
+        let snapshot = SyncSnapshot {
+            dbs: self.snapshot_dbs(),
+            aux: self.rdb_aux(),
+        };
+        self.attach_replica(Replica::new(client, stream, Vec::new()));
+        // make sure the new slave is told which database the stream writes to
+        self.repl_seldb = None;
+        snapshot
+    }
+
+    /*
+    Whether a FULLRESYNC for the calling client should be diskless.
+    */
+    pub(super) fn diskless_sync_for_client(&self) -> bool {
+        self.config.repl_diskless_sync && self.client.repl_capa.iter().any(|c| c == "eof")
+    }
+
+    /*
+    Attaches `stream` as a replica waiting for the next diskless sync. It
+    gets no writes until then: they will be part of the snapshot.
+    */
+    pub fn replica_wait_bgsave_start(&mut self, client: &Client, stream: TcpStream) {
+        let mut replica = Replica::new(client, stream, Vec::new());
+        replica.state = ReplicaState::WaitBgsaveStart;
+        self.attach_replica(replica);
+    }
+
+    /*
+    Called from the cron: once the first waiting replica waited
+    repl-diskless-sync-delay, takes the snapshot for all of them.
+    */
+    pub(super) fn replication_cron(&mut self) {
+        let delay = Duration::from_secs(self.config.repl_diskless_sync_delay);
+        let due: bool = self
+            .slave_servers
+            .iter()
+            .filter(|r| r.state == ReplicaState::WaitBgsaveStart)
+            .any(|r| r.attach_time.elapsed() >= delay);
+        if !due {
+            return;
+        }
+
+        if self.repl_backlog.is_none() {
+            self.repl_backlog = Some(ReplBacklog::new(self.config.repl_backlog_size));
+        }
+        let sync = Arc::new(DisklessSync {
+            replid: self.replication_id.clone().unwrap_or_default(),
+            offset: self.replication_offset.unwrap_or_default(),
+            mark: random_replid(),
+            snapshot: SyncSnapshot {
+                dbs: self.snapshot_dbs(),
+                aux: self.rdb_aux(),
+            },
+            rdb: OnceLock::new(),
+        });
+        let mut replicas: usize = 0;
+        for replica in self.slave_servers.iter_mut() {
+            if replica.state == ReplicaState::WaitBgsaveStart {
+                replica.state = ReplicaState::WaitBgsave;
+                replica.diskless = Some(Arc::clone(&sync));
+                replica.start_on_ack = true;
+                replicas += 1;
+            }
+        }
+        // make sure the new slaves are told which database the stream writes to
+        self.repl_seldb = None;
+        println!(
+            "Starting diskless sync for {} replicas at offset {}",
+            replicas, sync.offset
+        );
+    }
+
+    /*
+    The diskless sync the replica of `client_id` is part of, once it
+    started. An error if the replica is gone meanwhile.
+    */
+    pub fn replica_diskless_sync(
+        &self,
+        client_id: u64,
+    ) -> Result<Option<Arc<DisklessSync>>, String> {
+        match self.slave_servers.iter().find(|r| r.client_id == client_id) {
+            Some(replica) => Ok(replica.diskless.clone()),
+            None => Err("Replica disconnected before its sync started".to_string()),
+        }
+    }
+
+    fn attach_replica(&mut self, replica: Replica) {
+        println!(
+            "Replica {} asks for synchronization (capa: {})",
+            replica.name(),
+            replica.capa.join(" ")
+        );
+        self.slave_servers
+            .retain(|r| r.client_id != replica.client_id);
+        self.slave_servers.push(replica);
+    }
+
+    /*
+    The snapshot is serialized and about to be sent.
+    */
+    pub fn replica_send_bulk(&mut self, client_id: u64) {
+        if let Some(replica) = self
+            .slave_servers
+            .iter_mut()
+            .find(|r| r.client_id == client_id)
+        {
+            replica.state = ReplicaState::SendBulk;
+        }
+    }
+
+    /*
+    Forgets a replica whose connection closed.
+    */
+    pub fn client_closed(&mut self, client_id: u64) {
+        if let Some(idx) = self
+            .slave_servers
+            .iter()
+            .position(|r| r.client_id == client_id)
+        {
+            let replica: Replica = self.slave_servers.remove(idx);
+            println!("Connection with replica {} lost.", replica.name());
+        }
+    }
+
+    /*
+    The connected_slaves and slaveN lines of INFO replication. The lag is
+    the number of seconds since the replica last acknowledged.
+    */
+    pub(super) fn info_replicas(&self) -> Vec<String> {
+        let mut lines: Vec<String> = vec![format!("connected_slaves:{}", self.slave_servers.len())];
+        for (idx, replica) in self.slave_servers.iter().enumerate() {
+            lines.push(format!(
+                "slave{}:ip={},port={},state={},offset={},lag={}",
+                idx,
+                replica.addr,
+                replica.listening_port,
+                replica.state.as_str(),
+                replica.ack_offset,
+                replica.ack_time.elapsed().as_secs()
+            ));
+        }
+        lines
+    }
+
+    /*
+    The backlog lines of INFO replication.
+    */
+    pub(super) fn info_repl_backlog(&self, repl_offset: u64) -> Vec<String> {
+        let (active, first, histlen): (u8, u64, usize) = match &self.repl_backlog {
+            Some(backlog) => (1, backlog.first_byte_offset(repl_offset), backlog.histlen),
+            None => (0, 0, 0),
+        };
+        vec![
+            format!("repl_backlog_active:{}", active),
+            format!("repl_backlog_size:{}", self.config.repl_backlog_size),
+            format!("repl_backlog_first_byte_offset:{}", first),
+            format!("repl_backlog_histlen:{}", histlen),
+        ]
+    }
+
+    /*
+    Whether a replica that asked to continue history `replid` from offset
+    `psync_offset` can be served from the backlog.
+    */
+    pub(super) fn can_partial_resync(&self, replid: &str, psync_offset: i64) -> bool {
+        let (Some(master_replid), Some(repl_offset)) =
+            (&self.replication_id, self.replication_offset)
+        else {
+            return false;
+        };
+        // the previous id is only good up to where our own history began
+        let known: bool = replid == master_replid
+            || self
+                .replication_id2
+                .as_ref()
+                .is_some_and(|(replid2, offset)| {
+                    replid == replid2 && psync_offset as u64 <= *offset
+                });
+        if !known {
+            if replid != "?" {
+                println!(
+                    "Partial resynchronization not accepted: replication ID mismatch (asked for '{}', mine is '{}')",
+                    replid, master_replid
+                );
+            }
+            return false;
+        }
+        let in_backlog: bool = psync_offset >= 0
+            && self
+                .repl_backlog
+                .as_ref()
+                .is_some_and(|backlog| backlog.since(psync_offset as u64, repl_offset).is_some());
+        if !in_backlog {
+            println!(
+                "Unable to partial resync with replica: lack of backlog (replica request was: {})",
+                psync_offset
+            );
+        }
+        in_backlog
+    }
+
+    /*
+    Attaches `stream` as a replica after a CONTINUE reply, with the part of
+    the backlog it is missing queued until `replica_online`, just like the
+    writes that happen before the reply is out.
+    */
+    pub fn start_partial_resync(&mut self, client: &Client, stream: TcpStream, psync_offset: u64) {
+        let repl_offset: u64 = self.replication_offset.unwrap_or_default();
+        let missing: Vec<u8> = self
+            .repl_backlog
+            .as_ref()
+            .and_then(|backlog| backlog.since(psync_offset, repl_offset))
+            .unwrap_or_default();
+        println!(
+            "Partial resynchronization request accepted. Sending {} bytes of backlog starting from offset {}.",
+            missing.len(),
+            psync_offset
+        );
+        let mut replica = Replica::new(client, stream, missing);
+        replica.state = ReplicaState::SendBulk;
+        self.attach_replica(replica);
+    }
+
+    /*
+    Called once the RDB payload (or the CONTINUE reply) is out: flushes what
+    was buffered meanwhile and switches the replica to the live write stream.
+    */
+    pub fn replica_online(&mut self, client_id: u64) {
+        if let Some(replica) = self
+            .slave_servers
+            .iter_mut()
+            .find(|r| r.client_id == client_id && !r.start_on_ack)
+        {
+            replica.diskless = None;
+            let pending: Vec<u8> = std::mem::take(&mut replica.pending);
+            replica.state = ReplicaState::Online;
+            if let Err(e) = replica.stream.write_all(&pending) {
+                eprintln!("Error writing to replica {}: {}", replica.name(), e);
+                self.client_closed(client_id);
+            }
+        }
+    }
+}
+
+impl ServerState {
+    /*
+    REPLCONF ACK offset [FACK aof-offset], sent by a replica on its
+    replication link. It gets no reply.
+    */
+    pub(super) fn replica_ack(&mut self, arr: &[RespType]) {
+        let offset: Option<u64> = arg_i64(arr, 2).ok().map(|o| o.max(0) as u64);
+        let aof_offset: Option<u64> = match arg_string(arr, 3) {
+            Ok(option) if option.eq_ignore_ascii_case("fack") => {
+                arg_i64(arr, 4).ok().map(|o| o.max(0) as u64)
+            }
+            _ => None,
+        };
+        let client_id: u64 = self.client.id;
+        if let Some(replica) = self
+            .slave_servers
+            .iter_mut()
+            .find(|r| r.client_id == client_id)
+        {
+            if let Some(offset) = offset {
+                replica.ack_offset = replica.ack_offset.max(offset);
+            }
+            if let Some(aof_offset) = aof_offset {
+                replica.aof_ack_offset = replica.aof_ack_offset.max(aof_offset);
+            }
+            replica.ack_time = Instant::now();
+            if std::mem::take(&mut replica.start_on_ack) {
+                // it loaded the RDB of its diskless sync
+                self.replica_online(client_id);
+            }
+        }
+    }
+
+    /*
+    WAIT numreplicas timeout
+    Replies with the number of replicas that acknowledged the client's last
+    write, as soon as there are `numreplicas` of them or when `timeout`
+    (milliseconds, 0 for never) expires.
+    */
+    pub(super) fn handle_wait(&mut self, arr: Vec<RespType>) -> RespType {
+        if arr.len() != 3 {
+            return wrong_arity("wait");
+        }
+        if self.replica_of.is_some() {
+            return RespType::Error(
+                "ERR WAIT cannot be used with replica instances. Please also note that since Redis 4.0 if a replica is configured to be writable (which is not the default) writes to replicas are just local and are not propagated."
+                    .to_string(),
+            );
+        }
+        let numreplicas: i64 = match arg_i64(&arr, 1) {
+            Ok(n) => n,
+            Err(e) => return e,
+        };
+        let deadline: Option<Instant> = match Self::arg_wait_timeout(&arr, 2) {
+            Ok(deadline) => deadline,
+            Err(e) => return e,
+        };
+        self.block_for_acks(WaitRequest {
+            aof: false,
+            numlocal: 0,
+            numreplicas,
+            offset: self.client.woff,
+            deadline,
+        })
+    }
+
+    /*
+    WAITAOF numlocal numreplicas timeout
+    Like WAIT, but for the client's last write to be fsynced to the AOF, here
+    (numlocal is 0 or 1) and on replicas. Replies with both counts.
+    */
+    pub(super) fn handle_waitaof(&mut self, arr: Vec<RespType>) -> RespType {
+        if arr.len() != 4 {
+            return wrong_arity("waitaof");
+        }
+        if self.replica_of.is_some() {
+            return RespType::Error(
+                "ERR WAITAOF cannot be used with replica instances. Please also note that writes to replicas are just local and are not propagated."
+                    .to_string(),
+            );
+        }
+        let numlocal: i64 = match arg_i64(&arr, 1) {
+            Ok(n) => n,
+            Err(e) => return e,
+        };
+        let numreplicas: i64 = match arg_i64(&arr, 2) {
+            Ok(n) => n,
+            Err(e) => return e,
+        };
+        let deadline: Option<Instant> = match Self::arg_wait_timeout(&arr, 3) {
+            Ok(deadline) => deadline,
+            Err(e) => return e,
+        };
+        if numlocal > 0 && self.aof_fsynced_reploff().is_none() {
+            return RespType::Error(
+                "ERR WAITAOF cannot be used when numlocal is set but appendonly is disabled."
+                    .to_string(),
+            );
+        }
+        self.block_for_acks(WaitRequest {
+            aof: true,
+            numlocal,
+            numreplicas,
+            offset: self.client.woff,
+            deadline,
+        })
+    }
+
+    fn arg_wait_timeout(arr: &[RespType], idx: usize) -> Result<Option<Instant>, RespType> {
+        match arg_i64(arr, idx)? {
+            ms if ms < 0 => Err(RespType::Error("ERR timeout is negative".to_string())),
+            0 => Ok(None),
+            ms => Ok(Some(Instant::now() + Duration::from_millis(ms as u64))),
+        }
+    }
+
+    /*
+    Replies right away if the request is already satisfied. Otherwise asks
+    the replicas for their offsets and leaves the reply to the connection
+    thread, which polls `wait_reply` until there is one.
+    */
+    fn block_for_acks(&mut self, request: WaitRequest) -> RespType {
+        if let Some(reply) = self.wait_reply(&request, false) {
+            return reply;
+        }
+        let getack: RespType = RespType::Array(vec![
+            RespType::BulkString("REPLCONF".to_string()),
+            RespType::BulkString("GETACK".to_string()),
+            RespType::BulkString("*".to_string()),
+        ]);
+        self.feed_replicas(getack.to_resp_string().as_bytes());
+        self.client.wait = Some(request);
+        RespType::NullBulkString
+    }
+
+    /*
+    The reply to a blocked WAIT or WAITAOF, once enough acknowledgements came
+    in or, with `timed_out`, with whatever came in.
+    */
+    pub fn wait_reply(&self, request: &WaitRequest, timed_out: bool) -> Option<RespType> {
+        let replicas: i64 = self
+            .slave_servers
+            .iter()
+            .filter(|r| r.state == ReplicaState::Online)
+            .filter(|r| {
+                let acked: u64 = if request.aof {
+                    r.aof_ack_offset
+                } else {
+                    r.ack_offset
+                };
+                acked >= request.offset
+            })
+            .count() as i64;
+        let local: i64 = self
+            .aof_fsynced_reploff()
+            .is_some_and(|offset| offset >= request.offset) as i64;
+        let done: bool =
+            replicas >= request.numreplicas && (!request.aof || local >= request.numlocal);
+        if !done && !timed_out {
+            return None;
+        }
+        if request.aof {
+            Some(RespType::Array(vec![
+                RespType::Integer(local),
+                RespType::Integer(replicas),
+            ]))
+        } else {
+            Some(RespType::Integer(replicas))
+        }
+    }
+}
+
+impl ServerState {
+    /*
+    REPLICAOF host port | NO ONE
+    SLAVEOF host port | NO ONE
+    Starts following another master, or promotes this replica to a master.
+    The link itself belongs to the master link thread (see master_link.rs),
+    which notices the change: the current link is shut down here.
+    */
+    pub(super) fn handle_replicaof(&mut self, arr: Vec<RespType>, cmd: &str) -> RespType {
+        if arr.len() != 3 {
+            return wrong_arity(cmd);
+        }
+        let host: String = match arg_string(&arr, 1) {
+            Ok(s) => s,
+            Err(e) => return e,
+        };
+        let port: String = match arg_string(&arr, 2) {
+            Ok(s) => s,
+            Err(e) => return e,
+        };
+        if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+            if self.replica_of.is_some() {
+                self.promote_to_master();
+                println!("MASTER MODE enabled (user request)");
+            }
+            return RespType::SimpleString("OK".to_string());
+        }
+
+        let port: u16 = match arg_i64(&arr, 2) {
+            Ok(port) if (0..=65535).contains(&port) => port as u16,
+            Ok(_) => return RespType::Error("ERR Invalid master port".to_string()),
+            Err(e) => return e,
+        };
+        let master = ServerAddr::new(host, port);
+        if self.replica_of.as_ref() == Some(&master) {
+            return RespType::SimpleString("OK Already connected to specified master".to_string());
+        }
+        println!(
+            "REPLICAOF {}:{} enabled (user request)",
+            master._ip, master._port
+        );
+        self.replica_of = Some(master.clone());
+        self.config.replica_of = Some(master);
+        self.close_master_link();
+        self.set_repl_state(ReplState::Connect);
+        // our replicas stay: they get the new master's stream through us,
+        // unless it starts a new history (see `master_full_resync`)
+        RespType::SimpleString("OK".to_string())
+    }
+
+    /*
+    REPLICAOF NO ONE: stops replicating and starts a new history, keeping the
+    master's one as secondary so that the other replicas can continue from
+    us (PSYNC2).
+    */
+    fn promote_to_master(&mut self) {
+        self.replica_of = None;
+        self.config.replica_of = None;
+        self.close_master_link();
+
+        let offset: u64 = self.replication_offset.unwrap_or_default();
+        if let Some(old) = self.replication_id.take() {
+            self.replication_id2 = Some((old, offset + 1));
+        }
+        self.replication_id = Some(random_replid());
+        self.replication_offset = Some(offset);
+        // make sure our own stream starts by telling the database
+        self.repl_seldb = None;
+        // our replicas have to learn the new id, they can continue with it
+        self.disconnect_replicas();
+    }
+
+    /*
+    Drops every replica, so that they sync again and learn about a new
+    history.
+    */
+    fn disconnect_replicas(&mut self) {
+        for replica in self.slave_servers.drain(..) {
+            println!("Disconnecting replica {}", replica.name());
+            let _ = replica.stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn close_master_link(&mut self) {
+        if let Some(stream) = self.master_link.take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    /*
+    Replica side: whether `master` is still the one to replicate from. The
+    master link thread checks it whenever it takes the lock, so that nothing
+    from a link REPLICAOF replaced gets applied.
+    */
+    pub fn following(&self, master: &ServerAddr) -> bool {
+        self.replica_of.as_ref() == Some(master)
+    }
+
+    /*
+    Replica side: the link with `master` is connected; keep a handle on it
+    so REPLICAOF can shut it down.
+    */
+    pub fn master_link_connected(
+        &mut self,
+        master: &ServerAddr,
+        stream: &TcpStream,
+    ) -> Result<(), String> {
+        if !self.following(master) {
+            return Err("REPLICAOF changed the master".to_string());
+        }
+        let stream: TcpStream = stream
+            .try_clone()
+            .map_err(|e| format!("Failed to clone the master link: {}", e))?;
+        self.master_link = Some(stream);
+        Ok(())
+    }
+
+    /*
+    Replica side: the link with `master` is gone. Returns false if that's
+    because REPLICAOF moved on to another master (or promoted us).
+    */
+    pub fn master_link_closed(&mut self, master: &ServerAddr) -> bool {
+        if !self.following(master) {
+            return false;
+        }
+        self.master_link = None;
+        self.set_repl_state(ReplState::Connect);
+        true
+    }
+
+    /*
+    Replica side: refuses writes from clients other than the master
+    (replica-read-only), and anything but a few administrative commands
+    while the link is down (unless replica-serve-stale-data). The AOF
+    replayed at startup is trusted like the master.
+    */
+    pub(super) fn check_replica_access(&self, flags: u32) -> Result<(), RespType> {
+        if self.replica_of.is_none() || self.client.master || self.loading {
+            return Ok(());
+        }
+        if self.config.replica_read_only && flags & CMD_WRITE != 0 {
+            return Err(RespType::Error(
+                "READONLY You can't write against a read only replica.".to_string(),
+            ));
+        }
+        if !self.config.replica_serve_stale_data
+            && self.repl_state != ReplState::Connected
+            && flags & CMD_STALE == 0
+        {
+            return Err(RespType::Error(
+                "MASTERDOWN Link with MASTER is down and replica-serve-stale-data is set to 'no'."
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /*
+    Replica side: moves the link with the master to `state`, remembering when
+    it went down.
+    */
+    pub fn set_repl_state(&mut self, state: ReplState) {
+        if state == ReplState::Connected {
+            self.repl_down_since = None;
+        } else if self.repl_down_since.is_none() {
+            self.repl_down_since = Some(Instant::now());
+        }
+        self.repl_state = state;
+    }
+
+    /*
+    Replica side: the lines of INFO replication about the master link.
+    */
+    pub(super) fn info_master_link(&self, master: &ServerAddr) -> Vec<String> {
+        let up: bool = self.repl_state == ReplState::Connected;
+        let mut lines: Vec<String> = vec![
+            format!("master_host:{}", master._ip),
+            format!("master_port:{}", master._port),
+            format!("master_link_status:{}", if up { "up" } else { "down" }),
+            format!(
+                "master_sync_in_progress:{}",
+                (self.repl_state == ReplState::Transfer) as u8
+            ),
+        ];
+        if let Some(since) = self.repl_down_since {
+            lines.push(format!(
+                "master_link_down_since_seconds:{}",
+                since.elapsed().as_secs()
+            ));
+        }
+        lines
+    }
+
+    /*
+    Replica side: the arguments of PSYNC, to continue the master's history
+    where we are, or "? -1" when we have none and need a full resync.
+    */
+    pub fn psync_request(&self) -> (String, String) {
+        match (&self.replication_id, self.replication_offset) {
+            (Some(replid), Some(offset)) => (replid.clone(), (offset + 1).to_string()),
+            _ => ("?".to_string(), "-1".to_string()),
+        }
+    }
+
+    /*
+    Replica side: FULLRESYNC tells us the master's replication id and the
+    offset its snapshot corresponds to.
+    */
+    pub fn master_full_resync(&mut self, replid: String, offset: u64) {
+        self.replication_id = Some(replid);
+        self.replication_offset = Some(offset);
+        self.replication_id2 = None;
+        // the stream we keep has to start over with the new history, and so
+        // do our own replicas
+        self.repl_backlog = Some(ReplBacklog::new(self.config.repl_backlog_size));
+        self.disconnect_replicas();
+    }
+
+    /*
+    Replica side: the master accepted to continue. If it answered with
+    another replication id (it was promoted since), our history so far is
+    also part of the new one.
+    */
+    pub fn master_continue(&mut self, replid: &str) {
+        if self.repl_backlog.is_none() {
+            self.repl_backlog = Some(ReplBacklog::new(self.config.repl_backlog_size));
+        }
+        if replid.is_empty() || self.replication_id.as_deref() == Some(replid) {
+            return;
+        }
+        let offset: u64 = self.replication_offset.unwrap_or_default();
+        if let Some(old) = self.replication_id.replace(replid.to_string()) {
+            self.replication_id2 = Some((old, offset + 1));
+        }
+        // our replicas have to learn the new id, they can continue too
+        self.disconnect_replicas();
+    }
+
+    /*
+    Replica side: a command from the master's stream was applied, leaving
+    database `db` selected. Its bytes are forwarded as they are: to our
+    backlog, so that once promoted we can serve partial resyncs to the other
+    replicas of our master, and to our own replicas, so that the whole chain
+    shares the master's history and offsets.
+    */
+    pub fn master_stream_consumed(&mut self, bytes: &[u8], db: usize) {
+        self.master_stream_db = db;
+        self.feed_replicas(bytes);
+    }
+
+    /*
+    Replica side: the database the master's stream has selected.
+    */
+    pub fn master_stream_db(&self) -> usize {
+        self.master_stream_db
+    }
+
+    /*
+    Replica side: where to save the RDB of a full sync before loading it, or
+    None to load it straight into memory (repl-diskless-load).
+    */
+    pub fn master_rdb_file(&self) -> Option<PathBuf> {
+        let diskless: bool = match self.config.repl_diskless_load {
+            ReplDisklessLoad::Disabled => false,
+            ReplDisklessLoad::OnEmptyDb => self.dbs.iter().all(|db| db.is_empty()),
+            ReplDisklessLoad::Swapdb => true,
+        };
+        (!diskless).then(|| self.config.rdb_path())
+    }
+
+    /*
+    Replica side: swaps in a new keyspace loaded from the RDB the master sent
+    after FULLRESYNC, parsed beforehand without holding the lock. Returns the
+    number of keys loaded, and the old keyspace to be dropped by the caller
+    outside the lock. On error the old keyspace stays.
+    */
+    pub fn load_master_rdb(&mut self, rdb: Rdb) -> Result<(usize, Vec<Database>), String> {
+        // a replica's snapshot tells where its forwarded stream is
+        let stream_db: usize = rdb
+            .aux
+            .iter()
+            .find(|(key, _)| key == "repl-stream-db")
+            .and_then(|(_, value)| value.parse().ok())
+            .filter(|db| *db < self.dbs.len())
+            .unwrap_or(0);
+        let fresh: Vec<Database> = (0..self.dbs.len()).map(|_| Database::new()).collect();
+        let old: Vec<Database> = std::mem::replace(&mut self.dbs, fresh);
+        match self.load_rdb(rdb) {
+            Ok(keys) => {
+                self.master_stream_db = stream_db;
+                Ok((keys, old))
+            }
+            Err(e) => {
+                self.dbs = old;
+                Err(e)
+            }
+        }
+    }
+
+    /*
+    Takes back the replication id and offset saved with the RDB file loaded
+    at startup (or the RDB preamble of the AOF base).
+
+    A master may have gone past the saved offset before it stopped, with
+    writes that are lost now. So the saved history is only ours up to the
+    offset, as the previous id (PSYNC2), and goes on under a new id: the
+    replicas that are exactly there continue from an empty backlog, those
+    that went further need a full sync. A replica asks its master to
+    continue from there.
+    */
+    pub(super) fn restore_replication_info(&mut self, aux: &[(String, String)]) {
+        let field = |name: &str| {
+            aux.iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        };
+        let replid: &str = match field("repl-id") {
+            Some(replid) if replid.len() == 40 => replid,
+            _ => return,
+        };
+        let Some(offset) = field("repl-offset").and_then(|v| v.parse::<u64>().ok()) else {
+            return;
+        };
+        self.replication_offset = Some(offset);
+        match self.replica_of {
+            None => {
+                self.replication_id = Some(random_replid());
+                self.replication_id2 = Some((replid.to_string(), offset + 1));
+                self.repl_backlog = Some(ReplBacklog::new(self.config.repl_backlog_size));
+            }
+            Some(_) => {
+                self.replication_id = Some(replid.to_string());
+                self.replication_id2 = None;
+                self.master_stream_db = field("repl-stream-db")
+                    .and_then(|v| v.parse::<usize>().ok())
+                    .filter(|db| *db < self.dbs.len())
+                    .unwrap_or(0);
+            }
+        }
+        println!(
+            "Restored replication ID {} and offset {} from the RDB file",
+            replid, offset
+        );
+    }
+
+    /*
+    Forgets what `restore_replication_info` took from an AOF base once the
+    incremental files replayed writes over it: the dataset is past the saved
+    offset by an amount we can't tell, so it starts a new history.
+    */
+    pub(super) fn discard_replication_info(&mut self) {
+        match self.replica_of {
+            None => {
+                self.replication_id = Some(random_replid());
+                self.replication_offset = Some(0);
+            }
+            Some(_) => {
+                self.replication_id = None;
+                self.replication_offset = None;
+                self.master_stream_db = 0;
+            }
+        }
+        self.replication_id2 = None;
+        self.repl_backlog = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::{Clock, ManualClock},
+        config::Config,
+        parser::parse_resp_prefix,
+    };
+    use crate::{rdb::RdbWriter, test_dir::TempDir};
+    use std::{fs, net::TcpListener};
+
+    fn bulk(s: &str) -> RespType {
+        RespType::BulkString(s.to_string())
+    }
+
+    /*
+    The commands held in the backlog, parsed back.
+    */
+    fn backlog_commands(state: &ServerState) -> Vec<RespType> {
+        let offset: u64 = state.get_replication_offset();
+        let stream: Vec<u8> = state
+            .repl_backlog
+            .as_ref()
+            .and_then(|backlog| backlog.since(1, offset))
+            .unwrap();
+        assert_eq!(stream.len() as u64, offset);
+        let mut commands: Vec<RespType> = Vec::new();
+        let mut pos: usize = 0;
+        while pos < stream.len() {
+            let (cmd, size) = parse_resp_prefix(&stream[pos..]).unwrap().unwrap();
+            commands.push(cmd);
+            pos += size;
+        }
+        commands
+    }
+
+    #[test]
+    fn propagated_commands_keep_empty_and_binary_arguments() {
+        let mut state = ServerState::new(Config::default());
+        state.repl_backlog = Some(ReplBacklog::new(1024));
+        let empty: Vec<RespType> = vec![bulk("SET"), bulk("k"), bulk("")];
+        let binary: Vec<RespType> = vec![
+            bulk("SET"),
+            bulk("b"),
+            RespType::BulkBytes(vec![0xff, b'\r', b'\n', 0xfe]),
+        ];
+        state.propagate(empty.clone());
+        state.propagate(binary.clone());
+        assert_eq!(
+            backlog_commands(&state),
+            vec![
+                RespType::Array(vec![bulk("SELECT"), bulk("0")]),
+                RespType::Array(empty),
+                RespType::Array(binary),
+            ]
+        );
+    }
+
+    #[test]
+    fn expired_keys_are_propagated_as_del() {
+        let clock: Arc<ManualClock> = Arc::new(ManualClock::new(1_000_000));
+        let mut state = ServerState::with_clock(Config::default(), clock.clone() as Arc<dyn Clock>);
+        state.repl_backlog = Some(ReplBacklog::new(1024));
+        let mut client = Client::new();
+        let mut run = |state: &mut ServerState, args: &[&str]| {
+            let cmd: Vec<RespType> = args.iter().map(|arg| bulk(arg)).collect();
+            state.execute(&mut client, RespType::Array(cmd));
+        };
+        run(&mut state, &["SET", "lazy", "v", "PX", "100"]);
+        run(&mut state, &["SELECT", "2"]);
+        run(&mut state, &["SET", "active", "v", "PX", "100"]);
+        run(&mut state, &["SELECT", "0"]);
+
+        clock.advance(101);
+        run(&mut state, &["GET", "lazy"]);
+        state.active_expire_cycle();
+
+        let set = |key: &str| {
+            RespType::Array(vec![
+                bulk("SET"),
+                bulk(key),
+                bulk("v"),
+                bulk("PXAT"),
+                bulk("1000100"),
+            ])
+        };
+        assert_eq!(
+            backlog_commands(&state),
+            vec![
+                RespType::Array(vec![bulk("SELECT"), bulk("0")]),
+                set("lazy"),
+                RespType::Array(vec![bulk("SELECT"), bulk("2")]),
+                set("active"),
+                RespType::Array(vec![bulk("SELECT"), bulk("0")]),
+                RespType::Array(vec![bulk("DEL"), bulk("lazy")]),
+                RespType::Array(vec![bulk("SELECT"), bulk("2")]),
+                RespType::Array(vec![bulk("DEL"), bulk("active")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn the_backlog_keeps_the_last_bytes_across_wraps() {
+        let mut backlog = ReplBacklog::new(8);
+        assert_eq!(backlog.first_byte_offset(0), 1);
+        assert_eq!(backlog.since(1, 0), Some(vec![]));
+
+        backlog.feed(b"abcdef");
+        assert_eq!(backlog.first_byte_offset(6), 1);
+        assert_eq!(backlog.since(1, 6).unwrap(), b"abcdef");
+        // this one wraps around the end of the buffer
+        backlog.feed(b"ghij");
+        assert_eq!(backlog.histlen, 8);
+        assert_eq!(backlog.first_byte_offset(10), 3);
+        assert_eq!(backlog.since(3, 10).unwrap(), b"cdefghij");
+        assert_eq!(backlog.since(7, 10).unwrap(), b"ghij");
+        assert_eq!(backlog.since(10, 10).unwrap(), b"j");
+        // a replica that has everything continues with nothing to catch up
+        assert_eq!(backlog.since(11, 10), Some(vec![]));
+        // older than what is held, or past what was ever written
+        assert_eq!(backlog.since(2, 10), None);
+        assert_eq!(backlog.since(12, 10), None);
+
+        // a write longer than the whole backlog keeps its end
+        backlog.feed(b"0123456789ABCDEFGHIJ");
+        assert_eq!(backlog.first_byte_offset(30), 23);
+        assert_eq!(backlog.since(23, 30).unwrap(), b"CDEFGHIJ");
+        assert_eq!(backlog.since(29, 30).unwrap(), b"IJ");
+    }
+
+    /*
+    A master 10 bytes into history "aaa…", with a backlog holding them all.
+    */
+    fn master_with_backlog() -> ServerState {
+        let mut state = ServerState::new(Config::default());
+        state.replication_id = Some("a".repeat(40));
+        state.replication_offset = Some(10);
+        let mut backlog = ReplBacklog::new(1024);
+        backlog.feed(b"0123456789");
+        state.repl_backlog = Some(backlog);
+        state
+    }
+
+    #[test]
+    fn a_partial_resync_needs_a_known_history_and_the_bytes_from_there() {
+        let mut state: ServerState = master_with_backlog();
+        let id: String = "a".repeat(40);
+        assert!(state.can_partial_resync(&id, 1));
+        assert!(state.can_partial_resync(&id, 5));
+        assert!(state.can_partial_resync(&id, 11));
+        assert!(!state.can_partial_resync(&id, 12));
+        assert!(!state.can_partial_resync(&id, 0));
+        assert!(!state.can_partial_resync(&id, -1));
+        assert!(!state.can_partial_resync(&"b".repeat(40), 5));
+        assert!(!state.can_partial_resync("?", -1));
+
+        // the backlog was trimmed
+        let mut backlog = ReplBacklog::new(4);
+        backlog.feed(b"0123456789");
+        state.repl_backlog = Some(backlog);
+        assert!(state.can_partial_resync(&id, 7));
+        assert!(!state.can_partial_resync(&id, 6));
+
+        state.repl_backlog = None;
+        assert!(!state.can_partial_resync(&id, 11));
+    }
+
+    #[test]
+    fn the_previous_history_is_only_good_up_to_where_it_ended() {
+        let mut state: ServerState = master_with_backlog();
+        let old: String = "o".repeat(40);
+        // we left the old history after its 6th byte
+        state.replication_id2 = Some((old.clone(), 7));
+        assert!(state.can_partial_resync(&old, 1));
+        assert!(state.can_partial_resync(&old, 7));
+        // the old master may have written more, that we never had
+        assert!(!state.can_partial_resync(&old, 8));
+        assert!(!state.can_partial_resync(&old, 11));
+        assert!(state.can_partial_resync(&"a".repeat(40), 11));
+    }
+
+    fn run_as(state: &mut ServerState, client: &mut Client, args: &[&str]) -> RespType {
+        let cmd: Vec<RespType> = args.iter().map(|arg| bulk(arg)).collect();
+        state.execute(client, RespType::Array(cmd))
+    }
+
+    /*
+    Attaches a replica that acknowledged `ack_offset`, on a loopback
+    connection nobody reads: what it is sent just stays in the socket.
+    */
+    fn attach(
+        state: &mut ServerState,
+        listener: &TcpListener,
+        replica_state: ReplicaState,
+        ack_offset: u64,
+    ) -> u64 {
+        let stream: TcpStream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let client: Client = Client::new();
+        let mut replica = Replica::new(&client, stream, Vec::new());
+        replica.state = replica_state;
+        replica.ack_offset = ack_offset;
+        replica.aof_ack_offset = ack_offset;
+        state.slave_servers.push(replica);
+        client.id
+    }
+
+    fn ack(state: &mut ServerState, client_id: u64, offset: u64) {
+        let replica: &mut Replica = state
+            .slave_servers
+            .iter_mut()
+            .find(|r| r.client_id == client_id)
+            .unwrap();
+        replica.ack_offset = offset;
+        replica.aof_ack_offset = offset;
+    }
+
+    #[test]
+    fn wait_counts_the_online_replicas_that_reached_the_last_write() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut state = ServerState::new(Config::default());
+        let mut client = Client::new();
+        run_as(&mut state, &mut client, &["SET", "k", "v"]);
+        let woff: u64 = client.woff;
+        assert_eq!(woff, state.get_replication_offset());
+
+        attach(&mut state, &listener, ReplicaState::Online, woff);
+        let behind: u64 = attach(&mut state, &listener, ReplicaState::Online, woff - 1);
+        // still syncing: it may have acked, but doesn't count yet
+        attach(&mut state, &listener, ReplicaState::WaitBgsave, woff);
+
+        assert_eq!(
+            run_as(&mut state, &mut client, &["WAIT", "1", "0"]),
+            RespType::Integer(1)
+        );
+        assert!(client.wait.is_none());
+
+        assert_eq!(
+            run_as(&mut state, &mut client, &["WAIT", "2", "100"]),
+            RespType::NullBulkString
+        );
+        let request: WaitRequest = client.wait.take().unwrap();
+        assert_eq!(request.offset, woff);
+        // the replicas were asked for their offsets, which moves the stream
+        assert!(state.get_replication_offset() > woff);
+        assert_eq!(state.wait_reply(&request, false), None);
+        assert_eq!(state.wait_reply(&request, true), Some(RespType::Integer(1)));
+
+        ack(&mut state, behind, woff);
+        assert_eq!(
+            state.wait_reply(&request, false),
+            Some(RespType::Integer(2))
+        );
+        // the GETACK is not a write of the client's
+        assert_eq!(
+            run_as(&mut state, &mut client, &["WAIT", "2", "0"]),
+            RespType::Integer(2)
+        );
+    }
+
+    #[test]
+    fn waitaof_counts_local_fsyncs_only_with_appendonly() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut state = ServerState::new(Config::default());
+        let mut client = Client::new();
+        run_as(&mut state, &mut client, &["SET", "k", "v"]);
+        let woff: u64 = client.woff;
+        attach(&mut state, &listener, ReplicaState::Online, woff);
+
+        assert_eq!(
+            run_as(&mut state, &mut client, &["WAITAOF", "1", "0", "0"]),
+            RespType::Error(
+                "ERR WAITAOF cannot be used when numlocal is set but appendonly is disabled."
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            run_as(&mut state, &mut client, &["WAITAOF", "0", "1", "0"]),
+            RespType::Array(vec![RespType::Integer(0), RespType::Integer(1)])
+        );
+        assert_eq!(
+            run_as(&mut state, &mut client, &["WAITAOF", "0", "2", "50"]),
+            RespType::NullBulkString
+        );
+        let request: WaitRequest = client.wait.take().unwrap();
+        assert!(request.aof);
+        assert_eq!(
+            state.wait_reply(&request, true),
+            Some(RespType::Array(vec![
+                RespType::Integer(0),
+                RespType::Integer(1)
+            ]))
+        );
+    }
+
+    #[test]
+    fn wait_arguments_are_checked() {
+        let mut state = ServerState::new(Config::default());
+        let mut client = Client::new();
+        assert_eq!(
+            run_as(&mut state, &mut client, &["WAIT", "0", "-1"]),
+            RespType::Error("ERR timeout is negative".to_string())
+        );
+        assert_eq!(
+            run_as(&mut state, &mut client, &["WAIT", "0", "0"]),
+            RespType::Integer(0)
+        );
+
+        state.replica_of = Some(ServerAddr::new("127.0.0.1".to_string(), 1));
+        let RespType::Error(e) = run_as(&mut state, &mut client, &["WAIT", "0", "0"]) else {
+            panic!("WAIT was accepted on a replica");
+        };
+        assert!(e.starts_with("ERR WAIT cannot be used with replica instances"));
+    }
+
+    #[test]
+    fn replicaof_no_one_keeps_the_masters_history_as_the_previous_one() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let master = ServerAddr::new("127.0.0.1".to_string(), 1);
+        let config = Config {
+            replica_of: Some(master),
+            ..Config::default()
+        };
+        let mut state = ServerState::new(config);
+        let old: String = "m".repeat(40);
+        state.master_full_resync(old.clone(), 100);
+        state.master_stream_consumed(b"0123456789", 0);
+        // a replica of ours
+        attach(&mut state, &listener, ReplicaState::Online, 110);
+
+        let mut client = Client::new();
+        assert_eq!(
+            run_as(&mut state, &mut client, &["REPLICAOF", "NO", "ONE"]),
+            RespType::SimpleString("OK".to_string())
+        );
+        assert!(state.replica_of.is_none());
+        assert_eq!(state.replication_id2, Some((old.clone(), 111)));
+        let new: String = state.replication_id.clone().unwrap();
+        assert_ne!(new, old);
+        assert_eq!(state.get_replication_offset(), 110);
+        // they have to learn the new id
+        assert!(state.slave_servers.is_empty());
+
+        // the other replicas of the old master continue from us
+        assert!(state.can_partial_resync(&old, 111));
+        assert!(state.can_partial_resync(&old, 105));
+        assert!(!state.can_partial_resync(&old, 112));
+        assert!(state.can_partial_resync(&new, 111));
+
+        // and it is a master now
+        assert_eq!(
+            run_as(&mut state, &mut client, &["SET", "k", "v"]),
+            RespType::SimpleString("OK".to_string())
+        );
+        // a second REPLICAOF NO ONE changes nothing
+        run_as(&mut state, &mut client, &["REPLICAOF", "NO", "ONE"]);
+        assert_eq!(state.replication_id, Some(new));
+    }
+
+    #[test]
+    fn a_master_turned_replica_asks_to_continue_its_own_history() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut state = ServerState::new(Config::default());
+        let mut client = Client::new();
+        run_as(&mut state, &mut client, &["SET", "k", "v"]);
+        let id: String = state.replication_id.clone().unwrap();
+        let offset: u64 = state.get_replication_offset();
+        attach(&mut state, &listener, ReplicaState::Online, offset);
+
+        assert_eq!(
+            run_as(&mut state, &mut client, &["REPLICAOF", "127.0.0.1", "6380"]),
+            RespType::SimpleString("OK".to_string())
+        );
+        assert!(state.following(&ServerAddr::new("127.0.0.1".to_string(), 6380)));
+        assert_eq!(state.repl_state, ReplState::Connect);
+        assert_eq!(state.psync_request(), (id, (offset + 1).to_string()));
+        // our replicas stay, they get the new master's stream through us
+        assert_eq!(state.slave_servers.len(), 1);
+        assert_eq!(
+            run_as(&mut state, &mut client, &["REPLICAOF", "127.0.0.1", "6380"]),
+            RespType::SimpleString("OK Already connected to specified master".to_string())
+        );
+        assert_eq!(
+            run_as(
+                &mut state,
+                &mut client,
+                &["REPLICAOF", "127.0.0.1", "70000"]
+            ),
+            RespType::Error("ERR Invalid master port".to_string())
+        );
+    }
+
+    fn replica(config: Config) -> ServerState {
+        let config = Config {
+            replica_of: Some(ServerAddr::new("127.0.0.1".to_string(), 1)),
+            ..config
+        };
+        ServerState::new(config)
+    }
+
+    fn readonly() -> RespType {
+        RespType::Error("READONLY You can't write against a read only replica.".to_string())
+    }
+
+    #[test]
+    fn a_replica_only_takes_writes_from_its_master() {
+        let mut state: ServerState = replica(Config::default());
+        let mut client = Client::new();
+        assert_eq!(
+            run_as(&mut state, &mut client, &["SET", "k", "v"]),
+            readonly()
+        );
+        assert_eq!(run_as(&mut state, &mut client, &["DEL", "k"]), readonly());
+        assert_eq!(
+            run_as(&mut state, &mut client, &["GET", "k"]),
+            RespType::NullBulkString
+        );
+
+        let mut master = Client::new();
+        master.master = true;
+        run_as(&mut state, &mut master, &["SET", "k", "v"]);
+        assert_eq!(
+            run_as(&mut state, &mut client, &["GET", "k"]),
+            RespType::SimpleString("v".to_string())
+        );
+
+        // the AOF replayed at startup is trusted like the master
+        state.loading = true;
+        assert_eq!(
+            run_as(&mut state, &mut client, &["SET", "k", "w"]),
+            RespType::SimpleString("OK".to_string())
+        );
+        state.loading = false;
+
+        let mut state: ServerState = replica(Config {
+            replica_read_only: false,
+            ..Config::default()
+        });
+        assert_eq!(
+            run_as(&mut state, &mut client, &["SET", "k", "v"]),
+            RespType::SimpleString("OK".to_string())
+        );
+    }
+
+    #[test]
+    fn a_replica_without_its_master_can_refuse_to_serve_stale_data() {
+        let masterdown = RespType::Error(
+            "MASTERDOWN Link with MASTER is down and replica-serve-stale-data is set to 'no'."
+                .to_string(),
+        );
+        let mut state: ServerState = replica(Config {
+            replica_serve_stale_data: false,
+            ..Config::default()
+        });
+        let mut client = Client::new();
+        assert_eq!(run_as(&mut state, &mut client, &["GET", "k"]), masterdown);
+        assert!(matches!(
+            run_as(&mut state, &mut client, &["INFO", "replication"]),
+            RespType::BulkString(_)
+        ));
+        let mut master = Client::new();
+        master.master = true;
+        assert_eq!(
+            run_as(&mut state, &mut master, &["GET", "k"]),
+            RespType::NullBulkString
+        );
+
+        state.set_repl_state(ReplState::Connected);
+        assert_eq!(
+            run_as(&mut state, &mut client, &["GET", "k"]),
+            RespType::NullBulkString
+        );
+        state.set_repl_state(ReplState::Connect);
+        assert_eq!(run_as(&mut state, &mut client, &["GET", "k"]), masterdown);
+
+        // by default the replica answers with what it has
+        let mut state: ServerState = replica(Config::default());
+        assert_eq!(
+            run_as(&mut state, &mut client, &["GET", "k"]),
+            RespType::NullBulkString
+        );
+    }
+
+    fn saved_replication_info(replid: &str, offset: u64) -> Vec<(String, String)> {
+        vec![
+            ("repl-stream-db".to_string(), "3".to_string()),
+            ("repl-id".to_string(), replid.to_string()),
+            ("repl-offset".to_string(), offset.to_string()),
+        ]
+    }
+
+    #[test]
+    fn replication_ids_are_40_random_hex_characters() {
+        let id: String = random_replid();
+        assert_eq!(id.len(), 40);
+        assert!(id.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f')));
+        assert_ne!(random_replid(), id);
+    }
+
+    #[test]
+    fn a_restarted_master_continues_its_saved_history_under_a_new_id() {
+        let mut state = ServerState::new(Config::default());
+        let saved: String = "s".repeat(40);
+        state.restore_replication_info(&saved_replication_info(&saved, 500));
+
+        let new: String = state.replication_id.clone().unwrap();
+        assert_ne!(new, saved);
+        assert_eq!(new.len(), 40);
+        assert_eq!(state.replication_id2, Some((saved.clone(), 501)));
+        assert_eq!(state.get_replication_offset(), 500);
+        assert_eq!(state.repl_backlog.as_ref().unwrap().histlen, 0);
+        // only the replicas that are exactly where the file was saved
+        assert!(state.can_partial_resync(&saved, 501));
+        assert!(!state.can_partial_resync(&saved, 500));
+        assert!(!state.can_partial_resync(&saved, 502));
+    }
+
+    #[test]
+    fn a_restarted_replica_asks_to_continue_where_its_file_was_saved() {
+        let mut state: ServerState = replica(Config::default());
+        let saved: String = "s".repeat(40);
+        state.restore_replication_info(&saved_replication_info(&saved, 500));
+        assert_eq!(state.replication_id, Some(saved.clone()));
+        assert_eq!(state.replication_id2, None);
+        assert_eq!(state.master_stream_db(), 3);
+        assert_eq!(state.psync_request(), (saved, "501".to_string()));
+    }
+
+    #[test]
+    fn replication_info_that_is_missing_or_malformed_is_ignored() {
+        let mut state = ServerState::new(Config::default());
+        let id: Option<String> = state.replication_id.clone();
+        state.restore_replication_info(&[]);
+        state.restore_replication_info(&saved_replication_info("short", 500));
+        state.restore_replication_info(&saved_replication_info(&"s".repeat(40), 0)[..2]);
+        let mut bad_offset = saved_replication_info(&"s".repeat(40), 0);
+        bad_offset[2].1 = "-1".to_string();
+        state.restore_replication_info(&bad_offset);
+        assert_eq!(state.replication_id, id);
+        assert_eq!(state.replication_id2, None);
+        assert_eq!(state.get_replication_offset(), 0);
+    }
+
+    /*
+    An AOF whose base was written at offset 500 of history "sss…", with
+    `incr` as its only incremental file.
+    */
+    fn load_aof_with_saved_history(incr: &[u8]) -> ServerState {
+        let dir = TempDir::new();
+        let aof_dir = dir.path().join("appendonlydir");
+        fs::create_dir_all(&aof_dir).unwrap();
+        let mut writer = RdbWriter::new();
+        writer.aux("repl-id", &"s".repeat(40));
+        writer.aux("repl-offset", "500");
+        fs::write(aof_dir.join("appendonly.aof.1.base.rdb"), writer.finish()).unwrap();
+        fs::write(aof_dir.join("appendonly.aof.1.incr.aof"), incr).unwrap();
+        fs::write(
+            aof_dir.join("appendonly.aof.manifest"),
+            "file appendonly.aof.1.base.rdb seq 1 type b\n\
+             file appendonly.aof.1.incr.aof seq 1 type i\n",
+        )
+        .unwrap();
+        let config = Config {
+            dir: dir.path().display().to_string(),
+            appendonly: true,
+            ..Config::default()
+        };
+        let mut state = ServerState::new(config);
+        state.load_data().unwrap();
+        state
+    }
+
+    #[test]
+    fn writes_replayed_after_the_aof_base_start_a_new_history() {
+        let state: ServerState = load_aof_with_saved_history(b"");
+        assert_eq!(state.replication_id2, Some(("s".repeat(40), 501)));
+        assert_eq!(state.get_replication_offset(), 500);
+
+        let set: Vec<u8> = RespType::Array(vec![bulk("SET"), bulk("k"), bulk("v")]).to_resp_bytes();
+        let state: ServerState = load_aof_with_saved_history(&set);
+        assert_eq!(state.replication_id2, None);
+        assert_ne!(state.replication_id, Some("s".repeat(40)));
+        assert_eq!(state.get_replication_offset(), 0);
+        assert!(state.repl_backlog.is_none());
+        assert_eq!(state.dbs[0].dict.len(), 1);
+    }
+}
@@ -0,0 +1,46 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+static NEXT_DIR: AtomicU64 = AtomicU64::new(1);
+
+/*
+A scratch directory for tests that touch the disk. Every one gets its own
+path, so tests can run in parallel, and it is removed when dropped.
+*/
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl Default for TempDir {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TempDir {
+    pub fn new() -> Self {
+        let name: String = format!(
+            "redis-starter-rust-test-{}-{}",
+            process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::SeqCst)
+        );
+        let path: PathBuf = env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}